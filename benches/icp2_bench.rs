@@ -11,6 +11,17 @@ fn icp2_bench(c: &mut Criterion) {
     });
 }
 
+fn icp2_steepest_descent_bench(c: &mut Criterion) {
+    let mut icp_client = data_gen();
+    icp_client.set_solver(IcpSolver::SteepestDescent);
+    c.bench_function("icp2_steepest_descent_bench", |b| {
+        b.iter(|| {
+            let mut icp_client = icp_client.clone();
+            icp_client.scan_matching(5);
+        });
+    });
+}
+
 fn data_gen() -> IterativeClosestPoint2 {
     let init_pose = Pose2::new(0.0, 0.0, 0.0);
     let mut scan_points_inner = vec![Point2::new(init_pose.x(), init_pose.y())];
@@ -39,5 +50,5 @@ fn data_gen() -> IterativeClosestPoint2 {
     IterativeClosestPoint2::new(&scan_points, &reference_points, &init_pose)
 }
 
-criterion_group!(benches, icp2_bench);
+criterion_group!(benches, icp2_bench, icp2_steepest_descent_bench);
 criterion_main!(benches);
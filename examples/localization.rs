@@ -0,0 +1,53 @@
+use grid_map::Position;
+use slam::*;
+
+/// Builds a map from the sample logs, then localises along the same logs with MCL.
+fn main() {
+    let scan_log_file_name = "sample/ros2_scan_log.yaml";
+    let odom_log_file_name = "sample/ros2_odom_log.yaml";
+    let scan_log_path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), scan_log_file_name);
+    let odom_log_path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), odom_log_file_name);
+
+    let mut data_loader = DebuggerYaml::new(&scan_log_path, &odom_log_path);
+
+    let mut mapping = Mapping::new(
+        Position::new(-5.0, -5.0),
+        Position::new(5.0, 5.0),
+        0.05,
+        DEFAULT_PROBABILITY_FREE_SPACE,
+        DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+    );
+    while let Some((laser_scan, current_position)) = data_loader.next_scan_2d() {
+        mapping.update(&current_position, &laser_scan);
+    }
+
+    data_loader.reset_count();
+    let Some((laser_scan, current_position)) = data_loader.next_scan_2d() else {
+        return;
+    };
+    let mut localization = MonteCarloLocalization::new(
+        &mapping,
+        DEFAULT_MCL_PARTICLES,
+        &current_position,
+        (0.2, 0.2, 0.1),
+    );
+    localization.set_kld_sampling(Some(KldSampling::default()));
+    localization.set_recovery(Some(Recovery::default()));
+    localization.update(&current_position, &laser_scan);
+
+    while let Some((laser_scan, current_position)) = data_loader.next_scan_2d() {
+        let start = std::time::Instant::now();
+        localization.update(&current_position, &laser_scan);
+        let covariance = localization.covariance();
+        println!(
+            "{:?} (odometry {:?}), std ({:.3}, {:.3}, {:.3}), {} particles in {:?}",
+            localization.pose(),
+            Pose2::from(current_position),
+            covariance[(0, 0)].sqrt(),
+            covariance[(1, 1)].sqrt(),
+            covariance[(2, 2)].sqrt(),
+            localization.particles().len(),
+            start.elapsed(),
+        );
+    }
+}
@@ -11,9 +11,18 @@ fn main() {
     let start = na::Translation2::new(2.7, 3.2);
     let end = na::Translation2::new(10.9, 7.4);
 
-    let path_grids = bresenham_algorithm(&start, &end, grid_map.resolution(), grid_map.min_point());
+    let path_cells = ray_traversal2_clipped(
+        &start,
+        &end,
+        grid_map.resolution(),
+        grid_map.min_point(),
+        (grid_map.width(), grid_map.height()),
+    );
 
-    for grid in path_grids {
-        println!("{:?}", grid);
+    for cell in path_cells {
+        println!(
+            "{:?}: entry {:.3} m, exit {:.3} m",
+            cell.index, cell.entry, cell.exit
+        );
     }
 }
@@ -0,0 +1,46 @@
+use slam::*;
+
+/// Matches consecutive scans of the sample logs with ICP and NDT and compares the results.
+fn main() {
+    let scan_log_file_name = "sample/ros2_scan_log.yaml";
+    let odom_log_file_name = "sample/ros2_odom_log.yaml";
+    let scan_log_path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), scan_log_file_name);
+    let odom_log_path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), odom_log_file_name);
+
+    let mut data_loader = DebuggerYaml::new(&scan_log_path, &odom_log_path);
+
+    let Some((laser_scan, mut previous_position)) = data_loader.next_scan_2d() else {
+        return;
+    };
+    let mut previous_points = Pointcloud2::from(laser_scan);
+
+    while let Some((laser_scan, current_position)) = data_loader.next_scan_2d() {
+        let points = Pointcloud2::from(laser_scan);
+        // Odometry gives the initial guess of the motion between the scans.
+        let odometry_delta = Pose2::from(previous_position.inv_mul(&current_position));
+
+        let mut icp = IterativeClosestPoint2::new(&points, &previous_points, &odometry_delta);
+        let mut ndt =
+            NormalDistributionsTransform2::new(&points, &previous_points, &odometry_delta);
+
+        for (name, matcher) in [
+            ("ICP", &mut icp as &mut dyn ScanMatcher),
+            ("NDT", &mut ndt as &mut dyn ScanMatcher),
+        ] {
+            let start = std::time::Instant::now();
+            let result = matcher.scan_matching(50);
+            println!(
+                "{}: {:?} in {:?}, {:?} after {} iterations, {} inliers",
+                name,
+                result.pose,
+                start.elapsed(),
+                result.status,
+                result.iterations,
+                result.inliers,
+            );
+        }
+
+        previous_position = current_position;
+        previous_points = points;
+    }
+}
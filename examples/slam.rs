@@ -11,7 +11,7 @@ fn main() {
 
     let min_point = Position::new(-5.0, -5.0);
     let max_point = Position::new(5.0, 5.0);
-    let resolution = 0.02;
+    let resolution = 0.05;
 
     let mut mapping = Mapping::new(
         min_point,
@@ -20,18 +20,23 @@ fn main() {
         DEFAULT_PROBABILITY_FREE_SPACE,
         DEFAULT_PROBABILITY_OCCUPIED_SPACE,
     );
+    mapping.set_no_return_policy(NoReturnPolicy::ClearToRangeMax);
+
+    let mut slam = RbpfSlam::new(mapping, DEFAULT_RBPF_PARTICLES);
 
     let mut map_viz = MapViz2::new();
 
     while let Some((laser_scan, current_position)) = data_loader.next_scan_2d() {
-        mapping.update(&current_position, &laser_scan);
-
-        let current_pose = Pose2::new(
-            current_position.translation.vector.x,
-            current_position.translation.vector.y,
-            current_position.rotation.angle(),
+        let start = std::time::Instant::now();
+        slam.update(&current_position, &laser_scan);
+        println!(
+            "{:?} (odometry {:?}) in {:?}",
+            slam.pose(),
+            Pose2::from(current_position),
+            start.elapsed(),
         );
-        map_viz.update(&mapping, current_pose);
+
+        map_viz.update(slam.mapping(), slam.pose());
         std::thread::sleep(std::time::Duration::from_millis(25));
     }
 }
@@ -0,0 +1,432 @@
+/// Correlative scan matching with multi-resolution branch-and-bound
+use crate::*;
+use grid_map::Grid;
+
+/// Default half width of the translational search window. [m]
+pub const DEFAULT_LINEAR_SEARCH_WINDOW: f64 = 0.5;
+/// Default half width of the rotational search window. [rad]
+pub const DEFAULT_ANGULAR_SEARCH_WINDOW: f64 = 0.35;
+/// Default number of precomputed resolutions for branch-and-bound.
+pub const DEFAULT_BRANCH_AND_BOUND_DEPTH: usize = 7;
+
+/// Best pose found by the correlative scan matcher.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorrelativeMatch {
+    pub pose: Pose2,
+    /// Mean occupancy probability of the cells hit by the scan points, in [0, 1].
+    pub score: f64,
+}
+
+/// Maximum occupancy probability over the `2^level x 2^level` cells starting at each cell.
+///
+/// Cells are indexed like the map, but the grid reaches `2^level - 1` cells below zero so that
+/// windows which only partially overlap the map are covered as well.
+#[derive(Debug, Clone)]
+struct PrecomputationGrid {
+    padding: i64,
+    width: i64,
+    height: i64,
+    values: Vec<f64>,
+}
+
+impl PrecomputationGrid {
+    fn get(&self, x: i64, y: i64) -> f64 {
+        let (x, y) = (x + self.padding, y + self.padding);
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return 0.0;
+        }
+        self.values[(y * self.width + x) as usize]
+    }
+
+    /// Builds the next coarser level, whose windows are twice as wide.
+    ///
+    /// Unlike a `MapPyramid` level, this keeps the map resolution and a window at every cell,
+    /// as the bounds of branch-and-bound must hold for every translation of a candidate.
+    fn coarsen(&self) -> Self {
+        let half_window = self.padding + 1;
+        let padding = 2 * half_window - 1;
+        let width = self.width - self.padding + padding;
+        let height = self.height - self.padding + padding;
+
+        let mut values = Vec::with_capacity((width * height) as usize);
+        for y in -padding..height - padding {
+            for x in -padding..width - padding {
+                values.push(
+                    self.get(x, y)
+                        .max(self.get(x + half_window, y))
+                        .max(self.get(x, y + half_window))
+                        .max(self.get(x + half_window, y + half_window)),
+                );
+            }
+        }
+
+        Self {
+            padding,
+            width,
+            height,
+            values,
+        }
+    }
+}
+
+/// Set of translations `[x, x + 2^level) x [y, y + 2^level)` at one rotation.
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    rotation: usize,
+    x: i64,
+    y: i64,
+    level: usize,
+    score: f64,
+}
+
+/// Scan points rotated by one of the searched angles, as cell indices at zero translation.
+struct DiscreteScan {
+    theta: f64,
+    cells: Vec<(i64, i64)>,
+}
+
+/// Exhaustive x/y/theta search for the pose that best overlays a scan on an occupancy grid.
+///
+/// Large windows are searched with the branch-and-bound scheme of Hess et al., which bounds
+/// the score of a block of translations with the maximum of the grid over that block.
+#[derive(Debug, Clone)]
+pub struct CorrelativeScanMatcher {
+    /// Precomputed grids, from the map resolution to the coarsest level.
+    levels: Vec<PrecomputationGrid>,
+    /// Centre of the first cell of the map. [m]
+    origin: (f64, f64),
+    resolution: f64,
+    /// Half width of the translational search window. [m]
+    linear_search_window: f64,
+    /// Half width of the rotational search window. [rad]
+    angular_search_window: f64,
+    /// Poses that do not score above this are not returned.
+    min_score: f64,
+}
+
+impl CorrelativeScanMatcher {
+    /// Rasterises the occupancy probabilities of `mapping`. Unexplored cells score zero.
+    pub fn new(mapping: &Mapping) -> Self {
+        let (width, height) = mapping.map_size();
+        let mut values = Vec::with_capacity(width * height);
+        for h in 0..height {
+            for w in 0..width {
+                values.push(
+                    mapping
+                        .map_element(&Grid::new(w, h))
+                        .map_or(0.0, |element| element.probability),
+                );
+            }
+        }
+
+        let mut matcher = Self {
+            levels: vec![PrecomputationGrid {
+                padding: 0,
+                width: width as i64,
+                height: height as i64,
+                values,
+            }],
+            origin: (mapping.min_point().x, mapping.min_point().y),
+            resolution: mapping.resolution(),
+            linear_search_window: DEFAULT_LINEAR_SEARCH_WINDOW,
+            angular_search_window: DEFAULT_ANGULAR_SEARCH_WINDOW,
+            min_score: 0.0,
+        };
+        matcher.set_branch_and_bound_depth(DEFAULT_BRANCH_AND_BOUND_DEPTH);
+        matcher
+    }
+
+    pub fn linear_search_window(&self) -> f64 {
+        self.linear_search_window
+    }
+
+    pub fn set_linear_search_window(&mut self, linear_search_window: f64) {
+        self.linear_search_window = linear_search_window;
+    }
+
+    pub fn angular_search_window(&self) -> f64 {
+        self.angular_search_window
+    }
+
+    pub fn set_angular_search_window(&mut self, angular_search_window: f64) {
+        self.angular_search_window = angular_search_window;
+    }
+
+    pub fn min_score(&self) -> f64 {
+        self.min_score
+    }
+
+    pub fn set_min_score(&mut self, min_score: f64) {
+        self.min_score = min_score;
+    }
+
+    pub fn branch_and_bound_depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Sets the number of resolutions, each twice as coarse as the previous one.
+    pub fn set_branch_and_bound_depth(&mut self, depth: usize) {
+        self.levels.truncate(1);
+        while self.levels.len() < depth {
+            let coarser = self.levels.last().unwrap().coarsen();
+            self.levels.push(coarser);
+        }
+    }
+
+    /// Searches the window around `initial_pose` with branch-and-bound.
+    ///
+    /// Returns `None` if no pose scores above the minimum score.
+    pub fn match_scan(
+        &self,
+        scan_points: &Pointcloud2,
+        initial_pose: &Pose2,
+    ) -> Option<CorrelativeMatch> {
+        let scans = self.discretize_scans(scan_points, initial_pose);
+        if scans.is_empty() {
+            return None;
+        }
+
+        let window = self.linear_window_cells();
+        let top_level = self.levels.len() - 1;
+        let step = 1 << top_level;
+
+        let mut candidates = Vec::new();
+        for rotation in 0..scans.len() {
+            let mut x = -window;
+            while x <= window {
+                let mut y = -window;
+                while y <= window {
+                    candidates.push(self.score_candidate(&scans, rotation, x, y, top_level));
+                    y += step;
+                }
+                x += step;
+            }
+        }
+
+        let best = self.branch_and_bound(&scans, candidates, window, None)?;
+        Some(self.to_match(&scans, initial_pose, &best))
+    }
+
+    /// Scores every pose of the window at the map resolution.
+    ///
+    /// Returns `None` if no pose scores above the minimum score.
+    pub fn match_scan_brute_force(
+        &self,
+        scan_points: &Pointcloud2,
+        initial_pose: &Pose2,
+    ) -> Option<CorrelativeMatch> {
+        let scans = self.discretize_scans(scan_points, initial_pose);
+        let window = self.linear_window_cells();
+
+        let mut best: Option<Candidate> = None;
+        for rotation in 0..scans.len() {
+            for x in -window..=window {
+                for y in -window..=window {
+                    let candidate = self.score_candidate(&scans, rotation, x, y, 0);
+                    if candidate.score > best.map_or(self.min_score, |best| best.score) {
+                        best = Some(candidate);
+                    }
+                }
+            }
+        }
+
+        best.map(|best| self.to_match(&scans, initial_pose, &best))
+    }
+
+    fn linear_window_cells(&self) -> i64 {
+        (self.linear_search_window / self.resolution).ceil() as i64
+    }
+
+    /// Rotates the scan by every searched angle around the initial pose.
+    ///
+    /// The angular step moves the farthest point by at most one cell.
+    fn discretize_scans(
+        &self,
+        scan_points: &Pointcloud2,
+        initial_pose: &Pose2,
+    ) -> Vec<DiscreteScan> {
+        let max_range = scan_points
+            .points()
+            .iter()
+            .map(|p| p.x().hypot(p.y()))
+            .fold(0.0, f64::max);
+        if max_range <= 0.0 {
+            return Vec::new();
+        }
+
+        let angular_step = (1.0
+            - self.resolution * self.resolution / (2.0 * max_range * max_range))
+            .clamp(-1.0, 1.0)
+            .acos();
+        let steps = (self.angular_search_window / angular_step).ceil() as i64;
+
+        (-steps..=steps)
+            .map(|i| {
+                let theta = initial_pose.theta() + i as f64 * angular_step;
+                let pose = Pose2::new(initial_pose.x(), initial_pose.y(), theta);
+                let cells = scan_points
+                    .points()
+                    .iter()
+                    .map(|p| {
+                        let p = pose.transform_point(p);
+                        (
+                            ((p.x() - self.origin.0) / self.resolution).round() as i64,
+                            ((p.y() - self.origin.1) / self.resolution).round() as i64,
+                        )
+                    })
+                    .collect();
+                DiscreteScan { theta, cells }
+            })
+            .collect()
+    }
+
+    /// Upper bound of the score of all translations in the candidate, exact at level 0.
+    fn score_candidate(
+        &self,
+        scans: &[DiscreteScan],
+        rotation: usize,
+        x: i64,
+        y: i64,
+        level: usize,
+    ) -> Candidate {
+        let grid = &self.levels[level];
+        let cells = &scans[rotation].cells;
+        let sum: f64 = cells.iter().map(|(cx, cy)| grid.get(cx + x, cy + y)).sum();
+        Candidate {
+            rotation,
+            x,
+            y,
+            level,
+            score: sum / cells.len() as f64,
+        }
+    }
+
+    /// Depth-first search that skips candidates whose bound does not beat the best leaf.
+    fn branch_and_bound(
+        &self,
+        scans: &[DiscreteScan],
+        mut candidates: Vec<Candidate>,
+        window: i64,
+        mut best: Option<Candidate>,
+    ) -> Option<Candidate> {
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+        for candidate in candidates {
+            if candidate.score <= best.map_or(self.min_score, |best| best.score) {
+                break;
+            }
+            if candidate.level == 0 {
+                best = Some(candidate);
+                continue;
+            }
+
+            let level = candidate.level - 1;
+            let half = 1 << level;
+            let mut children = Vec::with_capacity(4);
+            for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+                let (x, y) = (candidate.x + dx, candidate.y + dy);
+                if x <= window && y <= window {
+                    children.push(self.score_candidate(scans, candidate.rotation, x, y, level));
+                }
+            }
+            best = self.branch_and_bound(scans, children, window, best);
+        }
+
+        best
+    }
+
+    fn to_match(
+        &self,
+        scans: &[DiscreteScan],
+        initial_pose: &Pose2,
+        best: &Candidate,
+    ) -> CorrelativeMatch {
+        CorrelativeMatch {
+            pose: Pose2::new(
+                initial_pose.x() + best.x as f64 * self.resolution,
+                initial_pose.y() + best.y as f64 * self.resolution,
+                scans[best.rotation].theta,
+            ),
+            score: best.score,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_match_scan() {
+        let mapping = room_mapping(0.05);
+        let matcher = CorrelativeScanMatcher::new(&mapping);
+
+        let expected_pose = Pose2::new(0.3, -0.2, 0.15);
+        let scan_points = Pointcloud2::from(room_scan(&expected_pose));
+        let result = matcher
+            .match_scan(&scan_points, &Pose2::new(0.0, 0.0, 0.0))
+            .unwrap();
+
+        println!("{:?}", result);
+
+        assert_approx_eq!(result.pose.x(), expected_pose.x(), mapping.resolution());
+        assert_approx_eq!(result.pose.y(), expected_pose.y(), mapping.resolution());
+        assert_approx_eq!(result.pose.theta(), expected_pose.theta(), 0.02);
+        assert!(result.score > 0.5);
+    }
+
+    #[test]
+    fn test_branch_and_bound_is_exact() {
+        let mapping = room_mapping(0.05);
+        let mut matcher = CorrelativeScanMatcher::new(&mapping);
+        matcher.set_linear_search_window(0.3);
+        matcher.set_angular_search_window(0.1);
+
+        let scan_points = Pointcloud2::from(room_scan(&Pose2::new(-0.1, 0.15, -0.05)));
+        let initial_pose = Pose2::new(0.0, 0.0, 0.0);
+        let brute_force = matcher
+            .match_scan_brute_force(&scan_points, &initial_pose)
+            .unwrap();
+
+        for depth in [1, 3, 7] {
+            matcher.set_branch_and_bound_depth(depth);
+            let result = matcher.match_scan(&scan_points, &initial_pose).unwrap();
+            assert_approx_eq!(result.score, brute_force.score);
+        }
+    }
+
+    #[test]
+    fn test_large_window() {
+        let mapping = room_mapping(0.05);
+        let mut matcher = CorrelativeScanMatcher::new(&mapping);
+        matcher.set_linear_search_window(2.0);
+        matcher.set_angular_search_window(std::f64::consts::PI);
+
+        let expected_pose = Pose2::new(-0.6, 0.4, 1.2);
+        let scan_points = Pointcloud2::from(room_scan(&expected_pose));
+        let result = matcher
+            .match_scan(&scan_points, &Pose2::new(0.5, -0.5, 0.0))
+            .unwrap();
+
+        assert_approx_eq!(result.pose.x(), expected_pose.x(), mapping.resolution());
+        assert_approx_eq!(result.pose.y(), expected_pose.y(), mapping.resolution());
+        assert_approx_eq!(result.pose.theta(), expected_pose.theta(), 0.02);
+    }
+
+    #[test]
+    fn test_min_score() {
+        let mapping = room_mapping(0.05);
+        let mut matcher = CorrelativeScanMatcher::new(&mapping);
+        matcher.set_min_score(1.0);
+
+        let scan_points = Pointcloud2::from(room_scan(&Pose2::new(0.0, 0.0, 0.0)));
+        let initial_pose = Pose2::new(0.0, 0.0, 0.0);
+
+        assert!(matcher.match_scan(&scan_points, &initial_pose).is_none());
+        assert!(matcher
+            .match_scan_brute_force(&scan_points, &initial_pose)
+            .is_none());
+    }
+}
@@ -3,118 +3,490 @@ use argmin::{
     core::{CostFunction, Executor, Gradient},
     solver::{gradientdescent::SteepestDescent, linesearch::MoreThuenteLineSearch},
 };
+use nalgebra as na;
 
-// TODO: Abstraction to be able to deal with both 2D and 3D.
+/// Number of neighbouring reference points used to estimate a normal.
+const NORMAL_ESTIMATION_NEIGHBORS: usize = 5;
+/// Maximum number of Levenberg-Marquardt steps per ICP iteration.
+const LEVENBERG_MARQUARDT_MAX_STEPS: usize = 20;
+/// Initial damping factor of the Levenberg-Marquardt solver.
+const LEVENBERG_MARQUARDT_INITIAL_DAMPING: f64 = 1e-3;
+/// Step size below which the Levenberg-Marquardt solver stops.
+const LEVENBERG_MARQUARDT_STEP_TOLERANCE: f64 = 1e-12;
+/// Lower bound of the residual variance used for the pose covariance. [m^2]
+const MIN_RESIDUAL_VARIANCE: f64 = 1e-6;
+
+/// Error metric minimised by the ICP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IcpErrorMetric {
+    /// Squared distance between a scan point and its corresponding reference point.
+    #[default]
+    PointToPoint,
+    /// Squared distance between a scan point and the line (plane in 3D) through its corresponding
+    /// reference point along the estimated normal (PL-ICP).
+    PointToLine,
+}
+
+/// Solver used to minimise the error for fixed correspondences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IcpSolver {
+    /// Closed-form SVD alignment for point-to-point, Levenberg-Marquardt with analytic Jacobians
+    /// for the other metrics.
+    #[default]
+    Analytic,
+    /// argmin steepest descent with numerically differentiated gradients.
+    SteepestDescent,
+}
+
+/// Iterative closest point matcher of a scan against reference points.
+///
+/// The pose type decides the dimension, see [`IterativeClosestPoint2`] and
+/// [`IterativeClosestPoint3`].
 #[derive(Debug, Clone)]
-pub struct IterativeClosestPoint2 {
+pub struct IterativeClosestPoint<T: RigidTransform> {
     /// Robot coordinates of the scan points.
-    scan_points: Pointcloud2,
-    /// World coordinates of the reference points.
-    reference_points: Pointcloud2,
+    scan_points: Vec<T::Point>,
+    /// Spatial index over the world coordinates of the reference points.
+    reference_tree: KdTree<T::Point>,
     /// Estimated robot pose.
-    robot_pose: Pose2,
-    /// Correspondences between scan points and reference points.
-    correspondences: Vec<usize>,
+    robot_pose: T,
+    /// Pairs of scan point and reference point indices that are treated as inliers.
+    correspondences: Vec<(usize, usize)>,
+    /// Error metric to minimise.
+    error_metric: IcpErrorMetric,
+    /// Unit normals of the reference points, estimated from their neighbours.
+    reference_normals: Vec<na::DVector<f64>>,
+    /// Solver used in each iteration.
+    solver: IcpSolver,
+    /// Thresholds that stop the scan matching early.
+    convergence_criteria: ConvergenceCriteria,
+    /// Pairs farther apart than this are rejected. [m]
+    max_correspondence_distance: f64,
+    /// Fraction of the scan points kept as correspondences, closest pairs first (trimmed ICP).
+    trim_ratio: f64,
+    /// Loss applied to the residuals.
+    robust_kernel: RobustKernel,
 }
 
+pub type IterativeClosestPoint2 = IterativeClosestPoint<Pose2>;
+pub type IterativeClosestPoint3 = IterativeClosestPoint<Pose3>;
+
 impl IterativeClosestPoint2 {
     pub fn new(
         scan_points: &(impl Into<Pointcloud2> + Clone),
         reference_points: &(impl Into<Pointcloud2> + Clone),
         robot_pose: &(impl Into<Pose2> + Clone),
     ) -> Self {
+        Self::from_points(
+            (*scan_points).clone().into().points(),
+            (*reference_points).clone().into().points(),
+            (*robot_pose).clone().into(),
+        )
+    }
+}
+
+impl IterativeClosestPoint3 {
+    pub fn new(
+        scan_points: &(impl Into<Pointcloud3> + Clone),
+        reference_points: &(impl Into<Pointcloud3> + Clone),
+        robot_pose: &(impl Into<Pose3> + Clone),
+    ) -> Self {
+        Self::from_points(
+            (*scan_points).clone().into().points(),
+            (*reference_points).clone().into().points(),
+            (*robot_pose).clone().into(),
+        )
+    }
+}
+
+impl<T: RigidTransform> IterativeClosestPoint<T> {
+    pub fn from_points(
+        scan_points: &[T::Point],
+        reference_points: &[T::Point],
+        robot_pose: T,
+    ) -> Self {
+        let reference_tree = KdTree::new(reference_points);
+        let reference_normals = estimate_normals(&reference_tree, NORMAL_ESTIMATION_NEIGHBORS);
         Self {
-            scan_points: (*scan_points).clone().into(),
-            reference_points: (*reference_points).clone().into(),
-            robot_pose: (*robot_pose).clone().into(),
-            correspondences: vec![0; scan_points.clone().into().points().len()],
+            scan_points: scan_points.to_vec(),
+            reference_tree,
+            robot_pose,
+            correspondences: Vec::new(),
+            error_metric: IcpErrorMetric::default(),
+            reference_normals,
+            solver: IcpSolver::default(),
+            convergence_criteria: ConvergenceCriteria::default(),
+            max_correspondence_distance: f64::INFINITY,
+            trim_ratio: 1.0,
+            robust_kernel: RobustKernel::default(),
         }
     }
 
+    pub fn robot_pose(&self) -> T {
+        self.robot_pose
+    }
+
+    pub fn convergence_criteria(&self) -> ConvergenceCriteria {
+        self.convergence_criteria
+    }
+
+    pub fn set_convergence_criteria(&mut self, convergence_criteria: ConvergenceCriteria) {
+        self.convergence_criteria = convergence_criteria;
+    }
+
+    pub fn max_correspondence_distance(&self) -> f64 {
+        self.max_correspondence_distance
+    }
+
+    pub fn set_max_correspondence_distance(&mut self, max_correspondence_distance: f64) {
+        self.max_correspondence_distance = max_correspondence_distance;
+    }
+
+    pub fn trim_ratio(&self) -> f64 {
+        self.trim_ratio
+    }
+
+    /// Keeps only the given fraction (0.0 to 1.0) of the scan points, closest pairs first.
+    pub fn set_trim_ratio(&mut self, trim_ratio: f64) {
+        self.trim_ratio = trim_ratio;
+    }
+
+    pub fn robust_kernel(&self) -> RobustKernel {
+        self.robust_kernel
+    }
+
+    pub fn set_robust_kernel(&mut self, robust_kernel: RobustKernel) {
+        self.robust_kernel = robust_kernel;
+    }
+
+    pub fn error_metric(&self) -> IcpErrorMetric {
+        self.error_metric
+    }
+
+    pub fn set_error_metric(&mut self, error_metric: IcpErrorMetric) {
+        self.error_metric = error_metric;
+    }
+
+    pub fn solver(&self) -> IcpSolver {
+        self.solver
+    }
+
+    pub fn set_solver(&mut self, solver: IcpSolver) {
+        self.solver = solver;
+    }
+
+    /// Pairs every scan point with its nearest reference point and drops the pairs rejected by
+    /// the maximum correspondence distance and the trim ratio.
     fn data_correspondences(&mut self) {
-        let scan_points_transformed =
-            coordinate_transformation(&self.robot_pose, self.scan_points.points())
-                .iter()
-                .map(|p| Point2::from(*p))
-                .collect::<Vec<Point2>>();
-        for (i, scan_point) in scan_points_transformed.iter().enumerate() {
-            let mut min_distance = f64::MAX;
-            let mut min_idx = 0;
-            for (j, reference_point) in self.reference_points.points().iter().enumerate() {
-                let distance = scan_point.distance(reference_point);
-                if distance < min_distance {
-                    min_distance = distance;
-                    min_idx = j;
+        let mut candidates = Vec::with_capacity(self.scan_points.len());
+        for (i, scan_point) in self.scan_points.iter().enumerate() {
+            let scan_point = self.robot_pose.transform_point(scan_point);
+            if let Some((nearest_idx, distance)) = self.reference_tree.nearest(&scan_point) {
+                if distance <= self.max_correspondence_distance {
+                    candidates.push((i, nearest_idx, distance));
                 }
             }
-            self.correspondences[i] = min_idx;
         }
+
+        if self.trim_ratio < 1.0 {
+            let keep = (self.trim_ratio.max(0.0) * self.scan_points.len() as f64).ceil() as usize;
+            candidates.sort_by(|a, b| a.2.total_cmp(&b.2));
+            candidates.truncate(keep);
+        }
+
+        self.correspondences = candidates.iter().map(|(i, j, _)| (*i, *j)).collect();
     }
 
-    fn distance_between_correspondences(&self, pose: &Pose2) -> f64 {
-        let mut distance = 0.0;
-        let scan_points_transformed = coordinate_transformation(pose, self.scan_points.points())
+    /// Returns the error of each correspondence at `pose` under the current metric.
+    fn residuals(&self, pose: &T) -> Vec<f64> {
+        self.correspondences
             .iter()
-            .map(|p| Point2::from(*p))
-            .collect::<Vec<Point2>>();
-        for (i, j) in self.correspondences.iter().enumerate() {
-            distance +=
-                scan_points_transformed[i].distance_squared(&self.reference_points.points()[*j]);
+            .map(|(i, j)| {
+                let scan_point = pose.transform_point(&self.scan_points[*i]);
+                let reference_point = &self.reference_tree.points()[*j];
+                match self.error_metric {
+                    IcpErrorMetric::PointToPoint => scan_point.distance(reference_point),
+                    IcpErrorMetric::PointToLine => {
+                        let error = point_to_vector(&scan_point) - point_to_vector(reference_point);
+                        error.dot(&self.reference_normals[*j]).abs()
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Mean robust loss of the correspondences at `pose`.
+    fn distance_between_correspondences(&self, pose: &T) -> f64 {
+        let residuals = self.residuals(pose);
+        residuals
+            .iter()
+            .map(|r| self.robust_kernel.rho(*r))
+            .sum::<f64>()
+            / residuals.len() as f64
+    }
+
+    /// Aligns the scan points to their correspondences in closed form (Umeyama / SVD), weighting
+    /// each pair by the robust kernel at the current pose.
+    fn optimize_point_to_point(&mut self) {
+        let weights = self
+            .residuals(&self.robot_pose)
+            .iter()
+            .map(|r| self.robust_kernel.weight(*r))
+            .collect::<Vec<_>>();
+        let (scan_points, reference_points): (Vec<_>, Vec<_>) = self
+            .correspondences
+            .iter()
+            .map(|(i, j)| (self.scan_points[*i], self.reference_tree.points()[*j]))
+            .unzip();
+
+        if let Some(pose) = T::weighted_alignment(&scan_points, &reference_points, &weights) {
+            self.robot_pose = pose;
         }
-        distance / self.correspondences.len() as f64
     }
 
-    pub fn optimize_once(&mut self) {
-        self.data_correspondences();
+    /// Returns the Gauss-Newton approximation of the Hessian, the gradient and the total robust
+    /// loss of the point-to-line residuals at `pose`.
+    fn point_to_line_normal_equations(
+        &self,
+        pose: &T,
+    ) -> (na::DMatrix<f64>, na::DVector<f64>, f64) {
+        let mut hessian = na::DMatrix::zeros(T::DOF, T::DOF);
+        let mut gradient = na::DVector::zeros(T::DOF);
+        let mut cost = 0.0;
+        for (i, j) in self.correspondences.iter() {
+            let p = &self.scan_points[*i];
+            let n = &self.reference_normals[*j];
+            let error = point_to_vector(&pose.transform_point(p))
+                - point_to_vector(&self.reference_tree.points()[*j]);
+            let residual = error.dot(n);
+            let weight = self.robust_kernel.weight(residual);
+            let jacobian = pose.point_jacobian(p).tr_mul(n);
+            hessian.ger(weight, &jacobian, &jacobian, 1.0);
+            gradient.axpy(weight * residual, &jacobian, 1.0);
+            cost += self.robust_kernel.rho(residual);
+        }
+        (hessian, gradient, cost)
+    }
+
+    /// Minimises the point-to-line error for the current correspondences with Levenberg-Marquardt.
+    fn optimize_point_to_line(&mut self) {
+        let mut pose = self.robot_pose;
+        let (mut hessian, mut gradient, mut cost) = self.point_to_line_normal_equations(&pose);
+        let mut damping = LEVENBERG_MARQUARDT_INITIAL_DAMPING;
+
+        for _ in 0..LEVENBERG_MARQUARDT_MAX_STEPS {
+            let mut damped_hessian = hessian.clone();
+            for k in 0..T::DOF {
+                damped_hessian[(k, k)] += damping * hessian[(k, k)].max(f64::EPSILON);
+            }
+            let Some(step) = damped_hessian.cholesky().map(|c| c.solve(&(-&gradient))) else {
+                break;
+            };
+
+            let candidate = pose.retract(step.as_slice());
+            let (candidate_hessian, candidate_gradient, candidate_cost) =
+                self.point_to_line_normal_equations(&candidate);
+            if candidate_cost <= cost {
+                pose = candidate;
+                hessian = candidate_hessian;
+                gradient = candidate_gradient;
+                cost = candidate_cost;
+                damping = (damping * 0.1).max(f64::EPSILON);
+            } else {
+                damping *= 10.0;
+            }
+
+            if step.norm() < LEVENBERG_MARQUARDT_STEP_TOLERANCE {
+                break;
+            }
+        }
+        self.robot_pose = pose;
+    }
 
-        let init_pose = self.robot_pose;
+    /// Minimises the error for the current correspondences with argmin's steepest descent and
+    /// numerically differentiated gradients, over increments of the current pose.
+    fn optimize_steepest_descent(&mut self) {
         let linesearch = MoreThuenteLineSearch::new();
         let solver = SteepestDescent::new(linesearch);
 
         let result = Executor::new(self.clone(), solver)
-            .configure(|state| state.param(init_pose).max_iters(10).target_cost(0.0))
+            .configure(|state| {
+                state
+                    .param(vec![0.0; T::DOF])
+                    .max_iters(10)
+                    .target_cost(0.0)
+            })
             .run()
             .unwrap();
 
         let best = result.state.best_param.unwrap();
 
-        self.robot_pose = best;
+        self.robot_pose = self.robot_pose.retract(&best);
+    }
+
+    pub fn optimize_once(&mut self) {
+        self.data_correspondences();
+        if self.correspondences.is_empty() {
+            return;
+        }
+
+        match (self.solver, self.error_metric) {
+            (IcpSolver::SteepestDescent, _) => self.optimize_steepest_descent(),
+            (IcpSolver::Analytic, IcpErrorMetric::PointToPoint) => self.optimize_point_to_point(),
+            (IcpSolver::Analytic, IcpErrorMetric::PointToLine) => self.optimize_point_to_line(),
+        }
+    }
+
+    /// Iterates until one of the convergence criteria is met or `max_iterations` is reached.
+    pub fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult<T> {
+        let mut status = ScanMatchingStatus::MaxIterationsReached;
+        let mut iterations = 0;
+
+        if self.reference_tree.is_empty() || self.scan_points.is_empty() {
+            status = ScanMatchingStatus::Diverged;
+        } else {
+            let mut previous_cost = f64::INFINITY;
+            while iterations < max_iterations {
+                let previous_pose = self.robot_pose;
+                self.optimize_once();
+                iterations += 1;
+                if self.correspondences.is_empty() {
+                    status = ScanMatchingStatus::Diverged;
+                    break;
+                }
+
+                let cost = self.distance_between_correspondences(&self.robot_pose);
+                if !cost.is_finite() || !self.robot_pose.is_finite() {
+                    status = ScanMatchingStatus::Diverged;
+                    break;
+                }
+                if self.convergence_criteria.is_converged(
+                    &previous_pose,
+                    &self.robot_pose,
+                    previous_cost,
+                    cost,
+                ) {
+                    status = ScanMatchingStatus::Converged;
+                    break;
+                }
+                previous_cost = cost;
+            }
+        }
+
+        self.result(iterations, status)
     }
 
-    pub fn scan_matching(&mut self, max_iterations: usize) {
-        for _ in 0..max_iterations {
-            self.optimize_once();
+    /// Summarises the match at the current pose.
+    fn result(&mut self, iterations: usize, status: ScanMatchingStatus) -> ScanMatchingResult<T> {
+        let residuals = if status == ScanMatchingStatus::Diverged {
+            Vec::new()
+        } else {
+            self.data_correspondences();
+            self.residuals(&self.robot_pose)
+        };
+        let (residual, covariance) = if residuals.is_empty() {
+            (
+                f64::INFINITY,
+                na::DMatrix::from_diagonal_element(T::DOF, T::DOF, f64::INFINITY),
+            )
+        } else {
+            (
+                residuals.iter().sum::<f64>() / residuals.len() as f64,
+                self.covariance(&self.robot_pose),
+            )
+        };
+
+        ScanMatchingResult {
+            pose: self.robot_pose,
+            covariance,
+            residual,
+            iterations,
+            inliers: residuals.len(),
+            status,
         }
     }
+
+    /// Estimates the covariance of `pose` as `sigma^2 * H^-1` from the point-to-line Hessian.
+    ///
+    /// The point-to-line Jacobians are used for every metric because only they reveal the
+    /// directions the scene does not constrain, e.g. the axis of a corridor. Such directions get
+    /// a very large variance instead of an infinite one.
+    fn covariance(&self, pose: &T) -> na::DMatrix<f64> {
+        let (hessian, _, cost) = self.point_to_line_normal_equations(pose);
+        let degrees_of_freedom = self.correspondences.len().saturating_sub(T::DOF).max(1);
+        let variance = (cost / degrees_of_freedom as f64).max(MIN_RESIDUAL_VARIANCE);
+        variance * regularized_inverse(&hessian, HESSIAN_EIGENVALUE_FLOOR)
+    }
 }
 
-impl CostFunction for IterativeClosestPoint2 {
-    type Param = Pose2;
+/// Estimates the unit normal of each point from the principal axes of its nearest neighbours.
+///
+/// In 2D this is the normal of the local line, in 3D the normal of the local plane.
+pub fn estimate_normals<P: KdPoint>(
+    tree: &KdTree<P>,
+    num_neighbors: usize,
+) -> Vec<na::DVector<f64>> {
+    tree.points()
+        .iter()
+        .map(|point| {
+            let neighbors = tree
+                .k_nearest(point, num_neighbors.max(P::DIM))
+                .iter()
+                .map(|(i, _)| point_to_vector(&tree.points()[*i]))
+                .collect::<Vec<_>>();
+
+            let mean = neighbors
+                .iter()
+                .fold(na::DVector::zeros(P::DIM), |sum, p| sum + p)
+                / neighbors.len() as f64;
+            let covariance = neighbors
+                .iter()
+                .fold(na::DMatrix::zeros(P::DIM, P::DIM), |sum, p| {
+                    let d = p - &mean;
+                    sum + &d * d.transpose()
+                });
+
+            // The normal is the direction of least spread.
+            let eigen = covariance.symmetric_eigen();
+            let min_idx = eigen.eigenvalues.imin();
+            eigen.eigenvectors.column(min_idx).normalize()
+        })
+        .collect()
+}
+
+impl<T: RigidTransform> ScanMatcher<T> for IterativeClosestPoint<T> {
+    fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult<T> {
+        IterativeClosestPoint::scan_matching(self, max_iterations)
+    }
+}
+
+impl<T: RigidTransform> CostFunction for IterativeClosestPoint<T> {
+    /// Increment of the current robot pose.
+    type Param = Vec<f64>;
 
     type Output = f64;
 
     fn cost(&self, param: &Self::Param) -> Result<Self::Output, argmin::core::Error> {
-        Ok(self.distance_between_correspondences(param))
+        Ok(self.distance_between_correspondences(&self.robot_pose.retract(param)))
     }
 }
 
-impl Gradient for IterativeClosestPoint2 {
-    type Param = Pose2;
+impl<T: RigidTransform> Gradient for IterativeClosestPoint<T> {
+    type Param = Vec<f64>;
 
-    type Gradient = Pose2;
+    type Gradient = Vec<f64>;
 
     fn gradient(&self, param: &Self::Param) -> Result<Self::Gradient, argmin::core::Error> {
         let epsilon = 1e-6;
-        let grad_x = (self.cost(&Pose2::new(param.x() + epsilon, param.y(), param.theta()))?
-            - self.cost(&Pose2::new(param.x() - epsilon, param.y(), param.theta()))?)
-            / (2.0 * epsilon);
-        let grad_y = (self.cost(&Pose2::new(param.x(), param.y() + epsilon, param.theta()))?
-            - self.cost(&Pose2::new(param.x(), param.y() - epsilon, param.theta()))?)
-            / (2.0 * epsilon);
-        let grad_theta = (self.cost(&Pose2::new(param.x(), param.y(), param.theta() + epsilon))?
-            - self.cost(&Pose2::new(param.x(), param.y(), param.theta() - epsilon))?)
-            / (2.0 * epsilon);
-        Ok(Pose2::new(grad_x, grad_y, grad_theta))
+        (0..param.len())
+            .map(|k| {
+                let mut forward = param.clone();
+                let mut backward = param.clone();
+                forward[k] += epsilon;
+                backward[k] -= epsilon;
+                Ok((self.cost(&forward)? - self.cost(&backward)?) / (2.0 * epsilon))
+            })
+            .collect()
     }
 }
 
@@ -141,6 +513,325 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_icp_converges_to_machine_precision() {
+        let expected_pose = Pose2::new(0.1, 0.1, 0.1);
+        for error_metric in [IcpErrorMetric::PointToPoint, IcpErrorMetric::PointToLine] {
+            let mut icp_client = data_gen();
+            icp_client.set_error_metric(error_metric);
+            icp_client.scan_matching(5);
+
+            assert!(icp_client.robot_pose.distance(&expected_pose) < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_scan_matching_result() {
+        let mut icp_client = data_gen();
+        let result = icp_client.scan_matching(100);
+
+        let expected_pose = Pose2::new(0.1, 0.1, 0.1);
+
+        assert!(result.converged());
+        assert!(result.iterations < 100);
+        assert_eq!(result.inliers, 39);
+        assert!(result.residual < 1e-9);
+        assert_eq!(result.pose, icp_client.robot_pose());
+        assert_approx_eq!(result.pose.x(), expected_pose.x());
+        assert_approx_eq!(result.pose.y(), expected_pose.y());
+        assert_approx_eq!(result.pose.theta(), expected_pose.theta());
+    }
+
+    #[test]
+    fn test_scan_matching_max_iterations() {
+        let mut icp_client = data_gen();
+        let result = icp_client.scan_matching(1);
+
+        assert_eq!(result.status, ScanMatchingStatus::MaxIterationsReached);
+        assert_eq!(result.iterations, 1);
+    }
+
+    #[test]
+    fn test_scan_matching_without_reference() {
+        let scan_points = Pointcloud2::new(vec![Point2::new(1.0, 0.0)]);
+        let reference_points = Pointcloud2::new(Vec::new());
+        let mut icp_client = IterativeClosestPoint2::new(
+            &scan_points,
+            &reference_points,
+            &Pose2::new(0.0, 0.0, 0.0),
+        );
+        let result = icp_client.scan_matching(10);
+
+        assert_eq!(result.status, ScanMatchingStatus::Diverged);
+        assert_eq!(result.inliers, 0);
+    }
+
+    #[test]
+    fn test_max_correspondence_distance() {
+        let mut icp_client = data_gen_with_outliers();
+        icp_client.set_max_correspondence_distance(0.2);
+        let result = icp_client.scan_matching(100);
+
+        assert_pose_near(&result.pose, &Pose2::new(0.1, 0.1, 0.1), 1e-6);
+        assert_eq!(result.inliers, 39);
+    }
+
+    #[test]
+    fn test_trimmed_icp() {
+        let mut icp_client = data_gen_with_outliers();
+        icp_client.set_trim_ratio(0.75);
+        let result = icp_client.scan_matching(100);
+
+        assert_pose_near(&result.pose, &Pose2::new(0.1, 0.1, 0.1), 1e-6);
+        assert_eq!(result.inliers, 39);
+    }
+
+    #[test]
+    fn test_robust_kernels() {
+        let expected_pose = Pose2::new(0.1, 0.1, 0.1);
+
+        let mut icp_client = data_gen_with_outliers();
+        let squared_error = icp_client.scan_matching(100).pose.distance(&expected_pose);
+
+        for robust_kernel in [
+            RobustKernel::Huber(0.02),
+            RobustKernel::Cauchy(0.02),
+            RobustKernel::Tukey(0.1),
+        ] {
+            for error_metric in [IcpErrorMetric::PointToPoint, IcpErrorMetric::PointToLine] {
+                let mut icp_client = data_gen_with_outliers();
+                icp_client.set_robust_kernel(robust_kernel);
+                icp_client.set_error_metric(error_metric);
+                let error = icp_client.scan_matching(100).pose.distance(&expected_pose);
+
+                println!("{robust_kernel:?} {error_metric:?}: {error} (squared: {squared_error})");
+                assert!(error < squared_error);
+            }
+        }
+    }
+
+    #[test]
+    fn test_covariance() {
+        let mut icp_client = data_gen();
+        let result = icp_client.scan_matching(100);
+
+        let covariance = result.covariance;
+        assert_approx_eq!(covariance[(0, 1)], covariance[(1, 0)]);
+        assert!(covariance.clone().symmetric_eigen().eigenvalues.min() > 0.0);
+        assert!(covariance.diagonal().max() < 1e-4);
+    }
+
+    #[test]
+    fn test_covariance_in_corridor() {
+        // Two parallel walls along the x axis constrain y and theta, but not x.
+        let mut points = Vec::new();
+        for i in 0..80 {
+            let x = -2.0 + 0.05 * i as f64;
+            let noise = 0.005 * (i as f64 * 1.7).sin();
+            points.push(Point2::new(x, 1.0 + noise));
+            points.push(Point2::new(x, -1.0 - noise));
+        }
+        let reference_points = Pointcloud2::new(points.clone());
+        let scan_points = Pointcloud2::new(
+            points
+                .iter()
+                .map(|p| Point2::new(p.x() + 0.01, p.y() - 0.003))
+                .collect(),
+        );
+
+        let mut icp_client = IterativeClosestPoint2::new(
+            &scan_points,
+            &reference_points,
+            &Pose2::new(0.0, 0.0, 0.0),
+        );
+        let result = icp_client.scan_matching(100);
+
+        println!("Covariance: {}", result.covariance);
+
+        assert!(result.covariance[(0, 0)] > 1e3 * result.covariance[(1, 1)]);
+        assert!(result.covariance[(0, 0)] > 1e3 * result.covariance[(2, 2)]);
+    }
+
+    #[test]
+    fn test_icp_steepest_descent() {
+        let mut icp_client = data_gen();
+        icp_client.set_solver(IcpSolver::SteepestDescent);
+        icp_client.scan_matching(5);
+
+        let expected_pose = Pose2::new(0.1, 0.1, 0.1);
+
+        assert_approx_eq!(icp_client.robot_pose.x(), expected_pose.x());
+        assert_approx_eq!(icp_client.robot_pose.y(), expected_pose.y());
+        assert_approx_eq!(icp_client.robot_pose.theta(), expected_pose.theta());
+    }
+
+    #[test]
+    fn test_weighted_alignment2() {
+        let expected_pose = Pose2::new(-0.3, 0.7, 2.5);
+        let source = (0..10)
+            .map(|i| Point2::new((i as f64).cos(), i as f64 * 0.1))
+            .collect::<Vec<_>>();
+        let target = source
+            .iter()
+            .map(|p| expected_pose.transform_point(p))
+            .collect::<Vec<_>>();
+
+        let pose = Pose2::weighted_alignment(&source, &target, &[1.0; 10]).unwrap();
+
+        assert_approx_eq!(pose.x(), expected_pose.x());
+        assert_approx_eq!(pose.y(), expected_pose.y());
+        assert_approx_eq!(pose.theta(), expected_pose.theta());
+    }
+
+    #[test]
+    fn test_weighted_alignment3() {
+        let expected_pose = Pose3::new(
+            -0.3,
+            0.7,
+            0.2,
+            na::UnitQuaternion::from_euler_angles(0.3, -0.2, 2.5),
+        );
+        let source = (0..10)
+            .map(|i| Point3::new((i as f64).cos(), i as f64 * 0.1, (i as f64).sin()))
+            .collect::<Vec<_>>();
+        let target = source
+            .iter()
+            .map(|p| expected_pose.transform_point(p))
+            .collect::<Vec<_>>();
+
+        let pose = Pose3::weighted_alignment(&source, &target, &[1.0; 10]).unwrap();
+
+        assert!(pose.distance(&expected_pose) < 1e-9);
+    }
+
+    #[test]
+    fn test_icp3() {
+        let expected_pose = Pose3::new(
+            0.1,
+            -0.1,
+            0.05,
+            na::UnitQuaternion::from_euler_angles(0.02, -0.03, 0.1),
+        );
+        for error_metric in [IcpErrorMetric::PointToPoint, IcpErrorMetric::PointToLine] {
+            let mut icp_client = data_gen3(&expected_pose);
+            icp_client.set_error_metric(error_metric);
+            let result = icp_client.scan_matching(100);
+
+            println!("{:?}: {:?}", error_metric, result);
+
+            assert!(result.converged());
+            assert!(result.pose.distance(&expected_pose) < 1e-9);
+            assert_eq!(result.covariance.shape(), (6, 6));
+        }
+    }
+
+    #[test]
+    fn test_icp3_steepest_descent() {
+        let expected_pose = Pose3::new(
+            0.05,
+            0.0,
+            0.0,
+            na::UnitQuaternion::from_euler_angles(0.0, 0.0, 0.05),
+        );
+        let mut icp_client = data_gen3(&expected_pose);
+        icp_client.set_solver(IcpSolver::SteepestDescent);
+        let initial_error = icp_client.robot_pose().distance(&expected_pose);
+        let result = icp_client.scan_matching(20);
+
+        assert!(result.pose.distance(&expected_pose) < 0.1 * initial_error);
+    }
+
+    #[test]
+    fn test_point_to_line_converges_faster() {
+        let expected_pose = Pose2::new(0.1, 0.1, 0.1);
+        let iterations_to_converge = |error_metric, solver| {
+            let mut icp_client = data_gen();
+            icp_client.set_error_metric(error_metric);
+            icp_client.set_solver(solver);
+            for iteration in 1..=50 {
+                icp_client.optimize_once();
+                if icp_client.robot_pose.distance(&expected_pose) < 1e-6 {
+                    return iteration;
+                }
+            }
+            usize::MAX
+        };
+
+        // Against the steepest descent point-to-point of the original implementation.
+        let point_to_point =
+            iterations_to_converge(IcpErrorMetric::PointToPoint, IcpSolver::SteepestDescent);
+        let point_to_line =
+            iterations_to_converge(IcpErrorMetric::PointToLine, IcpSolver::Analytic);
+
+        println!("Point-to-point: {point_to_point}, point-to-line: {point_to_line}");
+
+        assert!(point_to_line < usize::MAX);
+        assert!(point_to_line < point_to_point);
+    }
+
+    #[test]
+    fn test_estimate_normals() {
+        let points = (0..10)
+            .map(|i| Point2::new(i as f64 * 0.1, 0.5))
+            .collect::<Vec<Point2>>();
+        let normals = estimate_normals(&KdTree2::new(&points), NORMAL_ESTIMATION_NEIGHBORS);
+
+        for normal in normals {
+            assert_approx_eq!(normal[0], 0.0);
+            assert_approx_eq!(normal[1].abs(), 1.0);
+        }
+    }
+
+    /// Corner of a room: three perpendicular walls observed from the origin.
+    fn data_gen3(expected_pose: &Pose3) -> IterativeClosestPoint3 {
+        let mut scan_points_inner = Vec::new();
+        let resolution = 10;
+        for i in 0..resolution {
+            for j in 0..resolution {
+                let u = i as f64 / resolution as f64;
+                let v = j as f64 / resolution as f64;
+                scan_points_inner.push(Point3::new(1.0, u, v));
+                scan_points_inner.push(Point3::new(u, 1.0, v));
+                scan_points_inner.push(Point3::new(u, v, 0.0));
+            }
+        }
+
+        let reference_points_inner = scan_points_inner
+            .iter()
+            .map(|p| expected_pose.transform_point(p))
+            .collect::<Vec<Point3>>();
+
+        let scan_points = Pointcloud3::new(scan_points_inner);
+        let reference_points = Pointcloud3::new(reference_points_inner);
+
+        IterativeClosestPoint3::new(
+            &scan_points,
+            &reference_points,
+            &Pose3::new(0.0, 0.0, 0.0, na::UnitQuaternion::identity()),
+        )
+    }
+
+    fn assert_pose_near(pose: &Pose2, expected: &Pose2, tolerance: f64) {
+        println!("Estimated: {:?}", pose);
+        println!("Expected: {:?}", expected);
+        assert!(pose.distance(expected) < tolerance);
+    }
+
+    /// Same L-shaped walls as `data_gen`, with scan points of an obstacle missing from the
+    /// reference.
+    fn data_gen_with_outliers() -> IterativeClosestPoint2 {
+        let icp_client = data_gen();
+        let mut scan_points = icp_client.scan_points.clone();
+        for i in 0..13 {
+            scan_points.push(Point2::new(0.6 + 0.02 * i as f64, 0.5));
+        }
+        IterativeClosestPoint2::new(
+            &Pointcloud2::new(scan_points),
+            &Pointcloud2::new(icp_client.reference_tree.points().clone()),
+            &icp_client.robot_pose,
+        )
+    }
+
     fn data_gen() -> IterativeClosestPoint2 {
         let init_pose = Pose2::new(0.0, 0.0, 0.0);
         let mut scan_points_inner = vec![Point2::new(init_pose.x(), init_pose.y())];
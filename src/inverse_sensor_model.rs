@@ -0,0 +1,136 @@
+/// Inverse sensor models for occupancy grid mapping
+use crate::*;
+
+/// Parameters of the inverse sensor models of this crate, saved with the map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InverseSensorModelParameters {
+    Constant(ConstantInverseSensorModel),
+    Gaussian(GaussianInverseSensorModel),
+}
+
+/// Fixed log-odds for the cells before the endpoint and for the cell of the endpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConstantInverseSensorModel {
+    pub free_log_odds: f64,
+    pub hit_log_odds: f64,
+}
+
+impl ConstantInverseSensorModel {
+    /// Model of a sensor whose beams end in occupied space with `probability_occupied_space`
+    /// and pass through occupied space with `probability_free_space`.
+    pub fn new(probability_free_space: f64, probability_occupied_space: f64) -> Self {
+        Self {
+            free_log_odds: probability_free_space.ln() - probability_occupied_space.ln(),
+            hit_log_odds: probability_occupied_space.ln() - probability_free_space.ln(),
+        }
+    }
+}
+
+impl Default for ConstantInverseSensorModel {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        )
+    }
+}
+
+impl InverseSensorModel for ConstantInverseSensorModel {
+    fn free_log_odds(&self, _distance: f64, _range: f64) -> f64 {
+        self.free_log_odds
+    }
+
+    fn hit_log_odds(&self, _offset: f64, _range: f64) -> f64 {
+        self.hit_log_odds
+    }
+
+    fn parameters(&self) -> Option<InverseSensorModelParameters> {
+        Some(InverseSensorModelParameters::Constant(*self))
+    }
+}
+
+/// Model whose hit is blurred by the range noise and whose evidence fades with distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GaussianInverseSensorModel {
+    /// Log-odds of free cells close to the sensor.
+    pub free_log_odds: f64,
+    /// Log-odds at the endpoint of a close hit.
+    pub hit_log_odds: f64,
+    /// Standard deviation of the range measurement. [m]
+    pub sigma: f64,
+    /// Distance at which the evidence has dropped to half. Infinite keeps it constant. [m]
+    pub half_confidence_range: f64,
+}
+
+impl GaussianInverseSensorModel {
+    fn confidence(&self, distance: f64) -> f64 {
+        if self.half_confidence_range.is_infinite() {
+            return 1.0;
+        }
+        self.half_confidence_range / (self.half_confidence_range + distance)
+    }
+}
+
+impl Default for GaussianInverseSensorModel {
+    fn default() -> Self {
+        let constant = ConstantInverseSensorModel::default();
+        Self {
+            free_log_odds: constant.free_log_odds,
+            hit_log_odds: constant.hit_log_odds,
+            sigma: 0.05,
+            half_confidence_range: f64::INFINITY,
+        }
+    }
+}
+
+impl InverseSensorModel for GaussianInverseSensorModel {
+    fn free_log_odds(&self, distance: f64, _range: f64) -> f64 {
+        self.free_log_odds * self.confidence(distance)
+    }
+
+    fn hit_log_odds(&self, offset: f64, range: f64) -> f64 {
+        let blur = (-offset * offset / (2.0 * self.sigma * self.sigma)).exp();
+        self.hit_log_odds * blur * self.confidence(range)
+    }
+
+    /// Three standard deviations.
+    fn hit_radius(&self) -> f64 {
+        3.0 * self.sigma
+    }
+
+    fn parameters(&self) -> Option<InverseSensorModelParameters> {
+        Some(InverseSensorModelParameters::Gaussian(*self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_constant_model() {
+        let model = ConstantInverseSensorModel::new(0.3, 0.9);
+
+        assert_approx_eq!(model.free_log_odds(1.0, 2.0), (0.3_f64 / 0.9).ln());
+        assert_approx_eq!(model.hit_log_odds(0.0, 2.0), (0.9_f64 / 0.3).ln());
+        assert_approx_eq!(model.hit_radius(), 0.0);
+    }
+
+    #[test]
+    fn test_gaussian_model() {
+        let model = GaussianInverseSensorModel {
+            half_confidence_range: 4.0,
+            ..Default::default()
+        };
+
+        assert_approx_eq!(model.hit_log_odds(0.0, 4.0), model.hit_log_odds / 2.0);
+        assert!(model.hit_log_odds(0.05, 1.0) < model.hit_log_odds(0.0, 1.0));
+        assert!(model.hit_log_odds(0.0, 8.0) < model.hit_log_odds(0.0, 1.0));
+        assert_approx_eq!(
+            model.free_log_odds(4.0, f64::INFINITY),
+            model.free_log_odds / 2.0
+        );
+        assert_approx_eq!(model.hit_radius(), 0.15);
+    }
+}
@@ -0,0 +1,261 @@
+/// KD-tree for nearest neighbour search
+use crate::*;
+
+pub type KdTree2 = KdTree<Point2>;
+pub type KdTree3 = KdTree<Point3>;
+
+/// Static KD-tree over a set of points.
+///
+/// The tree is stored implicitly: the median of every index range is the node that splits it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KdTree<P: KdPoint> {
+    /// Points in their original order.
+    points: Vec<P>,
+    /// Point indices arranged as an implicit balanced tree.
+    indices: Vec<usize>,
+    /// Split axis of the node stored at the same position in `indices`.
+    axes: Vec<usize>,
+}
+
+impl<P: KdPoint> KdTree<P> {
+    pub fn new(points: &[P]) -> Self {
+        let mut tree = Self {
+            points: points.to_vec(),
+            indices: (0..points.len()).collect(),
+            axes: vec![0; points.len()],
+        };
+        tree.build(0, points.len());
+        tree
+    }
+
+    fn build(&mut self, lo: usize, hi: usize) {
+        if hi <= lo {
+            return;
+        }
+
+        // Split along the axis with the largest spread.
+        let mut axis = 0;
+        let mut max_spread = f64::NEG_INFINITY;
+        for a in 0..P::DIM {
+            let (min, max) = self.indices[lo..hi]
+                .iter()
+                .map(|i| self.points[*i].coordinate(a))
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), c| {
+                    (min.min(c), max.max(c))
+                });
+            if max - min > max_spread {
+                max_spread = max - min;
+                axis = a;
+            }
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let points = &self.points;
+        self.indices[lo..hi].select_nth_unstable_by(mid - lo, |a, b| {
+            points[*a]
+                .coordinate(axis)
+                .total_cmp(&points[*b].coordinate(axis))
+        });
+        self.axes[mid] = axis;
+
+        self.build(lo, mid);
+        self.build(mid + 1, hi);
+    }
+
+    pub fn points(&self) -> &Vec<P> {
+        &self.points
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Returns the index of the closest point and its distance.
+    pub fn nearest(&self, query: &P) -> Option<(usize, f64)> {
+        self.k_nearest(query, 1).first().copied()
+    }
+
+    /// Returns the indices and distances of the `k` closest points, sorted by distance.
+    pub fn k_nearest(&self, query: &P, k: usize) -> Vec<(usize, f64)> {
+        let mut neighbors = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.search_k_nearest(query, k, 0, self.points.len(), &mut neighbors);
+        }
+        neighbors
+            .into_iter()
+            .map(|(i, d2)| (i, d2.sqrt()))
+            .collect()
+    }
+
+    /// Returns the indices and distances of all points within `radius`, sorted by distance.
+    pub fn radius_search(&self, query: &P, radius: f64) -> Vec<(usize, f64)> {
+        let mut neighbors = Vec::new();
+        self.search_radius(query, radius * radius, 0, self.points.len(), &mut neighbors);
+        neighbors.sort_by(|a, b| a.1.total_cmp(&b.1));
+        neighbors
+            .into_iter()
+            .map(|(i, d2)| (i, d2.sqrt()))
+            .collect()
+    }
+
+    /// Collects the `k` nearest neighbours as (index, squared distance), sorted by distance.
+    fn search_k_nearest(
+        &self,
+        query: &P,
+        k: usize,
+        lo: usize,
+        hi: usize,
+        neighbors: &mut Vec<(usize, f64)>,
+    ) {
+        if hi <= lo {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let idx = self.indices[mid];
+        let axis = self.axes[mid];
+
+        let distance_squared = query.distance_squared(&self.points[idx]);
+        if neighbors.len() < k || distance_squared < neighbors[neighbors.len() - 1].1 {
+            let position = neighbors.partition_point(|(_, d2)| *d2 <= distance_squared);
+            neighbors.insert(position, (idx, distance_squared));
+            neighbors.truncate(k);
+        }
+
+        let diff = query.coordinate(axis) - self.points[idx].coordinate(axis);
+        let (near, far) = if diff < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.search_k_nearest(query, k, near.0, near.1, neighbors);
+        if neighbors.len() < k || diff * diff < neighbors[neighbors.len() - 1].1 {
+            self.search_k_nearest(query, k, far.0, far.1, neighbors);
+        }
+    }
+
+    /// Collects all points within the squared radius as (index, squared distance).
+    fn search_radius(
+        &self,
+        query: &P,
+        radius_squared: f64,
+        lo: usize,
+        hi: usize,
+        neighbors: &mut Vec<(usize, f64)>,
+    ) {
+        if hi <= lo {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let idx = self.indices[mid];
+        let axis = self.axes[mid];
+
+        let distance_squared = query.distance_squared(&self.points[idx]);
+        if distance_squared <= radius_squared {
+            neighbors.push((idx, distance_squared));
+        }
+
+        let diff = query.coordinate(axis) - self.points[idx].coordinate(axis);
+        if diff < 0.0 || diff * diff <= radius_squared {
+            self.search_radius(query, radius_squared, lo, mid, neighbors);
+        }
+        if diff >= 0.0 || diff * diff <= radius_squared {
+            self.search_radius(query, radius_squared, mid + 1, hi, neighbors);
+        }
+    }
+}
+
+impl From<&Pointcloud2> for KdTree2 {
+    fn from(pointcloud: &Pointcloud2) -> Self {
+        Self::new(pointcloud.points())
+    }
+}
+
+impl From<&Pointcloud3> for KdTree3 {
+    fn from(pointcloud: &Pointcloud3) -> Self {
+        Self::new(pointcloud.points())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_k_nearest() {
+        let points = points3_gen(500);
+        let tree = KdTree3::new(&points);
+
+        for query in points3_gen(20)
+            .iter()
+            .map(|p| Point3::new(p.y(), p.z(), p.x()))
+        {
+            let mut expected = points
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (i, query.distance(p)))
+                .collect::<Vec<_>>();
+            expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            let neighbors = tree.k_nearest(&query, 7);
+
+            assert_eq!(neighbors.len(), 7);
+            for (neighbor, expected) in neighbors.iter().zip(expected.iter()) {
+                assert_approx_eq!(neighbor.1, expected.1);
+            }
+            assert_eq!(tree.nearest(&query).unwrap().0, expected[0].0);
+        }
+    }
+
+    #[test]
+    fn test_radius_search() {
+        let points = points3_gen(500)
+            .iter()
+            .map(|p| Point2::new(p.x(), p.y()))
+            .collect::<Vec<_>>();
+        let tree = KdTree2::from(&Pointcloud2::new(points.clone()));
+
+        let query = Point2::new(0.5, 0.5);
+        let radius = 0.2;
+        let expected = points
+            .iter()
+            .filter(|p| query.distance(p) <= radius)
+            .count();
+
+        let neighbors = tree.radius_search(&query, radius);
+
+        assert_eq!(neighbors.len(), expected);
+        assert!(neighbors.iter().all(|(_, d)| *d <= radius));
+        assert!(neighbors.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn test_empty() {
+        let tree = KdTree2::new(&[]);
+
+        assert!(tree.is_empty());
+        assert!(tree.nearest(&Point2::new(0.0, 0.0)).is_none());
+        assert!(tree.radius_search(&Point2::new(0.0, 0.0), 1.0).is_empty());
+    }
+
+    /// Deterministic pseudo-random points in the unit cube.
+    fn points3_gen(n: usize) -> Vec<Point3> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..n)
+            .map(|_| Point3::new(next(), next(), next()))
+            .collect()
+    }
+}
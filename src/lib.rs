@@ -1,15 +1,51 @@
+mod correlative_scan_matcher;
 mod debugger_yaml;
 mod icp;
+mod inverse_sensor_model;
+mod kdtree;
+mod likelihood_field;
+mod map_pyramid;
+mod map_server;
 mod map_viz;
 mod mapping;
+mod monte_carlo_localization;
+mod ndt;
+mod pose_graph;
+mod pose_graph_optimizer;
 mod protocol;
+mod ray_traversal;
+mod rbpf_slam;
+mod robust_kernel;
+mod scan_matching;
+mod scan_to_map_matcher;
+mod sparse_ldlt;
+mod submap;
+#[cfg(test)]
+mod test_util;
 mod traits;
 mod utils;
 
+pub use correlative_scan_matcher::*;
 pub use debugger_yaml::*;
 pub use icp::*;
+pub use inverse_sensor_model::*;
+pub use kdtree::*;
+pub use likelihood_field::*;
+pub use map_pyramid::*;
+pub use map_server::*;
 pub use map_viz::*;
 pub use mapping::*;
+pub use monte_carlo_localization::*;
+pub use ndt::*;
+pub use pose_graph::*;
+pub use pose_graph_optimizer::*;
 pub use protocol::*;
+pub use ray_traversal::*;
+pub use rbpf_slam::*;
+pub use robust_kernel::*;
+pub use scan_matching::*;
+pub use scan_to_map_matcher::*;
+pub use sparse_ldlt::*;
+pub use submap::*;
 pub use traits::*;
 pub use utils::*;
@@ -0,0 +1,424 @@
+/// Likelihood field of the distance to the nearest obstacle
+use crate::*;
+use grid_map::{Grid, Position};
+
+/// Default distance beyond which obstacles are not looked for. [m]
+pub const DEFAULT_LIKELIHOOD_FIELD_MAX_DISTANCE: f64 = 2.0;
+/// Default standard deviation of the measurement noise of the likelihood. [m]
+pub const DEFAULT_LIKELIHOOD_FIELD_SIGMA: f64 = 0.2;
+
+/// Distance from every cell of a `Mapping` to the nearest occupied cell.
+///
+/// Distances are exact Euclidean distances between cell centres (Felzenszwalb and Huttenlocher),
+/// clamped to the maximum distance. Unexplored cells are not obstacles.
+#[derive(Debug, Clone)]
+pub struct LikelihoodField {
+    /// Distances clamped to `max_distance`, row-major. [m]
+    distances: Vec<f64>,
+    width: usize,
+    height: usize,
+    /// Centre of the first cell. [m]
+    origin: Position,
+    resolution: f64,
+    /// [m]
+    max_distance: f64,
+    /// [m]
+    sigma: f64,
+}
+
+impl LikelihoodField {
+    pub fn new(mapping: &Mapping, max_distance: f64) -> Self {
+        let (width, height) = mapping.map_size();
+        let mut likelihood_field = Self {
+            distances: Vec::new(),
+            width,
+            height,
+            origin: Position::new(mapping.min_point().x, mapping.min_point().y),
+            resolution: mapping.resolution(),
+            max_distance,
+            sigma: DEFAULT_LIKELIHOOD_FIELD_SIGMA,
+        };
+        likelihood_field.distances =
+            likelihood_field.window_distances(mapping, (0, 0), (width, height));
+        likelihood_field
+    }
+
+    pub fn max_distance(&self) -> f64 {
+        self.max_distance
+    }
+
+    pub fn sigma(&self) -> f64 {
+        self.sigma
+    }
+
+    pub fn set_sigma(&mut self, sigma: f64) {
+        self.sigma = sigma;
+    }
+
+    /// Recomputes the distances after the occupancy inside the box has changed, e.g. around
+    /// the scan given to `Mapping::update`.
+    ///
+    /// Only cells within the maximum distance of the box are touched, unless the map has grown
+    /// and the whole field is rebuilt.
+    pub fn update(&mut self, mapping: &Mapping, min_point: &Position, max_point: &Position) {
+        if self.rebuild_if_grown(mapping) {
+            return;
+        }
+
+        let reach = (self.max_distance / self.resolution).ceil() as i64;
+        let (min_x, min_y) = self.grid_index(min_point.x, min_point.y);
+        let (max_x, max_y) = self.grid_index(max_point.x, max_point.y);
+        let clamp_x = |x: i64| x.clamp(0, self.width as i64) as usize;
+        let clamp_y = |y: i64| y.clamp(0, self.height as i64) as usize;
+
+        // Obstacles up to twice the reach away can be the nearest one of a changed cell.
+        let source_min = (clamp_x(min_x - 2 * reach), clamp_y(min_y - 2 * reach));
+        let source_max = (
+            clamp_x(max_x + 2 * reach + 1),
+            clamp_y(max_y + 2 * reach + 1),
+        );
+        let target_min = (clamp_x(min_x - reach), clamp_y(min_y - reach));
+        let target_max = (clamp_x(max_x + reach + 1), clamp_y(max_y + reach + 1));
+
+        let distances = self.window_distances(mapping, source_min, source_max);
+        let source_width = source_max.0 - source_min.0;
+        for y in target_min.1..target_max.1 {
+            for x in target_min.0..target_max.0 {
+                self.distances[y * self.width + x] =
+                    distances[(y - source_min.1) * source_width + (x - source_min.0)];
+            }
+        }
+    }
+
+    /// Recomputes the distances around the cells whose occupancy flipped, as returned by
+    /// `Mapping::update`. The whole field is rebuilt if the map has grown.
+    pub fn update_from_changes(&mut self, mapping: &Mapping, changes: &[MapChange]) {
+        if self.rebuild_if_grown(mapping) {
+            return;
+        }
+
+        let flipped = changes
+            .iter()
+            .filter(|change| {
+                change.old.is_some_and(|old| old.is_occupied()) != change.new.is_occupied()
+            })
+            .map(|change| (change.grid.x, change.grid.y));
+        let Some((min_x, min_y, max_x, max_y)) = flipped.fold(None, |bounds, (x, y)| {
+            let (min_x, min_y, max_x, max_y) = bounds.unwrap_or((x, y, x, y));
+            Some((min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)))
+        }) else {
+            return;
+        };
+
+        let position = |x: usize, y: usize| {
+            Position::new(
+                self.origin.x + x as f64 * self.resolution,
+                self.origin.y + y as f64 * self.resolution,
+            )
+        };
+        self.update(mapping, &position(min_x, min_y), &position(max_x, max_y));
+    }
+
+    /// Recomputes the whole field if the map has grown since it was computed, as the cells no
+    /// longer line up. Returns whether it did.
+    fn rebuild_if_grown(&mut self, mapping: &Mapping) -> bool {
+        if mapping.map_size() == (self.width, self.height)
+            && mapping.min_point().x == self.origin.x
+            && mapping.min_point().y == self.origin.y
+        {
+            return false;
+        }
+        let sigma = self.sigma;
+        *self = Self::new(mapping, self.max_distance);
+        self.sigma = sigma;
+        true
+    }
+
+    /// Returns the distance stored for a cell, or `None` outside the map.
+    pub fn cell_distance(&self, grid: &Grid) -> Option<f64> {
+        let index = self.index(grid.x as i64, grid.y as i64)?;
+        Some(self.distances[index])
+    }
+
+    /// Bilinear interpolation of the distance between the cell centres.
+    ///
+    /// Returns the maximum distance outside the map.
+    pub fn distance(&self, position: &Position) -> f64 {
+        let u = (position.x - self.origin.x) / self.resolution;
+        let v = (position.y - self.origin.y) / self.resolution;
+        let (i, j) = (u.floor(), v.floor());
+        let (fu, fv) = (u - i, v - j);
+        let (i, j) = (i as i64, j as i64);
+
+        let get = |x: i64, y: i64| {
+            self.index(x, y)
+                .map_or(self.max_distance, |index| self.distances[index])
+        };
+        (1.0 - fv) * ((1.0 - fu) * get(i, j) + fu * get(i + 1, j))
+            + fv * ((1.0 - fu) * get(i, j + 1) + fu * get(i + 1, j + 1))
+    }
+
+    /// Gaussian likelihood of observing an obstacle at the position, in (0, 1].
+    pub fn likelihood(&self, position: &Position) -> f64 {
+        let distance = self.distance(position);
+        (-distance * distance / (2.0 * self.sigma * self.sigma)).exp()
+    }
+
+    /// Mean likelihood of the scan points seen from `pose`, in [0, 1].
+    pub fn score(&self, scan_points: &Pointcloud2, pose: &Pose2) -> f64 {
+        if scan_points.points().is_empty() {
+            return 0.0;
+        }
+        scan_points
+            .points()
+            .iter()
+            .map(|point| {
+                let point = pose.transform_point(point);
+                self.likelihood(&Position::new(point.x(), point.y()))
+            })
+            .sum::<f64>()
+            / scan_points.points().len() as f64
+    }
+
+    fn grid_index(&self, x: f64, y: f64) -> (i64, i64) {
+        (
+            ((x - self.origin.x) / self.resolution).round() as i64,
+            ((y - self.origin.y) / self.resolution).round() as i64,
+        )
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return None;
+        }
+        Some(y as usize * self.width + x as usize)
+    }
+
+    /// Clamped distances within the window, considering only the obstacles inside it.
+    fn window_distances(
+        &self,
+        mapping: &Mapping,
+        min: (usize, usize),
+        max: (usize, usize),
+    ) -> Vec<f64> {
+        let (width, height) = (max.0 - min.0, max.1 - min.1);
+        let mut squared = Vec::with_capacity(width * height);
+        for y in min.1..max.1 {
+            for x in min.0..max.0 {
+                let occupied = mapping
+                    .map_element(&Grid::new(x, y))
+                    .is_some_and(|element| element.is_occupied());
+                squared.push(if occupied { 0.0 } else { f64::INFINITY });
+            }
+        }
+
+        let mut input = Vec::with_capacity(width.max(height));
+        let mut output = vec![0.0; width.max(height)];
+        for x in 0..width {
+            input.clear();
+            input.extend((0..height).map(|y| squared[y * width + x]));
+            distance_transform_1d(&input, &mut output[..height]);
+            for y in 0..height {
+                squared[y * width + x] = output[y];
+            }
+        }
+        for y in 0..height {
+            input.clear();
+            input.extend_from_slice(&squared[y * width..(y + 1) * width]);
+            distance_transform_1d(&input, &mut output[..width]);
+            squared[y * width..(y + 1) * width].copy_from_slice(&output[..width]);
+        }
+
+        squared
+            .iter()
+            .map(|d2| (d2.sqrt() * self.resolution).min(self.max_distance))
+            .collect()
+    }
+}
+
+/// Squared distance transform of a sampled function, `d[q] = min_p (q - p)^2 + f[p]`.
+///
+/// Lower envelope of parabolas; samples with infinite value are skipped.
+fn distance_transform_1d(f: &[f64], d: &mut [f64]) {
+    let mut vertices: Vec<usize> = Vec::with_capacity(f.len());
+    let mut boundaries: Vec<f64> = Vec::with_capacity(f.len());
+
+    for (q, value) in f.iter().enumerate() {
+        if value.is_infinite() {
+            continue;
+        }
+        let parabola = value + (q * q) as f64;
+        let mut s = f64::NEG_INFINITY;
+        while let Some(&v) = vertices.last() {
+            s = (parabola - (f[v] + (v * v) as f64)) / (2.0 * (q - v) as f64);
+            if s <= *boundaries.last().unwrap() {
+                vertices.pop();
+                boundaries.pop();
+            } else {
+                break;
+            }
+        }
+        vertices.push(q);
+        boundaries.push(s);
+    }
+
+    if vertices.is_empty() {
+        d.fill(f64::INFINITY);
+        return;
+    }
+
+    let mut k = 0;
+    for (q, distance) in d.iter_mut().enumerate() {
+        while k + 1 < vertices.len() && boundaries[k + 1] < q as f64 {
+            k += 1;
+        }
+        let offset = q as f64 - vertices[k] as f64;
+        *distance = offset * offset + f[vertices[k]];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_exact_distances() {
+        let mapping = room_mapping(0.1);
+        let likelihood_field = LikelihoodField::new(&mapping, 1.0);
+
+        let occupied = mapping.get_occupied_grids_positions();
+        let (width, height) = mapping.map_size();
+        for h in (0..height).step_by(3) {
+            for w in (0..width).step_by(3) {
+                let x = mapping.min_point().x + w as f64 * mapping.resolution();
+                let y = mapping.min_point().y + h as f64 * mapping.resolution();
+                let expected = occupied
+                    .iter()
+                    .map(|p| (p.x - x).hypot(p.y - y))
+                    .fold(1.0, f64::min);
+                let distance = likelihood_field.cell_distance(&Grid::new(w, h)).unwrap();
+                assert_approx_eq!(distance, expected, 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let mut mapping = room_mapping(0.05);
+        let mut likelihood_field = LikelihoodField::new(&mapping, 0.5);
+
+        // A single beam that hits a new obstacle in the middle of the room.
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+        let laser_scan = LaserScan::new(10.0, 0.1, 0.1, 0.3, 0.3, 0.0, vec![1.0]);
+        for _ in 0..20 {
+            mapping.update(&isometry(&pose), &laser_scan);
+        }
+        likelihood_field.update(
+            &mapping,
+            &Position::new(pose.x(), pose.y()),
+            &Position::new(0.3_f64.cos(), 0.3_f64.sin()),
+        );
+
+        let expected = LikelihoodField::new(&mapping, 0.5);
+        assert_eq!(likelihood_field.distances, expected.distances);
+        assert_approx_eq!(
+            likelihood_field.distance(&Position::new(0.3_f64.cos(), 0.3_f64.sin())),
+            0.0,
+            0.05
+        );
+    }
+
+    #[test]
+    fn test_update_after_growth() {
+        let mut mapping = room_mapping(0.05);
+        let mut likelihood_field = LikelihoodField::new(&mapping, 0.5);
+
+        // A beam to the left that leaves the map moves its origin towards negative x.
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+        let laser_scan = LaserScan::new(10.0, 0.1, 0.1, 3.1, 3.1, 0.0, vec![5.0]);
+        mapping.update(&isometry(&pose), &laser_scan);
+        assert!(mapping.min_point().x < likelihood_field.origin.x);
+
+        likelihood_field.update(
+            &mapping,
+            &Position::new(pose.x(), pose.y()),
+            &Position::new(-5.0, 0.0),
+        );
+        assert_eq!(likelihood_field.origin.x, mapping.min_point().x);
+        assert_eq!(
+            likelihood_field.distances,
+            LikelihoodField::new(&mapping, 0.5).distances
+        );
+    }
+
+    #[test]
+    fn test_update_from_changes() {
+        let mut mapping = room_mapping(0.05);
+        let mut likelihood_field = LikelihoodField::new(&mapping, 0.5);
+
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+        let laser_scan = LaserScan::new(10.0, 0.1, 0.1, -0.5, -0.5, 0.0, vec![1.5]);
+        for _ in 0..20 {
+            let changes = mapping.update(&isometry(&pose), &laser_scan);
+            likelihood_field.update_from_changes(&mapping, &changes);
+        }
+        assert_eq!(
+            likelihood_field.distances,
+            LikelihoodField::new(&mapping, 0.5).distances
+        );
+
+        // A beam that leaves the map makes it grow.
+        let laser_scan = LaserScan::new(10.0, 0.1, 0.1, 0.0, 0.0, 0.0, vec![5.0]);
+        let changes = mapping.update(&isometry(&pose), &laser_scan);
+        likelihood_field.update_from_changes(&mapping, &changes);
+        assert_eq!(
+            (likelihood_field.width, likelihood_field.height),
+            mapping.map_size()
+        );
+        assert_eq!(
+            likelihood_field.distances,
+            LikelihoodField::new(&mapping, 0.5).distances
+        );
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mapping = room_mapping(0.05);
+        let likelihood_field = LikelihoodField::new(&mapping, 1.0);
+
+        // Half way between the right wall and the cells 0.5 m away from it.
+        assert_approx_eq!(likelihood_field.distance(&Position::new(2.75, 0.0)), 0.25);
+        assert_approx_eq!(
+            likelihood_field.distance(&Position::new(2.7625, 0.0)),
+            0.2375
+        );
+        assert_approx_eq!(likelihood_field.distance(&Position::new(100.0, 0.0)), 1.0);
+        assert_approx_eq!(likelihood_field.likelihood(&Position::new(3.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn test_score() {
+        let mapping = room_mapping(0.05);
+        let likelihood_field = LikelihoodField::new(&mapping, 1.0);
+
+        let pose = Pose2::new(0.4, -0.3, 0.2);
+        let scan_points = Pointcloud2::from(room_scan(&pose));
+
+        let score = likelihood_field.score(&scan_points, &pose);
+        assert!(score > 0.95);
+        for offset in [
+            Pose2::new(0.1, 0.0, 0.0),
+            Pose2::new(0.0, 0.1, 0.0),
+            Pose2::new(0.0, 0.0, 0.05),
+        ] {
+            let moved = Pose2::new(
+                pose.x() + offset.x(),
+                pose.y() + offset.y(),
+                pose.theta() + offset.theta(),
+            );
+            assert!(likelihood_field.score(&scan_points, &moved) < score);
+        }
+    }
+}
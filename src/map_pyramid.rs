@@ -0,0 +1,240 @@
+/// Multi-resolution pyramid of the occupancy grid
+use crate::*;
+use grid_map::{Grid, Position};
+use std::collections::HashSet;
+
+/// Occupancy of one level of a `MapPyramid`.
+#[derive(Debug, Clone)]
+pub struct MapLevel {
+    /// Largest occupancy probability of the map cells covered by each cell, `None` if they are
+    /// all unexplored. Row-major.
+    values: Vec<Option<f64>>,
+    width: usize,
+    height: usize,
+    /// Centre of the first cell. [m]
+    min_point: Position,
+    resolution: f64,
+}
+
+impl MapLevel {
+    pub fn resolution(&self) -> f64 {
+        self.resolution
+    }
+
+    /// Number of cells (width, height).
+    pub fn map_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Centre of the lower left cell.
+    pub fn min_point(&self) -> &Position {
+        &self.min_point
+    }
+
+    /// Returns the occupancy probability of a cell, or `None` if it is unexplored or outside
+    /// the level.
+    pub fn probability(&self, grid: &Grid) -> Option<f64> {
+        if grid.x >= self.width || grid.y >= self.height {
+            return None;
+        }
+        self.values[grid.y * self.width + grid.x]
+    }
+
+    /// Returns the occupancy probability of the cell containing the position.
+    pub fn probability_at(&self, position: &Position) -> Option<f64> {
+        let x = ((position.x - self.min_point.x) / self.resolution).round();
+        let y = ((position.y - self.min_point.y) / self.resolution).round();
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+        self.probability(&Grid::new(x as usize, y as usize))
+    }
+
+    /// Builds the level with twice the cell size, keeping the most occupied of every 2x2 cells.
+    fn downsample(&self) -> Self {
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let mut level = Self {
+            values: vec![None; width * height],
+            width,
+            height,
+            min_point: Position::new(
+                self.min_point.x + self.resolution / 2.0,
+                self.min_point.y + self.resolution / 2.0,
+            ),
+            resolution: self.resolution * 2.0,
+        };
+        for y in 0..height {
+            for x in 0..width {
+                level.values[y * width + x] = self.pooled(x, y);
+            }
+        }
+        level
+    }
+
+    /// Largest value of the 2x2 cells below the cell (x, y) of the next coarser level.
+    fn pooled(&self, x: usize, y: usize) -> Option<f64> {
+        [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .iter()
+            .filter_map(|(dx, dy)| self.probability(&Grid::new(2 * x + dx, 2 * y + dy)))
+            .reduce(f64::max)
+    }
+}
+
+/// Occupancy grid at the map resolution and at coarser resolutions, each twice the previous
+/// one, for coarse-to-fine matching and fast drawing.
+///
+/// A cell of a coarse level holds the largest occupancy probability of the map cells it covers,
+/// so obstacles never disappear from the coarse levels.
+#[derive(Debug, Clone)]
+pub struct MapPyramid {
+    levels: Vec<MapLevel>,
+}
+
+impl MapPyramid {
+    /// Builds `levels` levels of `mapping`, the first one at the map resolution.
+    pub fn new(mapping: &Mapping, levels: usize) -> Self {
+        let (width, height) = mapping.map_size();
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                values.push(
+                    mapping
+                        .map_element(&Grid::new(x, y))
+                        .map(|element| element.probability),
+                );
+            }
+        }
+
+        let mut pyramid = Self {
+            levels: vec![MapLevel {
+                values,
+                width,
+                height,
+                min_point: Position::new(mapping.min_point().x, mapping.min_point().y),
+                resolution: mapping.resolution(),
+            }],
+        };
+        pyramid.set_levels(levels);
+        pyramid
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Sets the number of levels, at least one, building the missing coarse levels.
+    pub fn set_levels(&mut self, levels: usize) {
+        self.levels.truncate(levels.max(1));
+        while self.levels.len() < levels {
+            let level = self.levels.last().unwrap().downsample();
+            self.levels.push(level);
+        }
+    }
+
+    /// Returns a level, 0 being the map resolution.
+    pub fn level(&self, level: usize) -> &MapLevel {
+        &self.levels[level]
+    }
+
+    /// Applies the cells changed by `Mapping::update`, rebuilding the pyramid if the map grew.
+    pub fn update_from_changes(&mut self, mapping: &Mapping, changes: &[MapChange]) {
+        let base = &self.levels[0];
+        if mapping.map_size() != (base.width, base.height)
+            || mapping.min_point().x != base.min_point.x
+            || mapping.min_point().y != base.min_point.y
+        {
+            *self = Self::new(mapping, self.levels.len());
+            return;
+        }
+
+        let base = &mut self.levels[0];
+        let mut dirty = HashSet::new();
+        for change in changes {
+            base.values[change.grid.y * base.width + change.grid.x] = Some(change.new.probability);
+            dirty.insert((change.grid.x / 2, change.grid.y / 2));
+        }
+        for level in 1..self.levels.len() {
+            let (finer, coarser) = self.levels.split_at_mut(level);
+            let (finer, coarser) = (&finer[level - 1], &mut coarser[0]);
+            for (x, y) in dirty.iter() {
+                coarser.values[y * coarser.width + x] = finer.pooled(*x, *y);
+            }
+            dirty = dirty.iter().map(|(x, y)| (x / 2, y / 2)).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_max_pooling() {
+        let mapping = room_mapping(0.05);
+        let pyramid = MapPyramid::new(&mapping, 4);
+
+        assert_eq!(pyramid.levels(), 4);
+        assert_eq!(pyramid.level(0).map_size(), mapping.map_size());
+        assert_eq!(pyramid.level(3).map_size(), (20, 15));
+        assert_approx_eq!(pyramid.level(3).resolution(), 0.4);
+        assert_approx_eq!(
+            pyramid.level(3).min_point().x,
+            mapping.min_point().x + 0.175
+        );
+
+        let (width, height) = pyramid.level(2).map_size();
+        for y in 0..height {
+            for x in 0..width {
+                let expected = (0..4)
+                    .flat_map(|dy| (0..4).map(move |dx| Grid::new(4 * x + dx, 4 * y + dy)))
+                    .filter_map(|grid| mapping.map_element(&grid))
+                    .map(|element| element.probability)
+                    .reduce(f64::max);
+                assert_eq!(pyramid.level(2).probability(&Grid::new(x, y)), expected);
+            }
+        }
+
+        // Walls stay occupied at every level.
+        for level in 0..pyramid.levels() {
+            let probability = pyramid
+                .level(level)
+                .probability_at(&Position::new(3.0, 0.0))
+                .unwrap();
+            assert!(probability > 0.5);
+        }
+    }
+
+    #[test]
+    fn test_update_from_changes() {
+        let mut mapping = Mapping::new(
+            Position::new(-4.0, -3.0),
+            Position::new(4.0, 3.0),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+        let mut pyramid = MapPyramid::new(&mapping, 3);
+
+        for pose in [
+            Pose2::new(0.0, 0.0, 0.0),
+            Pose2::new(-1.5, -1.0, 0.5),
+            Pose2::new(0.5, 1.0, -1.0),
+            Pose2::new(5.0, 4.0, 0.0),
+        ] {
+            let changes = mapping.update(&isometry(&pose), &room_scan(&pose));
+            pyramid.update_from_changes(&mapping, &changes);
+
+            let expected = MapPyramid::new(&mapping, 3);
+            for level in 0..3 {
+                assert_eq!(
+                    pyramid.level(level).map_size(),
+                    expected.level(level).map_size()
+                );
+                assert_eq!(pyramid.level(level).values, expected.level(level).values);
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+/// Occupancy maps in the ROS `map_server` format
+use crate::*;
+use grid_map::{Grid, Position};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use yaml_rust2::{Yaml, YamlLoader};
+
+pub const DEFAULT_MAP_SERVER_OCCUPIED_THRESH: f64 = 0.65;
+/// Just below the occupancy of the unknown shade 205, so that it loads as unknown.
+pub const DEFAULT_MAP_SERVER_FREE_THRESH: f64 = 0.196;
+
+const PGM_OCCUPIED: u8 = 0;
+const PGM_FREE: u8 = 254;
+const PGM_UNKNOWN: u8 = 205;
+
+/// Contents of the YAML file of a `map_server` map.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapServerMetadata {
+    /// Path of the image, relative to the YAML file unless absolute.
+    pub image: PathBuf,
+    pub resolution: f64,
+    /// Pose (x, y, yaw) of the lower left corner of the lower left pixel. [m, m, rad]
+    pub origin: (f64, f64, f64),
+    /// Whether white rather than black means occupied.
+    pub negate: bool,
+    pub occupied_thresh: f64,
+    pub free_thresh: f64,
+}
+
+impl MapServerMetadata {
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(yaml).map_err(invalid_data)?;
+        let doc = docs
+            .first()
+            .ok_or_else(|| invalid_data("empty map metadata"))?;
+
+        let number = |key: &str| -> Result<f64> {
+            yaml_number(&doc[key]).ok_or_else(|| invalid_data(format!("missing `{}`", key)))
+        };
+        let origin = doc["origin"]
+            .as_vec()
+            .filter(|origin| origin.len() == 3)
+            .and_then(|origin| {
+                Some((
+                    yaml_number(&origin[0])?,
+                    yaml_number(&origin[1])?,
+                    yaml_number(&origin[2])?,
+                ))
+            })
+            .ok_or_else(|| invalid_data("`origin` must be [x, y, yaw]"))?;
+        let negate = match &doc["negate"] {
+            Yaml::Integer(negate) => *negate != 0,
+            Yaml::Boolean(negate) => *negate,
+            Yaml::BadValue => false,
+            _ => return Err(invalid_data("`negate` must be 0 or 1")),
+        };
+
+        Ok(Self {
+            image: PathBuf::from(
+                doc["image"]
+                    .as_str()
+                    .ok_or_else(|| invalid_data("missing `image`"))?,
+            ),
+            resolution: number("resolution")?,
+            origin,
+            negate,
+            occupied_thresh: number("occupied_thresh")?,
+            free_thresh: number("free_thresh")?,
+        })
+    }
+
+    pub fn to_yaml(&self) -> String {
+        format!(
+            "image: {}\nresolution: {}\norigin: [{}, {}, {}]\n\
+             negate: {}\noccupied_thresh: {}\nfree_thresh: {}\n",
+            self.image.display(),
+            self.resolution,
+            self.origin.0,
+            self.origin.1,
+            self.origin.2,
+            self.negate as u8,
+            self.occupied_thresh,
+            self.free_thresh,
+        )
+    }
+}
+
+impl Mapping {
+    /// Saves the map as `yaml_path` and a PGM image next to it with the same file stem.
+    ///
+    /// Cells are written as occupied, free or unknown (trinary mode) with the default
+    /// thresholds.
+    pub fn save_map_server(&self, yaml_path: impl AsRef<Path>) -> Result<()> {
+        let yaml_path = yaml_path.as_ref();
+        let image_path = yaml_path.with_extension("pgm");
+        let Some(image_name) = image_path.file_name() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the map path has no file name",
+            ));
+        };
+        let metadata = MapServerMetadata {
+            image: PathBuf::from(image_name),
+            resolution: self.resolution(),
+            origin: (
+                self.min_point().x - self.resolution() / 2.0,
+                self.min_point().y - self.resolution() / 2.0,
+                0.0,
+            ),
+            negate: false,
+            occupied_thresh: DEFAULT_MAP_SERVER_OCCUPIED_THRESH,
+            free_thresh: DEFAULT_MAP_SERVER_FREE_THRESH,
+        };
+
+        // Image rows go from the top of the map down.
+        let (width, height) = self.map_size();
+        let mut pgm = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+        for y in (0..height).rev() {
+            for x in 0..width {
+                pgm.push(match self.map_element(&Grid::new(x, y)) {
+                    Some(element) if element.probability > metadata.occupied_thresh => PGM_OCCUPIED,
+                    Some(element) if element.probability < metadata.free_thresh => PGM_FREE,
+                    _ => PGM_UNKNOWN,
+                });
+            }
+        }
+
+        std::fs::write(&image_path, pgm)?;
+        std::fs::write(yaml_path, metadata.to_yaml())
+    }
+
+    /// Loads a map saved by `map_server` or `Mapping::save_map_server`.
+    ///
+    /// Occupied and free cells get the log-odds limits of the map and cells between the
+    /// thresholds are left unexplored.
+    pub fn load_map_server(yaml_path: impl AsRef<Path>) -> Result<Self> {
+        let yaml_path = yaml_path.as_ref();
+        let metadata = MapServerMetadata::from_yaml(&std::fs::read_to_string(yaml_path)?)?;
+        if metadata.origin.2 != 0.0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "maps with a rotated origin are not supported",
+            ));
+        }
+        let image_path = match yaml_path.parent() {
+            Some(directory) => directory.join(&metadata.image),
+            None => metadata.image.clone(),
+        };
+        let (width, height, pixels) = read_pgm(&std::fs::read(image_path)?)?;
+
+        let resolution = metadata.resolution;
+        let min_point = Position::new(
+            metadata.origin.0 + resolution / 2.0,
+            metadata.origin.1 + resolution / 2.0,
+        );
+        let max_point = Position::new(
+            min_point.x + (width as f64 - 0.5) * resolution,
+            min_point.y + (height as f64 - 0.5) * resolution,
+        );
+        let mut mapping = Mapping::new(
+            min_point,
+            max_point,
+            resolution,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+
+        let (min_log_odds, max_log_odds) = mapping.log_odds_limits();
+        for (i, shade) in pixels.into_iter().enumerate() {
+            let occupancy = if metadata.negate { shade } else { 1.0 - shade };
+            let log_odds = if occupancy > metadata.occupied_thresh {
+                max_log_odds
+            } else if occupancy < metadata.free_thresh {
+                min_log_odds
+            } else {
+                continue;
+            };
+            let grid = Grid::new(i % width, height - 1 - i / width);
+            mapping.set_map_element(&grid, MapElement::from_log_odds(log_odds));
+        }
+
+        Ok(mapping)
+    }
+}
+
+/// Reads a binary (P5) or plain (P2) PGM image into (width, height, shades in [0, 1]).
+fn read_pgm(bytes: &[u8]) -> Result<(usize, usize, Vec<f64>)> {
+    // The header is four whitespace separated fields, with comments starting at '#'.
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while fields.len() < 4 {
+        while position < bytes.len() && bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if position < bytes.len() && bytes[position] == b'#' {
+            while position < bytes.len() && bytes[position] != b'\n' {
+                position += 1;
+            }
+            continue;
+        }
+        let start = position;
+        while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            return Err(invalid_data("truncated PGM header"));
+        }
+        fields.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+    }
+
+    let parse = |field: &str| -> Result<usize> {
+        field
+            .parse()
+            .map_err(|_| invalid_data(format!("invalid PGM header field `{}`", field)))
+    };
+    let (width, height, max_value) = (parse(&fields[1])?, parse(&fields[2])?, parse(&fields[3])?);
+    if max_value == 0 || max_value > u16::MAX as usize {
+        return Err(invalid_data("invalid PGM maximum value"));
+    }
+
+    let values: Vec<usize> = match fields[0].as_str() {
+        "P5" => {
+            // A single whitespace separates the header from the raster.
+            let raster = &bytes[(position + 1).min(bytes.len())..];
+            if max_value < 256 {
+                raster.iter().map(|value| *value as usize).collect()
+            } else {
+                raster
+                    .chunks_exact(2)
+                    .map(|value| u16::from_be_bytes([value[0], value[1]]) as usize)
+                    .collect()
+            }
+        }
+        "P2" => String::from_utf8_lossy(&bytes[position..])
+            .split_ascii_whitespace()
+            .map(parse)
+            .collect::<Result<_>>()?,
+        magic => {
+            return Err(invalid_data(format!(
+                "unsupported image format `{}`",
+                magic
+            )));
+        }
+    };
+    if values.len() < width * height {
+        return Err(invalid_data("truncated PGM raster"));
+    }
+
+    let shades = values
+        .iter()
+        .take(width * height)
+        .map(|value| *value as f64 / max_value as f64)
+        .collect();
+    Ok((width, height, shades))
+}
+
+fn yaml_number(yaml: &Yaml) -> Option<f64> {
+    yaml.as_f64()
+        .or_else(|| yaml.as_i64().map(|value| value as f64))
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::new(ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_map_server_round_trip() {
+        let mapping = room_mapping(0.05);
+        let yaml_path = std::env::temp_dir().join("slam_test_map_server_round_trip.yaml");
+
+        mapping.save_map_server(&yaml_path).unwrap();
+        let loaded = Mapping::load_map_server(&yaml_path).unwrap();
+
+        assert_eq!(loaded.map_size(), mapping.map_size());
+        assert_approx_eq!(loaded.resolution(), mapping.resolution());
+        assert_approx_eq!(loaded.min_point().x, mapping.min_point().x);
+        assert_approx_eq!(loaded.min_point().y, mapping.min_point().y);
+
+        let (width, height) = mapping.map_size();
+        let mut occupied = 0;
+        for y in 0..height {
+            for x in 0..width {
+                let grid = Grid::new(x, y);
+                let classify = |element: Option<MapElement>| {
+                    element
+                        .map(|element| element.probability)
+                        .filter(|probability| {
+                            *probability > DEFAULT_MAP_SERVER_OCCUPIED_THRESH
+                                || *probability < DEFAULT_MAP_SERVER_FREE_THRESH
+                        })
+                        .map(|probability| probability > 0.5)
+                };
+                assert_eq!(
+                    classify(loaded.map_element(&grid)),
+                    classify(mapping.map_element(&grid))
+                );
+                occupied += loaded.map_element(&grid).is_some_and(|e| e.is_occupied()) as usize;
+            }
+        }
+        assert!(occupied > 0);
+
+        std::fs::remove_file(&yaml_path).unwrap();
+        std::fs::remove_file(yaml_path.with_extension("pgm")).unwrap();
+    }
+
+    #[test]
+    fn test_save_without_file_name() {
+        let mapping = room_mapping(0.1);
+        for path in ["..", "/"] {
+            let error = mapping.save_map_server(path).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_load_ros_map() {
+        let directory = std::env::temp_dir();
+        let yaml_path = directory.join("slam_test_load_ros_map.yaml");
+        std::fs::write(
+            &yaml_path,
+            "image: slam_test_load_ros_map.pgm\nmode: trinary\nresolution: 0.1\n\
+             origin: [-1.0, 2, 0.0]\nnegate: 1\noccupied_thresh: 0.65\nfree_thresh: 0.25\n",
+        )
+        .unwrap();
+        // Negated, so white is occupied. The top row is the highest y.
+        std::fs::write(
+            directory.join("slam_test_load_ros_map.pgm"),
+            "P2\n# CREATOR: test\n3 2\n15\n15 0 8\n0 0 15\n",
+        )
+        .unwrap();
+
+        let mapping = Mapping::load_map_server(&yaml_path).unwrap();
+
+        assert_eq!(mapping.map_size(), (3, 2));
+        assert_approx_eq!(mapping.min_point().x, -0.95);
+        assert_approx_eq!(mapping.min_point().y, 2.05);
+        assert!(mapping.map_element(&Grid::new(0, 1)).unwrap().is_occupied());
+        assert!(!mapping.map_element(&Grid::new(1, 1)).unwrap().is_occupied());
+        assert_eq!(mapping.map_element(&Grid::new(2, 1)), None);
+        assert!(mapping.map_element(&Grid::new(2, 0)).unwrap().is_occupied());
+        assert_eq!(mapping.get_occupied_grids_positions().len(), 2);
+
+        std::fs::remove_file(&yaml_path).unwrap();
+        std::fs::remove_file(directory.join("slam_test_load_ros_map.pgm")).unwrap();
+    }
+}
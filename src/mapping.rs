@@ -1,10 +1,23 @@
 /// Occupancy grid mapping
 use crate::*;
-use grid_map::{Cell, Grid, GridMap, Position};
+use grid_map::{Grid, Position};
 use nalgebra as na;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use std::sync::Arc;
 
 pub const DEFAULT_PROBABILITY_FREE_SPACE: f64 = 0.2;
 pub const DEFAULT_PROBABILITY_OCCUPIED_SPACE: f64 = 0.8;
+/// Edge length of the tiles the map allocates as it grows. [cells]
+pub const MAPPING_TILE_SIZE: usize = 64;
+/// Log-odds of a probability of occupancy of about 0.12.
+pub const DEFAULT_MIN_LOG_ODDS: f64 = -2.0;
+/// Log-odds of a probability of occupancy of about 0.97.
+pub const DEFAULT_MAX_LOG_ODDS: f64 = 3.5;
+/// Version of the format written by `Mapping::write_map_file`.
+pub const MAP_FILE_VERSION: u32 = 1;
+const MAP_FILE_MAGIC: &[u8; 8] = b"SLAMMAP\0";
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct MapElement {
@@ -15,6 +28,13 @@ pub struct MapElement {
 }
 
 impl MapElement {
+    pub fn from_log_odds(log_odds: f64) -> Self {
+        Self {
+            log_odds,
+            probability: 1.0 / (1.0 + (-log_odds).exp()),
+        }
+    }
+
     pub fn is_occupied(&self) -> bool {
         self.probability > 0.5
     }
@@ -30,15 +50,68 @@ impl Default for MapElement {
     }
 }
 
+/// How `Mapping::update` uses beams without a return, i.e. infinite or beyond `range_max`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NoReturnPolicy {
+    /// The beams are dropped.
+    #[default]
+    Ignore,
+    /// The cells along the beams are marked free up to `range_max` of the scan.
+    ClearToRangeMax,
+    /// The cells along the beams are marked free up to the given distance. [m]
+    ClearToDistance(f64),
+}
+
+/// Encoding of the cells in a map file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MapFileCompression {
+    /// Every cell is stored.
+    #[default]
+    None,
+    /// Runs of equal cells are stored once, which shrinks the large free and unexplored areas.
+    RunLength,
+}
+
+/// Cell changed by `Mapping::update`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapChange {
+    /// Index of the cell, relative to `Mapping::min_point` after the update.
+    pub grid: Grid,
+    /// Element before the update, `None` if the cell was unexplored.
+    pub old: Option<MapElement>,
+    /// Element after the update.
+    pub new: MapElement,
+}
+
 /// Occupancy grid mapping
+///
+/// Cells are stored in square tiles that are allocated when a ray enters them, so the map grows
+/// in every direction as needed.
+///
+/// Clones share their tiles until they write to them, so many copies of a map, e.g. one per
+/// particle of a particle filter, cost only the tiles in which they differ.
+#[derive(Clone)]
 pub struct Mapping {
-    /// Elements in the grid map are the log-odds of the probability of occupancy.
-    grid_map: GridMap<MapElement>,
-    probability_free_space: f64,
-    probability_occupied_space: f64,
+    /// Tiles of `MAPPING_TILE_SIZE` x `MAPPING_TILE_SIZE` cells, keyed by tile index.
+    /// Elements are the log-odds of the probability of occupancy.
+    tiles: HashMap<(i64, i64), Arc<Vec<Option<MapElement>>>>,
+    /// Centre of the cell with index (0, 0), the lower left cell of the initial map. [m]
+    origin: Position,
+    resolution: f64,
+    /// Cell indices of the initial map, (min, max) inclusive.
+    initial_extent: ((i64, i64), (i64, i64)),
+    /// Cell indices covered so far, (min, max) inclusive.
+    extent: ((i64, i64), (i64, i64)),
+    /// Centre of the lower left cell of the current extent. [m]
+    min_point: Position,
+    inverse_sensor_model: Arc<dyn InverseSensorModel>,
+    /// (min, max) log-odds of a cell.
+    log_odds_limits: (f64, f64),
+    no_return_policy: NoReturnPolicy,
 }
 
 impl Mapping {
+    /// Creates an empty map that initially covers the box between the points.
     pub fn new(
         min_point: Position,
         max_point: Position,
@@ -46,114 +119,860 @@ impl Mapping {
         probability_free_space: f64,
         probability_occupied_space: f64,
     ) -> Self {
-        let mut grid_map = GridMap::new(min_point, max_point, resolution);
-        for cell in grid_map.cells_mut() {
-            *cell = Cell::Uninitialized;
-        }
+        let width = ((max_point.x - min_point.x) / resolution).ceil().max(1.0) as i64;
+        let height = ((max_point.y - min_point.y) / resolution).ceil().max(1.0) as i64;
+        let initial_extent = ((0, 0), (width - 1, height - 1));
         Self {
-            grid_map,
-            probability_free_space,
-            probability_occupied_space,
+            tiles: HashMap::new(),
+            origin: Position::new(min_point.x, min_point.y),
+            resolution,
+            initial_extent,
+            extent: initial_extent,
+            min_point,
+            inverse_sensor_model: Arc::new(ConstantInverseSensorModel::new(
+                probability_free_space,
+                probability_occupied_space,
+            )),
+            log_odds_limits: (DEFAULT_MIN_LOG_ODDS, DEFAULT_MAX_LOG_ODDS),
+            no_return_policy: NoReturnPolicy::default(),
         }
     }
 
+    /// Forgets every cell and shrinks the map back to its initial extent.
     pub fn init(&mut self) {
-        for cell in self.grid_map.cells_mut() {
-            *cell = Cell::Uninitialized;
-        }
+        self.tiles.clear();
+        self.extent = self.initial_extent;
+        self.min_point = Position::new(self.origin.x, self.origin.y);
     }
 
-    pub fn update(&mut self, current_position: &na::Isometry2<f64>, laser_scan: &LaserScan) {
+    /// Integrates a scan taken at `current_position` and returns the cells it changed, sorted
+    /// by grid index.
+    pub fn update(
+        &mut self,
+        current_position: &na::Isometry2<f64>,
+        laser_scan: &LaserScan,
+    ) -> Vec<MapChange> {
+        // Elements before the update, by cell index.
+        let mut old_elements = HashMap::new();
+
         let points: Pointcloud2 = laser_scan.clone().into();
         let points = coordinate_transformation(current_position, points.points());
 
         let current_position_translation = current_position.translation;
+        let inverse_sensor_model = self.inverse_sensor_model.clone();
+        let hit_radius = inverse_sensor_model.hit_radius();
 
         for point in points {
-            let grids = bresenham_algorithm(
+            let cells = ray_traversal2(
                 &current_position_translation,
                 &point,
-                self.grid_map.resolution(),
-                self.grid_map.min_point(),
+                self.resolution,
+                &self.origin,
             );
+            let Some((last_cell, free_cells)) = cells.split_last() else {
+                continue;
+            };
+            let range = (point.vector - current_position_translation.vector).norm();
 
-            for grid in grids.iter().take(grids.len() - 1) {
-                let cell = self.grid_map.cell_mut(grid).unwrap();
-                if cell.is_uninitialized() {
-                    *cell = Cell::from_value(MapElement::default());
+            for cell in free_cells {
+                let grid = (cell.index[0], cell.index[1]);
+                if (self.cell_center(grid) - point.vector).norm() <= hit_radius {
+                    continue;
                 }
-                let log_odds = cell.value().unwrap().log_odds + self.probability_free_space.ln()
-                    - self.probability_occupied_space.ln();
-                let probability = 1.0 / (1.0 + (-log_odds).exp());
-                *cell = Cell::from_value(MapElement {
-                    log_odds,
-                    probability,
-                });
+                let distance = (cell.entry + cell.exit) / 2.0;
+                self.update_cell(
+                    grid,
+                    inverse_sensor_model.free_log_odds(distance, range),
+                    &mut old_elements,
+                );
             }
-            let last_grid = grids.last().unwrap();
-            let cell = self.grid_map.cell_mut(last_grid).unwrap();
-            if cell.is_uninitialized() {
-                *cell = Cell::from_value(MapElement::default());
+
+            let last_grid = (last_cell.index[0], last_cell.index[1]);
+            let reach = (hit_radius / self.resolution).ceil() as i64;
+            for dy in -reach..=reach {
+                for dx in -reach..=reach {
+                    let grid = (last_grid.0 + dx, last_grid.1 + dy);
+                    let offset = (self.cell_center(grid) - point.vector).norm();
+                    if (dx, dy) != (0, 0) && offset > hit_radius {
+                        continue;
+                    }
+                    self.update_cell(
+                        grid,
+                        inverse_sensor_model.hit_log_odds(offset, range),
+                        &mut old_elements,
+                    );
+                }
             }
-            let log_odds = cell.value().unwrap().log_odds + self.probability_occupied_space.ln()
-                - self.probability_free_space.ln();
-            let probability = 1.0 / (1.0 + (-log_odds).exp());
-            *cell = Cell::from_value(MapElement {
-                log_odds,
-                probability,
-            });
         }
 
-        // TODO: Return updated grid list
+        // Beams without a return only tell that the space along them is free.
+        let clearing_distance = match self.no_return_policy {
+            NoReturnPolicy::Ignore => None,
+            NoReturnPolicy::ClearToRangeMax => Some(laser_scan.range_max()),
+            NoReturnPolicy::ClearToDistance(distance) => Some(distance),
+        };
+        if let Some(clearing_distance) = clearing_distance {
+            self.clear_no_return_beams(
+                current_position,
+                laser_scan,
+                clearing_distance,
+                &mut old_elements,
+            );
+        }
+
+        let (min_x, min_y) = self.extent.0;
+        let mut changes = old_elements
+            .into_iter()
+            .filter_map(|(index, old)| {
+                let new = self.element(index).unwrap();
+                (old != Some(new)).then_some((index, old, new))
+            })
+            .collect::<Vec<_>>();
+        changes.sort_by_key(|(index, _, _)| (index.1, index.0));
+        changes
+            .into_iter()
+            .map(|(index, old, new)| MapChange {
+                grid: Grid::new((index.0 - min_x) as usize, (index.1 - min_y) as usize),
+                old,
+                new,
+            })
+            .collect()
+    }
+
+    /// Marks the cells along the beams without a return as free up to `clearing_distance`.
+    fn clear_no_return_beams(
+        &mut self,
+        current_position: &na::Isometry2<f64>,
+        laser_scan: &LaserScan,
+        clearing_distance: f64,
+        old_elements: &mut HashMap<(i64, i64), Option<MapElement>>,
+    ) {
+        let current_position_translation = current_position.translation;
+        let inverse_sensor_model = self.inverse_sensor_model.clone();
+
+        let no_return_points = laser_scan
+            .ranges()
+            .iter()
+            .enumerate()
+            .filter(|(_, range)| {
+                **range == f64::INFINITY || (range.is_finite() && **range > laser_scan.range_max())
+            })
+            .map(|(i, _)| {
+                let angle = laser_scan.angle_min() + i as f64 * laser_scan.angle_increment();
+                Point2::new(
+                    clearing_distance * angle.cos(),
+                    clearing_distance * angle.sin(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let no_return_points = coordinate_transformation(current_position, &no_return_points);
+
+        for point in no_return_points {
+            let cells = ray_traversal2(
+                &current_position_translation,
+                &point,
+                self.resolution,
+                &self.origin,
+            );
+            for cell in cells {
+                let distance = (cell.entry + cell.exit) / 2.0;
+                self.update_cell(
+                    (cell.index[0], cell.index[1]),
+                    inverse_sensor_model.free_log_odds(distance, f64::INFINITY),
+                    old_elements,
+                );
+            }
+        }
+    }
+
+    /// Adds log-odds to a cell, clamped to the limits, and grows the map to cover it.
+    ///
+    /// The element before the first update of the cell is kept in `old_elements`.
+    fn update_cell(
+        &mut self,
+        index: (i64, i64),
+        log_odds_update: f64,
+        old_elements: &mut HashMap<(i64, i64), Option<MapElement>>,
+    ) {
+        let (min_log_odds, max_log_odds) = self.log_odds_limits;
+        let (tile_index, offset) = tile_index(index);
+        let element = &mut self.tile_mut(tile_index)[offset];
+        old_elements.entry(index).or_insert(*element);
+
+        let log_odds = (element.unwrap_or_default().log_odds + log_odds_update)
+            .clamp(min_log_odds, max_log_odds);
+        *element = Some(MapElement::from_log_odds(log_odds));
+
+        self.grow_extent(index);
+    }
+
+    fn grow_extent(&mut self, index: (i64, i64)) {
+        let ((min_x, min_y), (max_x, max_y)) = self.extent;
+        if index.0 < min_x || index.1 < min_y || index.0 > max_x || index.1 > max_y {
+            self.extent = (
+                (min_x.min(index.0), min_y.min(index.1)),
+                (max_x.max(index.0), max_y.max(index.1)),
+            );
+            let center = self.cell_center(self.extent.0);
+            self.min_point = Position::new(center.x, center.y);
+        }
+    }
+
+    fn set_element(&mut self, index: (i64, i64), element: MapElement) {
+        let (tile_index, offset) = tile_index(index);
+        self.tile_mut(tile_index)[offset] = Some(element);
+        self.grow_extent(index);
+    }
+
+    /// Cells of a tile for writing, allocating the tile or copying it if a clone shares it.
+    fn tile_mut(&mut self, tile_index: (i64, i64)) -> &mut Vec<Option<MapElement>> {
+        Arc::make_mut(
+            self.tiles
+                .entry(tile_index)
+                .or_insert_with(|| Arc::new(vec![None; MAPPING_TILE_SIZE * MAPPING_TILE_SIZE])),
+        )
+    }
+
+    fn element(&self, index: (i64, i64)) -> Option<MapElement> {
+        let (tile_index, offset) = tile_index(index);
+        self.tiles.get(&tile_index)?[offset]
+    }
+
+    fn cell_center(&self, index: (i64, i64)) -> na::Vector2<f64> {
+        na::Vector2::new(
+            self.origin.x + index.0 as f64 * self.resolution,
+            self.origin.y + index.1 as f64 * self.resolution,
+        )
+    }
+
+    pub fn inverse_sensor_model(&self) -> &dyn InverseSensorModel {
+        self.inverse_sensor_model.as_ref()
+    }
+
+    /// Replaces the model built from the probabilities given to `Mapping::new`.
+    pub fn set_inverse_sensor_model(
+        &mut self,
+        inverse_sensor_model: impl InverseSensorModel + 'static,
+    ) {
+        self.inverse_sensor_model = Arc::new(inverse_sensor_model);
+    }
+
+    /// Returns the (min, max) log-odds of a cell.
+    pub fn log_odds_limits(&self) -> (f64, f64) {
+        self.log_odds_limits
+    }
+
+    /// Clamps the log-odds of every cell to [min, max], so that cells can still change when the
+    /// environment does.
+    pub fn set_log_odds_limits(&mut self, min_log_odds: f64, max_log_odds: f64) {
+        self.log_odds_limits = (min_log_odds, max_log_odds);
+    }
+
+    pub fn no_return_policy(&self) -> NoReturnPolicy {
+        self.no_return_policy
+    }
+
+    pub fn set_no_return_policy(&mut self, no_return_policy: NoReturnPolicy) {
+        self.no_return_policy = no_return_policy;
     }
 
     pub fn resolution(&self) -> f64 {
-        self.grid_map.resolution()
+        self.resolution
     }
 
+    /// Number of cells (width, height) of the current extent.
     pub fn map_size(&self) -> (usize, usize) {
-        (self.grid_map.width(), self.grid_map.height())
+        let ((min_x, min_y), (max_x, max_y)) = self.extent;
+        ((max_x - min_x + 1) as usize, (max_y - min_y + 1) as usize)
     }
 
+    /// Centre of the lower left cell of the current extent, i.e. of `Grid::new(0, 0)`.
     pub fn min_point(&self) -> &Position {
-        self.grid_map.min_point()
+        &self.min_point
+    }
+
+    /// Returns the element of an explored cell.
+    pub fn map_element(&self, grid: &Grid) -> Option<MapElement> {
+        let (width, height) = self.map_size();
+        if grid.x >= width || grid.y >= height {
+            return None;
+        }
+        let (min_x, min_y) = self.extent.0;
+        self.element((min_x + grid.x as i64, min_y + grid.y as i64))
+    }
+
+    /// Overwrites the element of a cell, growing the map to cover it.
+    pub fn set_map_element(&mut self, grid: &Grid, element: MapElement) {
+        let (min_x, min_y) = self.extent.0;
+        self.set_element((min_x + grid.x as i64, min_y + grid.y as i64), element);
     }
 
     pub fn get_explored_grids_positions(&self) -> Vec<Position> {
-        let mut explored_grids = Vec::new();
-        let width = self.grid_map.width();
-        let height = self.grid_map.height();
-        for h in 0..height {
-            for w in 0..width {
-                let grid = Grid::new(w, h);
-                if let Some(cell) = self.grid_map.cell(&grid) {
-                    if !cell.is_uninitialized() {
-                        let x = self.grid_map.min_point().x + w as f64 * self.grid_map.resolution();
-                        let y = self.grid_map.min_point().y + h as f64 * self.grid_map.resolution();
-                        explored_grids.push(Position::new(x, y));
+        self.grids_positions(|_| true)
+    }
+
+    pub fn get_occupied_grids_positions(&self) -> Vec<Position> {
+        self.grids_positions(|element| element.is_occupied())
+    }
+
+    fn grids_positions(&self, filter: impl Fn(&MapElement) -> bool) -> Vec<Position> {
+        let mut grids = Vec::new();
+        let ((min_x, min_y), (max_x, max_y)) = self.extent;
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                if self.element((x, y)).is_some_and(|element| filter(&element)) {
+                    let center = self.cell_center((x, y));
+                    grids.push(Position::new(center.x, center.y));
+                }
+            }
+        }
+        grids
+    }
+}
+
+/// Map files store the whole state of the map, so that mapping can be resumed after loading.
+///
+/// The file is little endian: a header with the magic bytes, the version and the compression,
+/// the parameters of the map and the tiles. Inverse sensor models without `parameters` are not
+/// stored and load as the default `ConstantInverseSensorModel`.
+impl Mapping {
+    pub fn save_map_file(
+        &self,
+        path: impl AsRef<Path>,
+        compression: MapFileCompression,
+    ) -> Result<()> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_map_file(&mut writer, compression)?;
+        writer.flush()
+    }
+
+    pub fn load_map_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_map_file(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn write_map_file(
+        &self,
+        writer: &mut impl Write,
+        compression: MapFileCompression,
+    ) -> Result<()> {
+        writer.write_all(MAP_FILE_MAGIC)?;
+        writer.write_all(&MAP_FILE_VERSION.to_le_bytes())?;
+        writer.write_all(&[match compression {
+            MapFileCompression::None => 0,
+            MapFileCompression::RunLength => 1,
+        }])?;
+
+        write_f64s(writer, &[self.resolution, self.origin.x, self.origin.y])?;
+        for ((min_x, min_y), (max_x, max_y)) in [self.initial_extent, self.extent] {
+            for value in [min_x, min_y, max_x, max_y] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        write_f64s(writer, &[self.log_odds_limits.0, self.log_odds_limits.1])?;
+        match self.no_return_policy {
+            NoReturnPolicy::Ignore => writer.write_all(&[0])?,
+            NoReturnPolicy::ClearToRangeMax => writer.write_all(&[1])?,
+            NoReturnPolicy::ClearToDistance(distance) => {
+                writer.write_all(&[2])?;
+                write_f64s(writer, &[distance])?;
+            }
+        }
+        match self.inverse_sensor_model.parameters() {
+            None => writer.write_all(&[0])?,
+            Some(InverseSensorModelParameters::Constant(model)) => {
+                writer.write_all(&[1])?;
+                write_f64s(writer, &[model.free_log_odds, model.hit_log_odds])?;
+            }
+            Some(InverseSensorModelParameters::Gaussian(model)) => {
+                writer.write_all(&[2])?;
+                write_f64s(
+                    writer,
+                    &[
+                        model.free_log_odds,
+                        model.hit_log_odds,
+                        model.sigma,
+                        model.half_confidence_range,
+                    ],
+                )?;
+            }
+        }
+
+        let mut tile_indices = self.tiles.keys().copied().collect::<Vec<_>>();
+        tile_indices.sort();
+        writer.write_all(&(tile_indices.len() as u64).to_le_bytes())?;
+        for tile_index in tile_indices {
+            writer.write_all(&tile_index.0.to_le_bytes())?;
+            writer.write_all(&tile_index.1.to_le_bytes())?;
+            let cells = self.tiles[&tile_index].as_slice();
+            match compression {
+                MapFileCompression::None => {
+                    for cell in cells {
+                        write_cell(writer, cell)?;
+                    }
+                }
+                MapFileCompression::RunLength => {
+                    for run in cells.chunk_by(|a, b| a == b) {
+                        writer.write_all(&(run.len() as u16).to_le_bytes())?;
+                        write_cell(writer, &run[0])?;
                     }
                 }
             }
         }
-        explored_grids
+
+        Ok(())
     }
 
-    pub fn get_occupied_grids_positions(&self) -> Vec<Position> {
-        let mut occupied_grids = Vec::new();
-        let width = self.grid_map.width();
-        let height = self.grid_map.height();
-        for h in 0..height {
-            for w in 0..width {
-                let grid = Grid::new(w, h);
-                if let Some(cell) = self.grid_map.cell(&grid) {
-                    if cell.has_value() && cell.value().unwrap().is_occupied() {
-                        let x = self.grid_map.min_point().x + w as f64 * self.grid_map.resolution();
-                        let y = self.grid_map.min_point().y + h as f64 * self.grid_map.resolution();
-                        occupied_grids.push(Position::new(x, y));
+    pub fn read_map_file(reader: &mut impl Read) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAP_FILE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a map file"));
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != MAP_FILE_VERSION {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported map file version {}", version),
+            ));
+        }
+        let compression = match read_u8(reader)? {
+            0 => MapFileCompression::None,
+            1 => MapFileCompression::RunLength,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unknown compression")),
+        };
+
+        let resolution = read_f64(reader)?;
+        let origin = Position::new(read_f64(reader)?, read_f64(reader)?);
+        let mut extents = [((0, 0), (0, 0)); 2];
+        for extent in extents.iter_mut() {
+            *extent = (
+                (read_i64(reader)?, read_i64(reader)?),
+                (read_i64(reader)?, read_i64(reader)?),
+            );
+        }
+        let log_odds_limits = (read_f64(reader)?, read_f64(reader)?);
+        let no_return_policy = match read_u8(reader)? {
+            0 => NoReturnPolicy::Ignore,
+            1 => NoReturnPolicy::ClearToRangeMax,
+            2 => NoReturnPolicy::ClearToDistance(read_f64(reader)?),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "unknown no-return policy",
+                ))
+            }
+        };
+        let inverse_sensor_model: Arc<dyn InverseSensorModel> = match read_u8(reader)? {
+            0 => Arc::new(ConstantInverseSensorModel::default()),
+            1 => Arc::new(ConstantInverseSensorModel {
+                free_log_odds: read_f64(reader)?,
+                hit_log_odds: read_f64(reader)?,
+            }),
+            2 => Arc::new(GaussianInverseSensorModel {
+                free_log_odds: read_f64(reader)?,
+                hit_log_odds: read_f64(reader)?,
+                sigma: read_f64(reader)?,
+                half_confidence_range: read_f64(reader)?,
+            }),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "unknown inverse sensor model",
+                ))
+            }
+        };
+
+        let mut length = [0; 8];
+        reader.read_exact(&mut length)?;
+        let tile_cells = MAPPING_TILE_SIZE * MAPPING_TILE_SIZE;
+        let mut tiles = HashMap::new();
+        for _ in 0..u64::from_le_bytes(length) {
+            let tile_index = (read_i64(reader)?, read_i64(reader)?);
+            let mut cells = Vec::with_capacity(tile_cells);
+            while cells.len() < tile_cells {
+                let run = match compression {
+                    MapFileCompression::None => 1,
+                    MapFileCompression::RunLength => {
+                        let mut run = [0; 2];
+                        reader.read_exact(&mut run)?;
+                        u16::from_le_bytes(run) as usize
                     }
+                };
+                if run == 0 || cells.len() + run > tile_cells {
+                    return Err(Error::new(ErrorKind::InvalidData, "invalid run of cells"));
+                }
+                let cell = read_cell(reader)?;
+                cells.resize(cells.len() + run, cell);
+            }
+            tiles.insert(tile_index, Arc::new(cells));
+        }
+
+        let [initial_extent, extent] = extents;
+        let mut mapping = Self {
+            tiles,
+            origin,
+            resolution,
+            initial_extent,
+            extent,
+            min_point: Position::new(0.0, 0.0),
+            inverse_sensor_model,
+            log_odds_limits,
+            no_return_policy,
+        };
+        let center = mapping.cell_center(extent.0);
+        mapping.min_point = Position::new(center.x, center.y);
+        Ok(mapping)
+    }
+}
+
+fn write_f64s(writer: &mut impl Write, values: &[f64]) -> Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Writes an unexplored cell as a zero byte, an explored one as a one byte and its log-odds.
+fn write_cell(writer: &mut impl Write, cell: &Option<MapElement>) -> Result<()> {
+    match cell {
+        None => writer.write_all(&[0]),
+        Some(element) => {
+            writer.write_all(&[1])?;
+            writer.write_all(&element.log_odds.to_le_bytes())
+        }
+    }
+}
+
+fn read_cell(reader: &mut impl Read) -> Result<Option<MapElement>> {
+    match read_u8(reader)? {
+        0 => Ok(None),
+        1 => Ok(Some(MapElement::from_log_odds(read_f64(reader)?))),
+        _ => Err(Error::new(ErrorKind::InvalidData, "invalid cell")),
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_i64(reader: &mut impl Read) -> Result<i64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+fn read_f64(reader: &mut impl Read) -> Result<f64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+/// Tile index of a cell and the offset of the cell inside the tile.
+fn tile_index(index: (i64, i64)) -> ((i64, i64), usize) {
+    let size = MAPPING_TILE_SIZE as i64;
+    (
+        (index.0.div_euclid(size), index.1.div_euclid(size)),
+        (index.1.rem_euclid(size) * size + index.0.rem_euclid(size)) as usize,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_no_return_policy() {
+        // A beam that hits at 1 m and one without a return, pointing left.
+        let laser_scan = LaserScan::new(
+            2.0,
+            0.1,
+            std::f64::consts::FRAC_PI_2,
+            0.0,
+            std::f64::consts::FRAC_PI_2,
+            0.0,
+            vec![1.0, f64::INFINITY],
+        );
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+        let left = |mapping: &Mapping, y: f64| {
+            let origin = mapping.min_point();
+            let grid = Grid::new(
+                ((0.0 - origin.x) / mapping.resolution()).round() as usize,
+                ((y - origin.y) / mapping.resolution()).round() as usize,
+            );
+            mapping.map_element(&grid)
+        };
+
+        let mut ignoring = mapping_gen();
+        ignoring.update(&isometry(&pose), &laser_scan);
+        assert!(left(&ignoring, 1.0).is_none());
+        assert_eq!(ignoring.get_occupied_grids_positions().len(), 1);
+
+        let mut clearing = mapping_gen();
+        clearing.set_no_return_policy(NoReturnPolicy::ClearToRangeMax);
+        clearing.update(&isometry(&pose), &laser_scan);
+        assert!(!left(&clearing, 1.0).unwrap().is_occupied());
+        assert!(!left(&clearing, 2.0).unwrap().is_occupied());
+        assert!(left(&clearing, 2.2).is_none());
+        assert_eq!(clearing.get_occupied_grids_positions().len(), 1);
+
+        let mut clearing = mapping_gen();
+        clearing.set_no_return_policy(NoReturnPolicy::ClearToDistance(0.5));
+        clearing.update(&isometry(&pose), &laser_scan);
+        assert!(!left(&clearing, 0.5).unwrap().is_occupied());
+        assert!(left(&clearing, 0.7).is_none());
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        // NaN marks an invalid measurement (REP-117) and must not touch the map.
+        let laser_scan = LaserScan::new(
+            5.0,
+            0.1,
+            0.1,
+            0.0,
+            0.2,
+            0.0,
+            vec![f64::NAN, 1.0, f64::NEG_INFINITY],
+        );
+        assert_eq!(Pointcloud2::from(laser_scan.clone()).points().len(), 1);
+
+        let mut mapping = mapping_gen();
+        let changes = mapping.update(&isometry(&Pose2::new(0.0, 0.0, 0.0)), &laser_scan);
+        assert!(!changes.is_empty());
+        assert_eq!(mapping.get_occupied_grids_positions().len(), 1);
+    }
+
+    #[test]
+    fn test_map_grows() {
+        let mut mapping = Mapping::new(
+            Position::new(-1.0, -1.0),
+            Position::new(1.0, 1.0),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+        assert_eq!(mapping.map_size(), (40, 40));
+
+        // The walls of the room are outside the initial map.
+        mapping.update(
+            &isometry(&Pose2::new(0.0, 0.0, 0.0)),
+            &room_scan(&Pose2::new(0.0, 0.0, 0.0)),
+        );
+        assert_approx_eq!(mapping.min_point().x, -3.0);
+        assert_approx_eq!(mapping.min_point().y, -2.0);
+        assert_eq!(mapping.map_size(), (121, 81));
+        let occupied = mapping.get_occupied_grids_positions();
+        assert!(occupied.iter().any(|p| (p.x - 3.0).abs() < 1e-9));
+        assert!(occupied.iter().any(|p| (p.y + 2.0).abs() < 1e-9));
+
+        // Grid indices follow the new lower left corner.
+        let grid = Grid::new(120, 40);
+        assert!(mapping.map_element(&grid).unwrap().is_occupied());
+        assert!(mapping.map_element(&Grid::new(121, 40)).is_none());
+
+        // The robot itself can leave the initial map, too.
+        let pose = Pose2::new(-10.0, 5.0, 0.0);
+        mapping.set_no_return_policy(NoReturnPolicy::ClearToRangeMax);
+        let laser_scan = LaserScan::new(3.0, 0.1, 0.1, 0.0, 0.0, 0.0, vec![f64::INFINITY]);
+        mapping.update(&isometry(&pose), &laser_scan);
+        assert_approx_eq!(mapping.min_point().x, -10.0);
+        assert_approx_eq!(mapping.min_point().y, -2.0);
+        assert_eq!(mapping.map_size(), (261, 141));
+
+        mapping.init();
+        assert_eq!(mapping.map_size(), (40, 40));
+        assert!(mapping.get_explored_grids_positions().is_empty());
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut mapping = mapping_gen();
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+        let laser_scan = LaserScan::new(5.0, 0.1, 0.1, 0.0, 0.1, 0.0, vec![1.0, 1.0]);
+
+        let changes = mapping.update(&isometry(&pose), &laser_scan);
+        assert_eq!(changes.len(), mapping.get_explored_grids_positions().len());
+        assert!(changes.iter().all(|change| change.old.is_none()));
+        assert!(changes
+            .windows(2)
+            .all(|w| (w[0].grid.y, w[0].grid.x) < (w[1].grid.y, w[1].grid.x)));
+        for change in changes.iter() {
+            assert_eq!(mapping.map_element(&change.grid), Some(change.new));
+        }
+        let hit = changes
+            .iter()
+            .find(|change| change.grid == Grid::new(100, 60))
+            .unwrap();
+        assert!(hit.new.is_occupied());
+
+        let changes = mapping.update(&isometry(&pose), &laser_scan);
+        assert!(changes.iter().all(|change| change.old.is_some()));
+
+        // Cells at the log-odds limits do not change any more.
+        for _ in 0..10 {
+            mapping.update(&isometry(&pose), &laser_scan);
+        }
+        assert!(mapping.update(&isometry(&pose), &laser_scan).is_empty());
+    }
+
+    #[test]
+    fn test_log_odds_clamping() {
+        let mut mapping = mapping_gen();
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+        let hit = LaserScan::new(5.0, 0.1, 0.1, 0.0, 0.0, 0.0, vec![1.0]);
+        let pass = LaserScan::new(5.0, 0.1, 0.1, 0.0, 0.0, 0.0, vec![2.0]);
+        let grid = Grid::new(100, 60);
+
+        for _ in 0..100 {
+            mapping.update(&isometry(&pose), &hit);
+        }
+        let element = mapping.map_element(&grid).unwrap();
+        assert_approx_eq!(element.log_odds, DEFAULT_MAX_LOG_ODDS);
+        assert!(element.is_occupied());
+
+        // The obstacle has gone; a few beams through it free the cell again.
+        for _ in 0..3 {
+            mapping.update(&isometry(&pose), &pass);
+        }
+        assert!(!mapping.map_element(&grid).unwrap().is_occupied());
+    }
+
+    #[test]
+    fn test_gaussian_inverse_sensor_model() {
+        let mut mapping = mapping_gen();
+        mapping.set_inverse_sensor_model(GaussianInverseSensorModel {
+            sigma: 0.1,
+            ..Default::default()
+        });
+        let laser_scan = LaserScan::new(5.0, 0.1, 0.1, 0.0, 0.0, 0.0, vec![1.0]);
+        mapping.update(&isometry(&Pose2::new(0.0, 0.0, 0.0)), &laser_scan);
+
+        let log_odds = |x: usize, y: usize| mapping.map_element(&Grid::new(x, y)).unwrap().log_odds;
+        let hit_log_odds = mapping.inverse_sensor_model().hit_log_odds(0.0, 1.0);
+        assert_approx_eq!(log_odds(100, 60), hit_log_odds);
+        assert!(log_odds(102, 60) > 0.0 && log_odds(102, 60) < hit_log_odds);
+        assert!(log_odds(100, 62) > 0.0 && log_odds(100, 62) < hit_log_odds);
+        assert!(log_odds(90, 60) < 0.0);
+        assert!(mapping.map_element(&Grid::new(107, 60)).is_none());
+    }
+
+    #[test]
+    fn test_clone_shares_tiles() {
+        let mapping = room_mapping(0.05);
+        let mut clone = mapping.clone();
+        let shared = |a: &Mapping, b: &Mapping| {
+            a.tiles
+                .iter()
+                .filter(|(index, tile)| b.tiles.get(index).is_some_and(|t| Arc::ptr_eq(t, tile)))
+                .count()
+        };
+        assert_eq!(shared(&mapping, &clone), mapping.tiles.len());
+
+        // Only the tiles the clone writes to are copied.
+        let grid = Grid::new(10, 10);
+        clone.set_map_element(&grid, MapElement::from_log_odds(1.0));
+        assert_eq!(shared(&mapping, &clone), mapping.tiles.len() - 1);
+        assert_ne!(clone.map_element(&grid), mapping.map_element(&grid));
+
+        let pose = Pose2::new(-2.0, 1.0, 0.0);
+        let changes = clone.update(&isometry(&pose), &room_scan(&pose));
+        assert!(!changes.is_empty());
+        assert!(shared(&mapping, &clone) < mapping.tiles.len() - 1);
+        for change in changes {
+            assert_eq!(mapping.map_element(&change.grid), change.old);
+        }
+    }
+
+    #[test]
+    fn test_map_file() {
+        let mut mapping = room_mapping(0.05);
+        mapping.set_inverse_sensor_model(GaussianInverseSensorModel {
+            half_confidence_range: 5.0,
+            ..Default::default()
+        });
+        mapping.set_log_odds_limits(-3.0, 4.0);
+        mapping.set_no_return_policy(NoReturnPolicy::ClearToDistance(1.5));
+
+        let mut bytes = Vec::new();
+        mapping
+            .write_map_file(&mut bytes, MapFileCompression::None)
+            .unwrap();
+        let mut compressed = Vec::new();
+        mapping
+            .write_map_file(&mut compressed, MapFileCompression::RunLength)
+            .unwrap();
+        assert!(compressed.len() * 4 < bytes.len());
+
+        for bytes in [bytes, compressed] {
+            let mut loaded = Mapping::read_map_file(&mut bytes.as_slice()).unwrap();
+
+            assert_eq!(loaded.map_size(), mapping.map_size());
+            assert_eq!(loaded.min_point().x, mapping.min_point().x);
+            assert_eq!(loaded.min_point().y, mapping.min_point().y);
+            assert_eq!(loaded.log_odds_limits(), mapping.log_odds_limits());
+            assert_eq!(loaded.no_return_policy(), mapping.no_return_policy());
+            assert_eq!(
+                loaded.inverse_sensor_model().parameters(),
+                mapping.inverse_sensor_model().parameters()
+            );
+            let (width, height) = mapping.map_size();
+            for y in 0..height {
+                for x in 0..width {
+                    let grid = Grid::new(x, y);
+                    assert_eq!(loaded.map_element(&grid), mapping.map_element(&grid));
                 }
             }
+
+            // Mapping goes on as if the map had never been saved.
+            let pose = Pose2::new(1.0, -0.5, 0.3);
+            let mut resumed = room_mapping(0.05);
+            resumed.set_inverse_sensor_model(GaussianInverseSensorModel {
+                half_confidence_range: 5.0,
+                ..Default::default()
+            });
+            resumed.set_log_odds_limits(-3.0, 4.0);
+            resumed.set_no_return_policy(NoReturnPolicy::ClearToDistance(1.5));
+            assert_eq!(
+                loaded.update(&isometry(&pose), &room_scan(&pose)),
+                resumed.update(&isometry(&pose), &room_scan(&pose))
+            );
+            loaded.init();
+            assert_eq!(loaded.map_size(), (160, 120));
         }
-        occupied_grids
+    }
+
+    #[test]
+    fn test_map_file_errors() {
+        let mut bytes = Vec::new();
+        mapping_gen()
+            .write_map_file(&mut bytes, MapFileCompression::RunLength)
+            .unwrap();
+
+        let mut newer = bytes.clone();
+        newer[8..12].copy_from_slice(&(MAP_FILE_VERSION + 1).to_le_bytes());
+        let error = Mapping::read_map_file(&mut newer.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+        let error = Mapping::read_map_file(&mut &bytes[1..]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(Mapping::read_map_file(&mut &truncated[..]).is_err());
+    }
+
+    fn mapping_gen() -> Mapping {
+        Mapping::new(
+            Position::new(-4.0, -3.0),
+            Position::new(4.0, 3.0),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        )
     }
 }
@@ -0,0 +1,974 @@
+/// Monte Carlo localization in a known occupancy grid
+use crate::*;
+use grid_map::{Grid, Position};
+use nalgebra as na;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+
+/// Default number of particles.
+pub const DEFAULT_MCL_PARTICLES: usize = 500;
+/// Default number of beams of a scan used to weight the particles.
+pub const DEFAULT_MCL_MAX_BEAMS: usize = 30;
+/// Default fraction of the particles below which the effective sample size triggers resampling.
+pub const DEFAULT_MCL_RESAMPLE_THRESHOLD: f64 = 0.5;
+/// Default bound on the Kullback-Leibler divergence of KLD-sampling.
+pub const DEFAULT_KLD_EPSILON: f64 = 0.05;
+/// Default upper standard normal quantile of KLD-sampling, for a 99 % confidence.
+pub const DEFAULT_KLD_Z: f64 = 2.326;
+/// Smallest variance of the motion noise in `OdometryMotionModel::log_probability`.
+const MIN_MOTION_VARIANCE: f64 = 1e-6;
+
+/// Pose hypothesis of the particle filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Particle {
+    pub pose: Pose2,
+    /// Normalised importance weight.
+    pub weight: f64,
+}
+
+/// Odometry motion model with the noise parameters of Thrun et al., Probabilistic Robotics.
+///
+/// The motion between two odometry poses is a rotation, a translation and a second rotation,
+/// each disturbed by noise whose variance grows with the rotations and the translation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OdometryMotionModel {
+    /// Rotation noise from rotation.
+    pub alpha1: f64,
+    /// Rotation noise from translation.
+    pub alpha2: f64,
+    /// Translation noise from translation.
+    pub alpha3: f64,
+    /// Translation noise from rotation.
+    pub alpha4: f64,
+}
+
+impl Default for OdometryMotionModel {
+    fn default() -> Self {
+        Self {
+            alpha1: 0.2,
+            alpha2: 0.2,
+            alpha3: 0.2,
+            alpha4: 0.2,
+        }
+    }
+}
+
+impl OdometryMotionModel {
+    /// Samples the pose after moving from `pose` as odometry moved from `previous_odometry` to
+    /// `odometry`.
+    pub fn sample(
+        &self,
+        pose: &Pose2,
+        previous_odometry: &Pose2,
+        odometry: &Pose2,
+        rng: &mut impl Rng,
+    ) -> Pose2 {
+        let (rotation1, translation, rotation2) = decompose_motion(previous_odometry, odometry);
+
+        // Driving backwards is a small rotation, not a half turn.
+        let rotation1_noise = rotation1
+            .abs()
+            .min((std::f64::consts::PI - rotation1.abs()).abs());
+        let rotation2_noise = rotation2
+            .abs()
+            .min((std::f64::consts::PI - rotation2.abs()).abs());
+
+        let rotation1 = rotation1
+            - sample_normal(
+                rng,
+                self.alpha1 * rotation1_noise.powi(2) + self.alpha2 * translation.powi(2),
+            );
+        let translation = translation
+            - sample_normal(
+                rng,
+                self.alpha3 * translation.powi(2)
+                    + self.alpha4 * (rotation1_noise.powi(2) + rotation2_noise.powi(2)),
+            );
+        let rotation2 = rotation2
+            - sample_normal(
+                rng,
+                self.alpha1 * rotation2_noise.powi(2) + self.alpha2 * translation.powi(2),
+            );
+
+        let heading = pose.theta() + rotation1;
+        Pose2::new(
+            pose.x() + translation * heading.cos(),
+            pose.y() + translation * heading.sin(),
+            normalize_angle(heading + rotation2),
+        )
+    }
+
+    /// Log of the probability density of moving from `previous_pose` to `pose` as odometry
+    /// moved from `previous_odometry` to `odometry`.
+    pub fn log_probability(
+        &self,
+        pose: &Pose2,
+        previous_pose: &Pose2,
+        previous_odometry: &Pose2,
+        odometry: &Pose2,
+    ) -> f64 {
+        let (rotation1, translation, rotation2) = decompose_motion(previous_odometry, odometry);
+        let (pose_rotation1, pose_translation, pose_rotation2) =
+            decompose_motion(previous_pose, pose);
+
+        let rotation1_noise = rotation1
+            .abs()
+            .min((std::f64::consts::PI - rotation1.abs()).abs());
+        let rotation2_noise = rotation2
+            .abs()
+            .min((std::f64::consts::PI - rotation2.abs()).abs());
+
+        log_normal(
+            normalize_angle(rotation1 - pose_rotation1),
+            self.alpha1 * rotation1_noise.powi(2) + self.alpha2 * translation.powi(2),
+        ) + log_normal(
+            translation - pose_translation,
+            self.alpha3 * translation.powi(2)
+                + self.alpha4 * (rotation1_noise.powi(2) + rotation2_noise.powi(2)),
+        ) + log_normal(
+            normalize_angle(rotation2 - pose_rotation2),
+            self.alpha1 * rotation2_noise.powi(2) + self.alpha2 * translation.powi(2),
+        )
+    }
+}
+
+/// Probability of a range measurement given the map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeasurementModel {
+    /// Gaussian of the distance from the beam endpoint to the nearest obstacle, mixed with
+    /// uniform random measurements. Beams without a return are skipped.
+    LikelihoodField {
+        /// [m]
+        sigma: f64,
+        z_hit: f64,
+        z_rand: f64,
+    },
+    /// Mixture of a Gaussian around the range found by ray casting in the map, unexpected
+    /// short readings, max-range readings and uniform random measurements.
+    Beam {
+        /// [m]
+        sigma: f64,
+        /// [1/m]
+        lambda_short: f64,
+        z_hit: f64,
+        z_short: f64,
+        z_max: f64,
+        z_rand: f64,
+    },
+}
+
+impl Default for MeasurementModel {
+    fn default() -> Self {
+        Self::LikelihoodField {
+            sigma: DEFAULT_LIKELIHOOD_FIELD_SIGMA,
+            z_hit: 0.95,
+            z_rand: 0.05,
+        }
+    }
+}
+
+impl MeasurementModel {
+    /// Beam model with the default mixture.
+    pub fn beam() -> Self {
+        Self::Beam {
+            sigma: DEFAULT_LIKELIHOOD_FIELD_SIGMA,
+            lambda_short: 0.1,
+            z_hit: 0.8,
+            z_short: 0.05,
+            z_max: 0.05,
+            z_rand: 0.1,
+        }
+    }
+}
+
+/// KLD-sampling (Fox, 2003): resampling draws particles until their number bounds the
+/// Kullback-Leibler divergence from the belief, so that a concentrated belief needs few
+/// particles and a spread one many.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KldSampling {
+    pub min_particles: usize,
+    pub max_particles: usize,
+    /// Bound on the divergence.
+    pub epsilon: f64,
+    /// Upper 1 - delta quantile of the standard normal distribution, for the confidence
+    /// 1 - delta that the bound holds.
+    pub z: f64,
+    /// Size of the histogram bins (x, y, theta) the particles are counted in. [m], [m], [rad]
+    pub bin_size: (f64, f64, f64),
+}
+
+impl Default for KldSampling {
+    fn default() -> Self {
+        Self {
+            min_particles: 100,
+            max_particles: 5000,
+            epsilon: DEFAULT_KLD_EPSILON,
+            z: DEFAULT_KLD_Z,
+            bin_size: (0.5, 0.5, 10f64.to_radians()),
+        }
+    }
+}
+
+impl KldSampling {
+    /// Number of particles needed when they occupy `bins` histogram bins, within the limits.
+    pub fn particles(&self, bins: usize) -> usize {
+        if bins <= 1 {
+            return self.min_particles;
+        }
+        // Wilson-Hilferty approximation of the chi-square quantile.
+        let k = (bins - 1) as f64;
+        let a = 2.0 / (9.0 * k);
+        let bound = k / (2.0 * self.epsilon) * (1.0 - a + a.sqrt() * self.z).powi(3);
+        (bound.ceil() as usize).clamp(self.min_particles, self.max_particles)
+    }
+
+    fn bin(&self, pose: &Pose2) -> (i64, i64, i64) {
+        (
+            (pose.x() / self.bin_size.0).floor() as i64,
+            (pose.y() / self.bin_size.1).floor() as i64,
+            (pose.theta() / self.bin_size.2).floor() as i64,
+        )
+    }
+}
+
+/// Recovery of augmented MCL: random particles are injected while the short term average of
+/// the measurement likelihood is below the long term one, e.g. after the robot was kidnapped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Recovery {
+    /// Rate of the long term average.
+    pub alpha_slow: f64,
+    /// Rate of the short term average.
+    pub alpha_fast: f64,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self {
+            alpha_slow: 0.001,
+            alpha_fast: 0.1,
+        }
+    }
+}
+
+/// Particle filter that localises a robot in a map built earlier (MCL).
+///
+/// Particles are moved with the odometry motion model, weighted with the measurement model and
+/// resampled with the low variance sampler when the effective sample size drops. With
+/// `KldSampling` the number of particles adapts at each resampling, and with `Recovery` random
+/// particles are injected when the scans stop matching.
+///
+/// The random numbers are seeded with 0, so that runs can be reproduced.
+pub struct MonteCarloLocalization {
+    particles: Vec<Particle>,
+    likelihood_field: LikelihoodField,
+    /// Occupancy of the explored cells of the map, row-major, for ray casting.
+    occupancy: Vec<Option<bool>>,
+    /// Indices of the free cells in `occupancy`, where random particles are drawn.
+    free_cells: Vec<usize>,
+    map_size: (usize, usize),
+    min_point: Position,
+    resolution: f64,
+    motion_model: OdometryMotionModel,
+    measurement_model: MeasurementModel,
+    max_beams: usize,
+    resample_threshold: f64,
+    kld_sampling: Option<KldSampling>,
+    recovery: Option<Recovery>,
+    /// Long and short term averages of the measurement likelihood per beam.
+    w_slow: f64,
+    w_fast: f64,
+    previous_odometry: Option<Pose2>,
+    rng: StdRng,
+}
+
+impl MonteCarloLocalization {
+    /// Starts with `particles` particles drawn around `initial_pose` with the standard
+    /// deviations `(x, y, theta)`.
+    pub fn new(
+        mapping: &Mapping,
+        particles: usize,
+        initial_pose: &(impl Into<Pose2> + Clone),
+        initial_sigma: (f64, f64, f64),
+    ) -> Self {
+        let mut localization = Self::from_mapping(mapping);
+        localization.initialize(particles, initial_pose, initial_sigma);
+        localization
+    }
+
+    /// Starts with `particles` particles spread uniformly over the free cells, for global
+    /// localisation.
+    pub fn new_global(mapping: &Mapping, particles: usize) -> Self {
+        let mut localization = Self::from_mapping(mapping);
+        localization.initialize_global(particles);
+        localization
+    }
+
+    fn from_mapping(mapping: &Mapping) -> Self {
+        let (width, height) = mapping.map_size();
+        let mut occupancy = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                occupancy.push(
+                    mapping
+                        .map_element(&Grid::new(x, y))
+                        .map(|element| element.is_occupied()),
+                );
+            }
+        }
+        let free_cells = occupancy
+            .iter()
+            .enumerate()
+            .filter(|(_, occupied)| **occupied == Some(false))
+            .map(|(i, _)| i)
+            .collect();
+
+        Self {
+            particles: Vec::new(),
+            likelihood_field: LikelihoodField::new(mapping, DEFAULT_LIKELIHOOD_FIELD_MAX_DISTANCE),
+            occupancy,
+            free_cells,
+            map_size: (width, height),
+            min_point: Position::new(mapping.min_point().x, mapping.min_point().y),
+            resolution: mapping.resolution(),
+            motion_model: OdometryMotionModel::default(),
+            measurement_model: MeasurementModel::default(),
+            max_beams: DEFAULT_MCL_MAX_BEAMS,
+            resample_threshold: DEFAULT_MCL_RESAMPLE_THRESHOLD,
+            kld_sampling: None,
+            recovery: None,
+            w_slow: 0.0,
+            w_fast: 0.0,
+            previous_odometry: None,
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    /// Replaces the particles with ones drawn around `pose`.
+    pub fn initialize(
+        &mut self,
+        particles: usize,
+        pose: &(impl Into<Pose2> + Clone),
+        sigma: (f64, f64, f64),
+    ) {
+        let pose: Pose2 = (*pose).clone().into();
+        let weight = 1.0 / particles as f64;
+        self.particles = (0..particles)
+            .map(|_| Particle {
+                pose: Pose2::new(
+                    pose.x() + sample_normal(&mut self.rng, sigma.0 * sigma.0),
+                    pose.y() + sample_normal(&mut self.rng, sigma.1 * sigma.1),
+                    normalize_angle(pose.theta() + sample_normal(&mut self.rng, sigma.2 * sigma.2)),
+                ),
+                weight,
+            })
+            .collect();
+    }
+
+    /// Replaces the particles with ones spread uniformly over the free cells.
+    pub fn initialize_global(&mut self, particles: usize) {
+        let weight = 1.0 / particles as f64;
+        self.particles = (0..particles)
+            .map_while(|_| self.random_pose().map(|pose| Particle { pose, weight }))
+            .collect();
+    }
+
+    /// Reseeds the random numbers used from now on.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    pub fn motion_model(&self) -> OdometryMotionModel {
+        self.motion_model
+    }
+
+    pub fn set_motion_model(&mut self, motion_model: OdometryMotionModel) {
+        self.motion_model = motion_model;
+    }
+
+    pub fn measurement_model(&self) -> MeasurementModel {
+        self.measurement_model
+    }
+
+    pub fn set_measurement_model(&mut self, measurement_model: MeasurementModel) {
+        self.measurement_model = measurement_model;
+        if let MeasurementModel::LikelihoodField { sigma, .. } = measurement_model {
+            self.likelihood_field.set_sigma(sigma);
+        }
+    }
+
+    pub fn max_beams(&self) -> usize {
+        self.max_beams
+    }
+
+    /// Sets the number of beams, evenly spread over the scan, used to weight the particles.
+    pub fn set_max_beams(&mut self, max_beams: usize) {
+        self.max_beams = max_beams.max(1);
+    }
+
+    pub fn resample_threshold(&self) -> f64 {
+        self.resample_threshold
+    }
+
+    /// Resamples when the effective sample size drops below this fraction of the particles.
+    /// 1.0 resamples after every scan.
+    pub fn set_resample_threshold(&mut self, resample_threshold: f64) {
+        self.resample_threshold = resample_threshold;
+    }
+
+    pub fn kld_sampling(&self) -> Option<KldSampling> {
+        self.kld_sampling
+    }
+
+    /// Adapts the number of particles at each resampling, or keeps it with `None`.
+    pub fn set_kld_sampling(&mut self, kld_sampling: Option<KldSampling>) {
+        self.kld_sampling = kld_sampling;
+    }
+
+    pub fn recovery(&self) -> Option<Recovery> {
+        self.recovery
+    }
+
+    /// Injects random particles when the scans stop matching, or never with `None`.
+    pub fn set_recovery(&mut self, recovery: Option<Recovery>) {
+        self.recovery = recovery;
+        self.w_slow = 0.0;
+        self.w_fast = 0.0;
+    }
+
+    /// Predicts the particles with the odometry pose, e.g. an `Odometry` message, and weights
+    /// them with the scan taken there.
+    pub fn update(&mut self, odometry: &(impl Into<Pose2> + Clone), laser_scan: &LaserScan) {
+        self.predict(odometry);
+        self.correct(laser_scan);
+    }
+
+    /// Moves the particles as odometry moved since the previous call.
+    pub fn predict(&mut self, odometry: &(impl Into<Pose2> + Clone)) {
+        let odometry: Pose2 = (*odometry).clone().into();
+        if let Some(previous_odometry) = self.previous_odometry {
+            for particle in self.particles.iter_mut() {
+                particle.pose = self.motion_model.sample(
+                    &particle.pose,
+                    &previous_odometry,
+                    &odometry,
+                    &mut self.rng,
+                );
+            }
+        }
+        self.previous_odometry = Some(odometry);
+    }
+
+    /// Weights the particles with the scan and resamples them if needed.
+    pub fn correct(&mut self, laser_scan: &LaserScan) {
+        if self.particles.is_empty() {
+            return;
+        }
+
+        let step = laser_scan.ranges().len().div_ceil(self.max_beams).max(1);
+        let beams = laser_scan
+            .ranges()
+            .iter()
+            .enumerate()
+            .step_by(step)
+            .map(|(i, range)| {
+                let angle = laser_scan.angle_min() + i as f64 * laser_scan.angle_increment();
+                (angle, *range)
+            })
+            .collect::<Vec<_>>();
+
+        let log_likelihoods = self
+            .particles
+            .iter()
+            .map(|particle| self.log_likelihood(&particle.pose, &beams, laser_scan))
+            .collect::<Vec<_>>();
+        let max_log_likelihood = log_likelihoods
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        for (particle, log_likelihood) in self.particles.iter_mut().zip(log_likelihoods) {
+            particle.weight *= (log_likelihood - max_log_likelihood).exp();
+        }
+
+        // The weights summed to one, so their sum is now the average likelihood relative to the
+        // best particle. Per beam, it does not underflow for long scans.
+        if let Some(recovery) = self.recovery {
+            let sum = self
+                .particles
+                .iter()
+                .map(|particle| particle.weight)
+                .sum::<f64>();
+            let average = ((max_log_likelihood + sum.ln()) / beams.len() as f64).exp();
+            if average.is_finite() {
+                if self.w_slow == 0.0 {
+                    self.w_slow = average;
+                    self.w_fast = average;
+                } else {
+                    self.w_slow += recovery.alpha_slow * (average - self.w_slow);
+                    self.w_fast += recovery.alpha_fast * (average - self.w_fast);
+                }
+            }
+        }
+        normalize_weights(
+            self.particles
+                .iter_mut()
+                .map(|particle| &mut particle.weight),
+        );
+
+        let effective_sample_size =
+            effective_sample_size(self.particles.iter().map(|particle| particle.weight));
+        if effective_sample_size < self.resample_threshold * self.particles.len() as f64
+            || self.injection_probability() > 0.0
+        {
+            self.resample();
+        }
+    }
+
+    /// Probability that a resampled particle is replaced with a random one.
+    pub fn injection_probability(&self) -> f64 {
+        if self.recovery.is_none() || self.w_slow <= 0.0 {
+            return 0.0;
+        }
+        (1.0 - self.w_fast / self.w_slow).max(0.0)
+    }
+
+    /// Weighted mean of the particles.
+    pub fn pose(&self) -> Pose2 {
+        let (mut x, mut y, mut cos, mut sin) = (0.0, 0.0, 0.0, 0.0);
+        for particle in self.particles.iter() {
+            x += particle.weight * particle.pose.x();
+            y += particle.weight * particle.pose.y();
+            cos += particle.weight * particle.pose.theta().cos();
+            sin += particle.weight * particle.pose.theta().sin();
+        }
+        Pose2::new(x, y, sin.atan2(cos))
+    }
+
+    /// Weighted covariance of the particles in (x, y, theta).
+    pub fn covariance(&self) -> na::Matrix3<f64> {
+        let mean = self.pose();
+        self.particles
+            .iter()
+            .map(|particle| {
+                let difference = na::Vector3::new(
+                    particle.pose.x() - mean.x(),
+                    particle.pose.y() - mean.y(),
+                    normalize_angle(particle.pose.theta() - mean.theta()),
+                );
+                particle.weight * difference * difference.transpose()
+            })
+            .sum()
+    }
+
+    fn log_likelihood(&self, pose: &Pose2, beams: &[(f64, f64)], laser_scan: &LaserScan) -> f64 {
+        let range_max = laser_scan.range_max();
+        beams
+            .iter()
+            .filter(|(_, range)| !range.is_nan() && *range >= laser_scan.range_min())
+            .filter_map(|(angle, range)| {
+                let probability = match self.measurement_model {
+                    MeasurementModel::LikelihoodField { z_hit, z_rand, .. } => {
+                        if !range.is_finite() || *range > range_max {
+                            return None;
+                        }
+                        let angle = pose.theta() + angle;
+                        let endpoint = Position::new(
+                            pose.x() + range * angle.cos(),
+                            pose.y() + range * angle.sin(),
+                        );
+                        z_hit * self.likelihood_field.likelihood(&endpoint) + z_rand / range_max
+                    }
+                    MeasurementModel::Beam {
+                        sigma,
+                        lambda_short,
+                        z_hit,
+                        z_short,
+                        z_max,
+                        z_rand,
+                    } => {
+                        let expected = self.cast_ray(pose, *angle, range_max);
+                        if !range.is_finite() || *range >= range_max {
+                            z_max + z_rand / range_max
+                        } else {
+                            let hit = (-(range - expected).powi(2) / (2.0 * sigma * sigma)).exp()
+                                / (sigma * (2.0 * std::f64::consts::PI).sqrt());
+                            let short = if *range <= expected {
+                                lambda_short * (-lambda_short * range).exp()
+                                    / (1.0 - (-lambda_short * expected).exp()).max(1e-12)
+                            } else {
+                                0.0
+                            };
+                            z_hit * hit + z_short * short + z_rand / range_max
+                        }
+                    }
+                };
+                Some(probability.ln())
+            })
+            .sum()
+    }
+
+    /// Range to the first occupied cell along the beam, or `range_max` if there is none.
+    fn cast_ray(&self, pose: &Pose2, angle: f64, range_max: f64) -> f64 {
+        let angle = pose.theta() + angle;
+        let start = na::Translation2::new(pose.x(), pose.y());
+        let end = na::Translation2::new(
+            pose.x() + range_max * angle.cos(),
+            pose.y() + range_max * angle.sin(),
+        );
+        ray_traversal2_clipped(
+            &start,
+            &end,
+            self.resolution,
+            &self.min_point,
+            self.map_size,
+        )
+        .iter()
+        .find(|cell| {
+            self.occupancy[cell.index[1] as usize * self.map_size.0 + cell.index[0] as usize]
+                == Some(true)
+        })
+        .map_or(range_max, |cell| (cell.entry + cell.exit) / 2.0)
+    }
+
+    /// Resamples with KLD-sampling if enabled, otherwise with the low variance sampler, and
+    /// replaces particles with random ones while recovering.
+    fn resample(&mut self) {
+        let injection_probability = self.injection_probability();
+        let mut poses = match self.kld_sampling {
+            Some(kld_sampling) => self.kld_sample(&kld_sampling, injection_probability),
+            None => {
+                let weights = self
+                    .particles
+                    .iter()
+                    .map(|particle| particle.weight)
+                    .collect::<Vec<_>>();
+                low_variance_sample(&mut self.rng, &weights)
+                    .into_iter()
+                    .map(|i| self.particles[i].pose)
+                    .collect()
+            }
+        };
+        if injection_probability > 0.0 && self.kld_sampling.is_none() {
+            for pose in poses.iter_mut() {
+                if self.rng.gen::<f64>() < injection_probability {
+                    *pose = self.random_pose().unwrap_or(*pose);
+                }
+            }
+        }
+
+        let weight = 1.0 / poses.len() as f64;
+        self.particles = poses
+            .into_iter()
+            .map(|pose| Particle { pose, weight })
+            .collect();
+    }
+
+    /// Draws particles one at a time until there are as many as the histogram bins they
+    /// occupy require. Random particles count towards the bins like the others.
+    fn kld_sample(&mut self, kld_sampling: &KldSampling, injection_probability: f64) -> Vec<Pose2> {
+        let cumulative = self
+            .particles
+            .iter()
+            .scan(0.0, |sum, particle| {
+                *sum += particle.weight;
+                Some(*sum)
+            })
+            .collect::<Vec<_>>();
+        let total = *cumulative.last().unwrap();
+
+        let mut poses = Vec::new();
+        let mut bins = HashSet::new();
+        while poses.len() < kld_sampling.max_particles.max(1)
+            && (poses.len() < kld_sampling.min_particles
+                || poses.len() < kld_sampling.particles(bins.len()))
+        {
+            let random_pose =
+                if injection_probability > 0.0 && self.rng.gen::<f64>() < injection_probability {
+                    self.random_pose()
+                } else {
+                    None
+                };
+            let pose = random_pose.unwrap_or_else(|| {
+                let pointer = self.rng.gen_range(0.0..total);
+                let i = cumulative
+                    .partition_point(|sum| *sum <= pointer)
+                    .min(cumulative.len() - 1);
+                self.particles[i].pose
+            });
+            bins.insert(kld_sampling.bin(&pose));
+            poses.push(pose);
+        }
+        poses
+    }
+
+    /// Pose in a random free cell with a random heading, or `None` if no cell is free.
+    fn random_pose(&mut self) -> Option<Pose2> {
+        if self.free_cells.is_empty() {
+            return None;
+        }
+        let cell = self.free_cells[self.rng.gen_range(0..self.free_cells.len())];
+        let x = (cell % self.map_size.0) as f64 + self.rng.gen_range(-0.5..0.5);
+        let y = (cell / self.map_size.0) as f64 + self.rng.gen_range(-0.5..0.5);
+        Some(Pose2::new(
+            self.min_point.x + x * self.resolution,
+            self.min_point.y + y * self.resolution,
+            self.rng
+                .gen_range(-std::f64::consts::PI..std::f64::consts::PI),
+        ))
+    }
+}
+
+/// Rotation, translation and second rotation that move from one pose to the other.
+fn decompose_motion(from: &Pose2, to: &Pose2) -> (f64, f64, f64) {
+    let dx = to.x() - from.x();
+    let dy = to.y() - from.y();
+    let translation = dx.hypot(dy);
+    // Turning in place does not define a heading.
+    let rotation1 = if translation < 1e-2 {
+        0.0
+    } else {
+        normalize_angle(dy.atan2(dx) - from.theta())
+    };
+    let rotation2 = normalize_angle(to.theta() - from.theta() - rotation1);
+    (rotation1, translation, rotation2)
+}
+
+/// Log of the zero mean normal density with the variance, kept finite for no variance.
+fn log_normal(value: f64, variance: f64) -> f64 {
+    let variance = variance.max(MIN_MOTION_VARIANCE);
+    -0.5 * (value * value / variance + (2.0 * std::f64::consts::PI * variance).ln())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_motion_model() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let previous_odometry = Pose2::new(1.0, 1.0, std::f64::consts::FRAC_PI_2);
+        let odometry = Pose2::new(1.0, 2.0, std::f64::consts::PI);
+        let pose = Pose2::new(0.0, 0.0, 0.0);
+
+        let exact = OdometryMotionModel {
+            alpha1: 0.0,
+            alpha2: 0.0,
+            alpha3: 0.0,
+            alpha4: 0.0,
+        };
+        let moved = exact.sample(&pose, &previous_odometry, &odometry, &mut rng);
+        assert_approx_eq!(moved.x(), 1.0);
+        assert_approx_eq!(moved.y(), 0.0);
+        assert_approx_eq!(moved.theta(), std::f64::consts::FRAC_PI_2);
+
+        let noisy = OdometryMotionModel {
+            alpha1: 0.01,
+            alpha2: 0.01,
+            alpha3: 0.05,
+            alpha4: 0.01,
+        };
+        let samples = (0..2000)
+            .map(|_| noisy.sample(&pose, &previous_odometry, &odometry, &mut rng))
+            .collect::<Vec<_>>();
+        let mean_x = samples.iter().map(|pose| pose.x()).sum::<f64>() / samples.len() as f64;
+        let spread_x = samples
+            .iter()
+            .map(|pose| (pose.x() - mean_x).powi(2))
+            .sum::<f64>()
+            / samples.len() as f64;
+        assert!((mean_x - 1.0).abs() < 0.05);
+        assert!(spread_x > 0.01);
+    }
+
+    #[test]
+    fn test_low_variance_resampling() {
+        let mapping = room_mapping(0.05);
+        let mut localization =
+            MonteCarloLocalization::new(&mapping, 100, &Pose2::new(0.0, 0.0, 0.0), (0.5, 0.5, 0.5));
+        for (i, particle) in localization.particles.iter_mut().enumerate() {
+            particle.weight = if i == 42 { 0.9 } else { 0.1 / 99.0 };
+        }
+        let heavy = localization.particles[42].pose;
+
+        localization.resample();
+
+        let copies = localization
+            .particles()
+            .iter()
+            .filter(|particle| particle.pose == heavy)
+            .count();
+        assert!((89..=91).contains(&copies));
+        assert!(localization
+            .particles()
+            .iter()
+            .all(|particle| particle.weight == 0.01));
+    }
+
+    #[test]
+    fn test_tracking() {
+        let mapping = room_mapping(0.05);
+        for measurement_model in [MeasurementModel::default(), MeasurementModel::beam()] {
+            let start = Pose2::new(-1.0, -0.5, 0.0);
+            let mut localization = MonteCarloLocalization::new(
+                &mapping,
+                DEFAULT_MCL_PARTICLES,
+                &start,
+                (0.2, 0.2, 0.1),
+            );
+            localization.set_measurement_model(measurement_model);
+
+            // Odometry overestimates the distance travelled by 10 %.
+            let mut pose = start;
+            for i in 0..30 {
+                let theta = 0.05 * i as f64;
+                pose = Pose2::new(-1.0 + 0.06 * i as f64, -0.5 + 0.03 * i as f64, theta);
+                let odometry = Pose2::new(
+                    -1.0 + 0.066 * i as f64,
+                    -0.5 + 0.033 * i as f64,
+                    theta * 1.1,
+                );
+                localization.update(&odometry, &room_scan(&pose));
+            }
+
+            let estimate = localization.pose();
+            println!("{:?}: {:?} for {:?}", measurement_model, estimate, pose);
+            // Odometry alone ends 0.2 m and 0.15 rad off.
+            assert!((estimate.x() - pose.x()).hypot(estimate.y() - pose.y()) < 0.1);
+            assert!(normalize_angle(estimate.theta() - pose.theta()).abs() < 0.1);
+            let covariance = localization.covariance();
+            assert!(covariance[(0, 0)] < 0.01 && covariance[(1, 1)] < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_kld_bound() {
+        let kld_sampling = KldSampling::default();
+        assert_eq!(kld_sampling.particles(1), kld_sampling.min_particles);
+        assert_eq!(kld_sampling.particles(10_000), kld_sampling.max_particles);
+        // (k - 1) / (2 epsilon) * chi-square quantile term, for k = 50.
+        assert_eq!(kld_sampling.particles(50), 750);
+        assert!(kld_sampling.particles(20) < kld_sampling.particles(40));
+    }
+
+    #[test]
+    fn test_global_localization() {
+        let mapping = room_mapping(0.05);
+        let mut localization = MonteCarloLocalization::new_global(&mapping, 5000);
+        localization.set_kld_sampling(Some(KldSampling::default()));
+        assert_eq!(localization.particles().len(), 5000);
+        assert!(localization.covariance()[(0, 0)] > 1.0);
+
+        let mut pose = loop_pose(0);
+        for i in 0..40 {
+            pose = loop_pose(i);
+            localization.update(&pose, &room_scan(&pose));
+        }
+
+        let estimate = localization.pose();
+        println!(
+            "{:?} for {:?} with {} particles",
+            estimate,
+            pose,
+            localization.particles().len()
+        );
+        assert!((estimate.x() - pose.x()).hypot(estimate.y() - pose.y()) < 0.1);
+        assert!(normalize_angle(estimate.theta() - pose.theta()).abs() < 0.1);
+        // The converged belief needs far fewer particles.
+        assert!(localization.particles().len() < 1000);
+    }
+
+    #[test]
+    fn test_kidnapped_robot() {
+        let mapping = room_mapping(0.05);
+        let mut localization = MonteCarloLocalization::new(
+            &mapping,
+            DEFAULT_MCL_PARTICLES,
+            &loop_pose(0),
+            (0.1, 0.1, 0.05),
+        );
+        localization.set_kld_sampling(Some(KldSampling::default()));
+        localization.set_recovery(Some(Recovery::default()));
+        for i in 0..10 {
+            let pose = loop_pose(i);
+            localization.update(&pose, &room_scan(&pose));
+        }
+        assert_eq!(localization.injection_probability(), 0.0);
+
+        // The robot is carried half way around the loop while odometry keeps counting.
+        let mut pose = loop_pose(0);
+        let mut peak_particles = 0;
+        for i in 10..60 {
+            pose = loop_pose(i + 30);
+            localization.update(&loop_pose(i), &room_scan(&pose));
+            peak_particles = peak_particles.max(localization.particles().len());
+        }
+
+        let estimate = localization.pose();
+        println!(
+            "{:?} for {:?} with {} particles",
+            estimate,
+            pose,
+            localization.particles().len()
+        );
+        assert!((estimate.x() - pose.x()).hypot(estimate.y() - pose.y()) < 0.1);
+        assert!(normalize_angle(estimate.theta() - pose.theta()).abs() < 0.1);
+        // Random particles spread the belief, so KLD-sampling took more particles meanwhile.
+        assert!(peak_particles > 1000);
+        assert!(localization.particles().len() < 1000);
+        assert_eq!(localization.injection_probability(), 0.0);
+    }
+
+    #[test]
+    fn test_sample_logs() {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let scan_log_path = format!("{}/sample/ros2_scan_log.yaml", manifest_dir);
+        let odom_log_path = format!("{}/sample/ros2_odom_log.yaml", manifest_dir);
+
+        // Map built along the odometry, which is then the reference for localisation.
+        let mut data_loader = DebuggerYaml::new(&scan_log_path, &odom_log_path);
+        let mut mapping = Mapping::new(
+            Position::new(-5.0, -5.0),
+            Position::new(5.0, 5.0),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+        while let Some((laser_scan, current_position)) = data_loader.next_scan_2d() {
+            mapping.update(&current_position, &laser_scan);
+        }
+
+        data_loader.reset_count();
+        let (laser_scan, current_position) = data_loader.next_scan_2d().unwrap();
+        let initial_pose = Pose2::from(current_position);
+        let mut localization = MonteCarloLocalization::new(
+            &mapping,
+            300,
+            &Pose2::new(
+                initial_pose.x() + 0.1,
+                initial_pose.y() - 0.1,
+                initial_pose.theta(),
+            ),
+            (0.2, 0.2, 0.1),
+        );
+        localization.update(&current_position, &laser_scan);
+
+        let mut errors = Vec::new();
+        while let Some((laser_scan, current_position)) = data_loader.next_scan_2d() {
+            localization.update(&current_position, &laser_scan);
+            let estimate = localization.pose();
+            errors.push(
+                (estimate.x() - current_position.translation.x)
+                    .hypot(estimate.y() - current_position.translation.y),
+            );
+        }
+
+        println!("last errors: {:?}", &errors[errors.len() - 5..]);
+        assert!(errors.iter().skip(20).all(|error| *error < 0.1));
+    }
+}
@@ -0,0 +1,486 @@
+/// Normal distributions transform (NDT) scan matching
+use crate::*;
+use nalgebra as na;
+use std::collections::HashMap;
+
+/// Default edge length of the NDT cells. [m]
+pub const DEFAULT_NDT_CELL_SIZE: f64 = 0.5;
+/// Minimum number of reference points for a cell to get a distribution.
+const MIN_POINTS_PER_CELL: usize = 3;
+/// Smallest covariance eigenvalue relative to the largest one, so that points on a line still
+/// give an invertible covariance.
+const COVARIANCE_EIGENVALUE_FLOOR: f64 = 0.01;
+/// Smallest covariance eigenvalue in absolute terms. [m^2]
+const MIN_COVARIANCE_EIGENVALUE: f64 = 1e-6;
+
+/// Gaussian of the reference points in one cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NdtCell {
+    mean: na::Vector2<f64>,
+    inverse_covariance: na::Matrix2<f64>,
+}
+
+/// 2D NDT scan matcher (Biber and Strasser) with analytic gradient and Hessian.
+#[derive(Debug, Clone)]
+pub struct NormalDistributionsTransform2 {
+    /// Robot coordinates of the scan points.
+    scan_points: Pointcloud2,
+    /// World coordinates of the reference points.
+    reference_points: Pointcloud2,
+    /// Estimated robot pose.
+    robot_pose: Pose2,
+    /// Edge length of the cells. [m]
+    cell_size: f64,
+    /// Distributions of the reference points, keyed by cell index.
+    cells: HashMap<(i64, i64), NdtCell>,
+    /// Thresholds that stop the scan matching early.
+    convergence_criteria: ConvergenceCriteria,
+}
+
+impl NormalDistributionsTransform2 {
+    pub fn new(
+        scan_points: &(impl Into<Pointcloud2> + Clone),
+        reference_points: &(impl Into<Pointcloud2> + Clone),
+        robot_pose: &(impl Into<Pose2> + Clone),
+    ) -> Self {
+        let mut ndt = Self {
+            scan_points: (*scan_points).clone().into(),
+            reference_points: (*reference_points).clone().into(),
+            robot_pose: (*robot_pose).clone().into(),
+            cell_size: DEFAULT_NDT_CELL_SIZE,
+            cells: HashMap::new(),
+            convergence_criteria: ConvergenceCriteria::default(),
+        };
+        ndt.build_cells();
+        ndt
+    }
+
+    /// Uses the centres of the occupied cells of `mapping` as reference points.
+    pub fn from_mapping(
+        scan_points: &(impl Into<Pointcloud2> + Clone),
+        mapping: &Mapping,
+        robot_pose: &(impl Into<Pose2> + Clone),
+    ) -> Self {
+        let reference_points = mapping
+            .get_occupied_grids_positions()
+            .iter()
+            .map(|p| Point2::new(p.x, p.y))
+            .collect();
+        Self::new(scan_points, &Pointcloud2::new(reference_points), robot_pose)
+    }
+
+    pub fn robot_pose(&self) -> Pose2 {
+        self.robot_pose
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    /// Changes the cell size and rebuilds the distributions.
+    pub fn set_cell_size(&mut self, cell_size: f64) {
+        self.cell_size = cell_size;
+        self.build_cells();
+    }
+
+    pub fn convergence_criteria(&self) -> ConvergenceCriteria {
+        self.convergence_criteria
+    }
+
+    pub fn set_convergence_criteria(&mut self, convergence_criteria: ConvergenceCriteria) {
+        self.convergence_criteria = convergence_criteria;
+    }
+
+    fn cell_index(&self, point: &na::Vector2<f64>) -> (i64, i64) {
+        (
+            (point.x / self.cell_size).floor() as i64,
+            (point.y / self.cell_size).floor() as i64,
+        )
+    }
+
+    fn build_cells(&mut self) {
+        let mut points_per_cell: HashMap<(i64, i64), Vec<na::Vector2<f64>>> = HashMap::new();
+        for point in self.reference_points.points() {
+            let point = na::Vector2::from(*point);
+            points_per_cell
+                .entry(self.cell_index(&point))
+                .or_default()
+                .push(point);
+        }
+
+        self.cells = points_per_cell
+            .into_iter()
+            .filter(|(_, points)| points.len() >= MIN_POINTS_PER_CELL)
+            .map(|(index, points)| {
+                let n = points.len() as f64;
+                let mean = points.iter().sum::<na::Vector2<f64>>() / n;
+                let covariance = points.iter().fold(na::Matrix2::zeros(), |sum, p| {
+                    sum + (p - mean) * (p - mean).transpose()
+                }) / (n - 1.0);
+
+                let eigen = covariance.symmetric_eigen();
+                let floor = (eigen.eigenvalues.max() * COVARIANCE_EIGENVALUE_FLOOR)
+                    .max(MIN_COVARIANCE_EIGENVALUE);
+                let inverse_eigenvalues = eigen.eigenvalues.map(|l| 1.0 / l.max(floor));
+                let inverse_covariance = eigen.eigenvectors
+                    * na::Matrix2::from_diagonal(&inverse_eigenvalues)
+                    * eigen.eigenvectors.transpose();
+
+                (
+                    index,
+                    NdtCell {
+                        mean,
+                        inverse_covariance,
+                    },
+                )
+            })
+            .collect();
+    }
+
+    /// Returns the cost, its gradient and Hessian at `pose`, the number of scan points that fall
+    /// near a distribution and the sum of their Mahalanobis distances.
+    ///
+    /// Every scan point is scored against the distributions of its cell and the eight
+    /// neighbouring cells, with cost `-exp(-q^T C q / 2)` each.
+    fn score(&self, pose: &Pose2) -> (f64, na::Vector3<f64>, na::Matrix3<f64>, usize, f64) {
+        let (sin, cos) = pose.theta().sin_cos();
+        let mut cost = 0.0;
+        let mut gradient = na::Vector3::zeros();
+        let mut hessian = na::Matrix3::zeros();
+        let mut inliers = 0;
+        let mut mahalanobis_sum = 0.0;
+
+        for point in self.scan_points.points() {
+            let transformed = na::Vector2::from(pose.transform_point(point));
+            let d_theta = na::Vector2::new(
+                -sin * point.x() - cos * point.y(),
+                cos * point.x() - sin * point.y(),
+            );
+            let d_theta_theta = na::Vector2::new(
+                -cos * point.x() + sin * point.y(),
+                -sin * point.x() - cos * point.y(),
+            );
+            let jacobian = na::Matrix2x3::new(1.0, 0.0, d_theta.x, 0.0, 1.0, d_theta.y);
+
+            let (i, j) = self.cell_index(&transformed);
+            let mut min_mahalanobis = f64::INFINITY;
+            for di in -1..=1 {
+                for dj in -1..=1 {
+                    let Some(cell) = self.cells.get(&(i + di, j + dj)) else {
+                        continue;
+                    };
+                    let q = transformed - cell.mean;
+                    let c_q = cell.inverse_covariance * q;
+                    let mahalanobis_squared = q.dot(&c_q);
+                    min_mahalanobis = min_mahalanobis.min(mahalanobis_squared.sqrt());
+                    let e = (-0.5 * mahalanobis_squared).exp();
+
+                    let projected = jacobian.transpose() * c_q;
+                    cost -= e;
+                    gradient += e * projected;
+                    hessian += e
+                        * (jacobian.transpose() * cell.inverse_covariance * jacobian
+                            - projected * projected.transpose());
+                    hessian[(2, 2)] += e * c_q.dot(&d_theta_theta);
+                }
+            }
+            if min_mahalanobis.is_finite() {
+                inliers += 1;
+                mahalanobis_sum += min_mahalanobis;
+            }
+        }
+
+        (cost, gradient, hessian, inliers, mahalanobis_sum)
+    }
+
+    /// Returns the cost and its gradient at `pose`, and the full Newton step from there.
+    fn newton_step(&self, pose: &Pose2) -> (f64, na::Vector3<f64>, na::Vector3<f64>) {
+        let (cost, gradient, hessian, _, _) = self.score(pose);
+
+        // Shift the Hessian until it is positive definite, so the step goes downhill.
+        let mut shift = 0.0;
+        let step = loop {
+            let shifted = hessian + na::Matrix3::from_diagonal_element(shift);
+            if let Some(cholesky) = shifted.cholesky() {
+                break cholesky.solve(&(-gradient));
+            }
+            shift = (shift * 2.0).max(hessian.diagonal().abs().max().max(1.0) * 1e-6);
+        };
+        (cost, gradient, step)
+    }
+
+    /// Takes one Newton step with backtracking. Returns `false` if no step decreased the cost.
+    pub fn optimize_once(&mut self) -> bool {
+        let (cost, gradient, step) = self.newton_step(&self.robot_pose);
+
+        let mut step_length = 1.0;
+        while step_length >= MIN_STEP_LENGTH {
+            let candidate = self.robot_pose.retract((step * step_length).as_slice());
+            let (candidate_cost, _, _, _, _) = self.score(&candidate);
+            if candidate_cost < cost + 1e-4 * step_length * gradient.dot(&step) {
+                self.robot_pose = candidate;
+                return true;
+            }
+            step_length *= 0.5;
+        }
+        false
+    }
+
+    /// Returns whether the full Newton step from `pose` meets the convergence criteria.
+    fn is_stationary(&self, pose: &Pose2, cost: f64) -> bool {
+        let (_, _, step) = self.newton_step(pose);
+        let candidate = pose.retract(step.as_slice());
+        let (candidate_cost, _, _, _, _) = self.score(&candidate);
+        self.convergence_criteria
+            .is_converged(pose, &candidate, cost, candidate_cost)
+    }
+
+    /// Iterates until one of the convergence criteria is met or `max_iterations` is reached.
+    ///
+    /// If no step decreases the cost from the initial pose, the match diverged. If that happens
+    /// later, the match is converged only if the full Newton step meets the criteria.
+    pub fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult {
+        let mut status = ScanMatchingStatus::MaxIterationsReached;
+        let mut iterations = 0;
+        let mut moved = false;
+
+        let (mut previous_cost, _, _, inliers, _) = self.score(&self.robot_pose);
+        if inliers == 0 {
+            status = ScanMatchingStatus::Diverged;
+        } else {
+            while iterations < max_iterations {
+                let previous_pose = self.robot_pose;
+                let improved = self.optimize_once();
+                iterations += 1;
+
+                let (cost, _, _, inliers, _) = self.score(&self.robot_pose);
+                if inliers == 0 || !cost.is_finite() || !self.robot_pose.is_finite() {
+                    status = ScanMatchingStatus::Diverged;
+                    break;
+                }
+                if !improved {
+                    status = if !moved {
+                        ScanMatchingStatus::Diverged
+                    } else if self.is_stationary(&self.robot_pose, cost) {
+                        ScanMatchingStatus::Converged
+                    } else {
+                        ScanMatchingStatus::MaxIterationsReached
+                    };
+                    break;
+                }
+                moved = true;
+                if self.convergence_criteria.is_converged(
+                    &previous_pose,
+                    &self.robot_pose,
+                    previous_cost,
+                    cost,
+                ) {
+                    status = ScanMatchingStatus::Converged;
+                    break;
+                }
+                previous_cost = cost;
+            }
+        }
+
+        let (_, _, hessian, inliers, mahalanobis_sum) = self.score(&self.robot_pose);
+        let (residual, covariance) = if inliers == 0 || status == ScanMatchingStatus::Diverged {
+            (
+                f64::INFINITY,
+                na::DMatrix::from_diagonal_element(3, 3, f64::INFINITY),
+            )
+        } else {
+            // Near the match the Hessian tends to the sum of `J^T C J` over the inverse cell
+            // covariances `C`, which already weight the residuals by their noise. It is an
+            // information matrix as it is, without the residual variance of ICP.
+            let hessian = na::DMatrix::from_column_slice(3, 3, hessian.as_slice());
+            (
+                mahalanobis_sum / inliers as f64,
+                regularized_inverse(&hessian, HESSIAN_EIGENVALUE_FLOOR),
+            )
+        };
+
+        ScanMatchingResult {
+            pose: self.robot_pose,
+            covariance,
+            residual,
+            iterations,
+            inliers,
+            status,
+        }
+    }
+}
+
+impl ScanMatcher for NormalDistributionsTransform2 {
+    fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult {
+        NormalDistributionsTransform2::scan_matching(self, max_iterations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_ndt() {
+        let expected_pose = Pose2::new(0.1, -0.05, 0.05);
+        let mut ndt = data_gen(&expected_pose);
+        let result = ndt.scan_matching(100);
+
+        println!("{:?}", result);
+
+        assert!(result.converged());
+        assert!(result.pose.distance(&expected_pose) < 1e-3);
+        assert_eq!(result.pose, ndt.robot_pose());
+    }
+
+    #[test]
+    fn test_gradient_and_hessian() {
+        let ndt = data_gen(&Pose2::new(0.1, -0.05, 0.05));
+        let pose = Pose2::new(0.03, 0.02, 0.01);
+        let (_, gradient, hessian, _, _) = ndt.score(&pose);
+
+        let epsilon = 1e-6;
+        for k in 0..3 {
+            let mut delta = [0.0; 3];
+            delta[k] = epsilon;
+            let (_, forward_gradient, _, _, _) = ndt.score(&pose.retract(&delta));
+            let (forward, _, _, _, _) = ndt.score(&pose.retract(&delta));
+            delta[k] = -epsilon;
+            let (_, backward_gradient, _, _, _) = ndt.score(&pose.retract(&delta));
+            let (backward, _, _, _, _) = ndt.score(&pose.retract(&delta));
+
+            let tolerance = 1e-6 * hessian.abs().max();
+            assert_approx_eq!(
+                gradient[k],
+                (forward - backward) / (2.0 * epsilon),
+                tolerance
+            );
+            let hessian_column = (forward_gradient - backward_gradient) / (2.0 * epsilon);
+            for l in 0..3 {
+                assert_approx_eq!(hessian[(l, k)], hessian_column[l], tolerance);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_matcher_trait() {
+        let expected_pose = Pose2::new(0.05, -0.02, 0.02);
+        let reference_points = room_gen(&expected_pose);
+        let scan_points = room_gen(&Pose2::new(0.0, 0.0, 0.0));
+        let init_pose = Pose2::new(0.0, 0.0, 0.0);
+
+        let mut matchers: Vec<Box<dyn ScanMatcher>> = vec![
+            Box::new(NormalDistributionsTransform2::new(
+                &scan_points,
+                &reference_points,
+                &init_pose,
+            )),
+            Box::new(IterativeClosestPoint2::new(
+                &scan_points,
+                &reference_points,
+                &init_pose,
+            )),
+        ];
+        for matcher in matchers.iter_mut() {
+            let result = matcher.scan_matching(100);
+            assert!(result.pose.distance(&expected_pose) < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_from_mapping() {
+        // Walls of a 3 m x 2 m room through cell centres, seen from the origin.
+        let beams = 360;
+        let angle_increment = 2.0 * std::f64::consts::PI / beams as f64;
+        let ranges = (0..beams)
+            .map(|i| {
+                let (sin, cos) = (i as f64 * angle_increment).sin_cos();
+                (1.5 / cos.abs()).min(1.0 / sin.abs())
+            })
+            .collect();
+        let scan = LaserScan::new(
+            10.0,
+            0.1,
+            angle_increment,
+            0.0,
+            2.0 * std::f64::consts::PI,
+            0.0,
+            ranges,
+        );
+        let mut mapping = Mapping::new(
+            grid_map::Position::new(-2.0, -1.5),
+            grid_map::Position::new(2.0, 1.5),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+        mapping.update(&na::Isometry2::identity(), &scan);
+
+        let expected_pose = Pose2::new(0.0, 0.0, 0.0);
+        let mut ndt = NormalDistributionsTransform2::from_mapping(
+            &Pointcloud2::from(scan),
+            &mapping,
+            &Pose2::new(0.03, -0.02, 0.02),
+        );
+        let result = ndt.scan_matching(100);
+
+        assert!(result.converged());
+        assert!(result.pose.distance(&expected_pose) < 0.01);
+    }
+
+    #[test]
+    fn test_stuck_initial_pose() {
+        // A wall in the neighbouring cell, too far for its distribution to pull the scan.
+        let wall = Pointcloud2::new((0..25).map(|i| Point2::new(i as f64 * 0.02, 0.0)).collect());
+        let init_pose = Pose2::new(0.0, 0.7, 0.0);
+        let mut ndt = NormalDistributionsTransform2::new(&wall, &wall, &init_pose);
+        let result = ndt.scan_matching(100);
+
+        println!("{:?}", result);
+
+        assert_eq!(result.status, ScanMatchingStatus::Diverged);
+        assert_eq!(result.pose, init_pose);
+        assert_eq!(result.inliers, 25);
+    }
+
+    #[test]
+    fn test_no_reference() {
+        let mut ndt = NormalDistributionsTransform2::new(
+            &room_gen(&Pose2::new(0.0, 0.0, 0.0)),
+            &Pointcloud2::new(Vec::new()),
+            &Pose2::new(0.0, 0.0, 0.0),
+        );
+        let result = ndt.scan_matching(10);
+
+        assert_eq!(result.status, ScanMatchingStatus::Diverged);
+        assert_eq!(result.inliers, 0);
+    }
+
+    fn data_gen(expected_pose: &Pose2) -> NormalDistributionsTransform2 {
+        NormalDistributionsTransform2::new(
+            &room_gen(&Pose2::new(0.0, 0.0, 0.0)),
+            &room_gen(expected_pose),
+            &Pose2::new(0.0, 0.0, 0.0),
+        )
+    }
+
+    /// Walls of a 4 m x 3 m room with a pillar, seen from `pose`.
+    fn room_gen(pose: &Pose2) -> Pointcloud2 {
+        let mut points = Vec::new();
+        for i in 0..200 {
+            let t = i as f64 * 0.02;
+            points.push(Point2::new(-2.0 + t, -1.5));
+            points.push(Point2::new(-2.0 + t, 1.5));
+            if t <= 3.0 {
+                points.push(Point2::new(-2.0, -1.5 + t));
+                points.push(Point2::new(2.0, -1.5 + t));
+            }
+            if t <= 0.4 {
+                points.push(Point2::new(0.5 + t, 0.3));
+                points.push(Point2::new(0.5, 0.3 + t));
+            }
+        }
+        Pointcloud2::new(points.iter().map(|p| pose.transform_point(p)).collect())
+    }
+}
@@ -0,0 +1,495 @@
+/// 2D pose graph with g2o and TORO file IO
+use crate::*;
+use nalgebra as na;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+/// Relative pose measurement between two nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoseGraphEdge2 {
+    pub from: usize,
+    pub to: usize,
+    /// Pose of `to` in the frame of `from`.
+    pub measurement: Pose2,
+    /// Inverse covariance of the measurement in (x, y, theta).
+    pub information: na::Matrix3<f64>,
+    /// Whether the edge closes a loop rather than following the trajectory.
+    pub loop_closure: bool,
+}
+
+/// Graph of robot poses linked by relative pose measurements, e.g. odometry and loop closures.
+///
+/// Nodes are keyed by id. Edges can only be added between existing nodes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoseGraph2 {
+    nodes: BTreeMap<usize, Pose2>,
+    edges: Vec<PoseGraphEdge2>,
+}
+
+impl PoseGraph2 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Nodes sorted by id.
+    pub fn nodes(&self) -> &BTreeMap<usize, Pose2> {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: usize) -> Option<Pose2> {
+        self.nodes.get(&id).copied()
+    }
+
+    /// Adds a node, or moves it if the id exists.
+    pub fn set_node(&mut self, id: usize, pose: &(impl Into<Pose2> + Clone)) {
+        self.nodes.insert(id, (*pose).clone().into());
+    }
+
+    /// Adds a node with the id following the largest one and returns the id.
+    pub fn push_node(&mut self, pose: &(impl Into<Pose2> + Clone)) -> usize {
+        let id = self.nodes.keys().next_back().map_or(0, |id| id + 1);
+        self.set_node(id, pose);
+        id
+    }
+
+    pub fn edges(&self) -> &[PoseGraphEdge2] {
+        &self.edges
+    }
+
+    /// Edges that close loops.
+    pub fn loop_closures(&self) -> impl Iterator<Item = &PoseGraphEdge2> {
+        self.edges.iter().filter(|edge| edge.loop_closure)
+    }
+
+    /// Adds an edge along the trajectory and returns its index.
+    ///
+    /// # Panics
+    /// If either node does not exist.
+    pub fn add_edge(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: &(impl Into<Pose2> + Clone),
+        information: na::Matrix3<f64>,
+    ) -> usize {
+        self.push_edge(from, to, measurement, information, false)
+    }
+
+    /// Adds a loop-closure edge and returns its index.
+    ///
+    /// The flag survives [`Self::save_g2o`] and [`Self::save_toro`] even between consecutive ids,
+    /// as a `# LOOP_CLOSURE 1` comment after the edge.
+    ///
+    /// # Panics
+    /// If either node does not exist.
+    pub fn add_loop_closure(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: &(impl Into<Pose2> + Clone),
+        information: na::Matrix3<f64>,
+    ) -> usize {
+        self.push_edge(from, to, measurement, information, true)
+    }
+
+    fn push_edge(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: &(impl Into<Pose2> + Clone),
+        information: na::Matrix3<f64>,
+        loop_closure: bool,
+    ) -> usize {
+        assert!(
+            self.nodes.contains_key(&from) && self.nodes.contains_key(&to),
+            "edge {} -> {} between missing nodes",
+            from,
+            to
+        );
+        self.edges.push(PoseGraphEdge2 {
+            from,
+            to,
+            measurement: (*measurement).clone().into(),
+            information,
+            loop_closure,
+        });
+        self.edges.len() - 1
+    }
+
+    /// Pose of `to` in the frame of `from` according to the node poses, which the measurement
+    /// of an edge between them should match.
+    pub fn relative_pose(&self, from: usize, to: usize) -> Option<Pose2> {
+        let from: na::Isometry2<f64> = self.node(from)?.into();
+        let to: na::Isometry2<f64> = self.node(to)?.into();
+        Some((from.inverse() * to).into())
+    }
+}
+
+/// File formats of [`PoseGraph2`]
+///
+/// g2o: `VERTEX_SE2 id x y theta` and `EDGE_SE2 from to dx dy dtheta` followed by the upper
+/// triangle of the information matrix row by row, `i11 i12 i13 i22 i23 i33`.
+///
+/// TORO: `VERTEX2 id x y theta` and `EDGE2 from to dx dy dtheta i11 i12 i22 i33 i13 i23`.
+///
+/// Neither format marks loop closures, so an edge read from a file is a loop closure unless it
+/// links consecutive ids. Where that guess is wrong, the writers follow the edge with a
+/// `# LOOP_CLOSURE 1` or `# LOOP_CLOSURE 0` line, which other tools skip as a comment. Other
+/// comments starting with `#` and other tags, e.g. `FIX`, are skipped.
+impl PoseGraph2 {
+    pub fn load_g2o(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_g2o(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn save_g2o(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_g2o(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn read_g2o(reader: &mut impl Read) -> Result<Self> {
+        Self::read_text(reader, PoseGraphFormat::G2o)
+    }
+
+    pub fn write_g2o(&self, writer: &mut impl Write) -> Result<()> {
+        self.write_text(writer, PoseGraphFormat::G2o)
+    }
+
+    pub fn load_toro(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_toro(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn save_toro(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_toro(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn read_toro(reader: &mut impl Read) -> Result<Self> {
+        Self::read_text(reader, PoseGraphFormat::Toro)
+    }
+
+    pub fn write_toro(&self, writer: &mut impl Write) -> Result<()> {
+        self.write_text(writer, PoseGraphFormat::Toro)
+    }
+
+    fn read_text(reader: &mut impl Read, format: PoseGraphFormat) -> Result<Self> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+
+        let mut graph = Self::new();
+        let mut edges: Vec<(usize, PoseGraphEdge2)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            if let Some(flag) = line.trim().strip_prefix(LOOP_CLOSURE_MARKER) {
+                let loop_closure = match flag.trim() {
+                    "1" => true,
+                    "0" => false,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("line {}: malformed loop closure flag", number + 1),
+                        ))
+                    }
+                };
+                let Some((_, edge)) = edges.last_mut() else {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("line {}: loop closure flag before any edge", number + 1),
+                    ));
+                };
+                edge.loop_closure = loop_closure;
+                continue;
+            }
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let Some(tag) = fields.next() else {
+                continue;
+            };
+            let is_vertex = match (format, tag) {
+                (PoseGraphFormat::G2o, "VERTEX_SE2") => true,
+                (PoseGraphFormat::G2o, "EDGE_SE2") => false,
+                (PoseGraphFormat::Toro, "VERTEX2" | "VERTEX") => true,
+                (PoseGraphFormat::Toro, "EDGE2" | "EDGE") => false,
+                _ => continue,
+            };
+
+            let error = || {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: malformed `{}`", number + 1, tag),
+                )
+            };
+            let fields = fields.collect::<Vec<_>>();
+            let id = |i: usize| fields[i].parse::<usize>().map_err(|_| error());
+            let values = |range: std::ops::Range<usize>| {
+                fields[range]
+                    .iter()
+                    .map(|field| field.parse::<f64>().map_err(|_| error()))
+                    .collect::<Result<Vec<_>>>()
+            };
+
+            if is_vertex {
+                if fields.len() != 4 {
+                    return Err(error());
+                }
+                let pose = values(1..4)?;
+                graph.set_node(id(0)?, &Pose2::new(pose[0], pose[1], pose[2]));
+            } else {
+                if fields.len() != 11 {
+                    return Err(error());
+                }
+                let (from, to) = (id(0)?, id(1)?);
+                let measurement = values(2..5)?;
+                let i = values(5..11)?;
+                let information = match format {
+                    PoseGraphFormat::G2o => na::Matrix3::new(
+                        i[0], i[1], i[2], //
+                        i[1], i[3], i[4], //
+                        i[2], i[4], i[5],
+                    ),
+                    PoseGraphFormat::Toro => na::Matrix3::new(
+                        i[0], i[1], i[4], //
+                        i[1], i[2], i[5], //
+                        i[4], i[5], i[3],
+                    ),
+                };
+                edges.push((
+                    number,
+                    PoseGraphEdge2 {
+                        from,
+                        to,
+                        measurement: Pose2::new(measurement[0], measurement[1], measurement[2]),
+                        information,
+                        loop_closure: is_loop_closure_by_ids(from, to),
+                    },
+                ));
+            }
+        }
+
+        // Vertices may follow the edges that use them.
+        for (number, edge) in edges {
+            if graph.node(edge.from).is_none() || graph.node(edge.to).is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "line {}: edge {} -> {} between missing nodes",
+                        number + 1,
+                        edge.from,
+                        edge.to
+                    ),
+                ));
+            }
+            graph.edges.push(edge);
+        }
+        Ok(graph)
+    }
+
+    fn write_text(&self, writer: &mut impl Write, format: PoseGraphFormat) -> Result<()> {
+        let (vertex_tag, edge_tag) = match format {
+            PoseGraphFormat::G2o => ("VERTEX_SE2", "EDGE_SE2"),
+            PoseGraphFormat::Toro => ("VERTEX2", "EDGE2"),
+        };
+        for (id, pose) in self.nodes.iter() {
+            writeln!(
+                writer,
+                "{} {} {} {} {}",
+                vertex_tag,
+                id,
+                pose.x(),
+                pose.y(),
+                pose.theta()
+            )?;
+        }
+        for edge in self.edges.iter() {
+            let i = &edge.information;
+            let information = match format {
+                PoseGraphFormat::G2o => [
+                    i[(0, 0)],
+                    i[(0, 1)],
+                    i[(0, 2)],
+                    i[(1, 1)],
+                    i[(1, 2)],
+                    i[(2, 2)],
+                ],
+                PoseGraphFormat::Toro => [
+                    i[(0, 0)],
+                    i[(0, 1)],
+                    i[(1, 1)],
+                    i[(2, 2)],
+                    i[(0, 2)],
+                    i[(1, 2)],
+                ],
+            };
+            write!(
+                writer,
+                "{} {} {} {} {} {}",
+                edge_tag,
+                edge.from,
+                edge.to,
+                edge.measurement.x(),
+                edge.measurement.y(),
+                edge.measurement.theta()
+            )?;
+            for value in information {
+                write!(writer, " {}", value)?;
+            }
+            writeln!(writer)?;
+            if edge.loop_closure != is_loop_closure_by_ids(edge.from, edge.to) {
+                writeln!(
+                    writer,
+                    "{} {}",
+                    LOOP_CLOSURE_MARKER, edge.loop_closure as u8
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Comment that sets the loop closure flag of the edge before it.
+const LOOP_CLOSURE_MARKER: &str = "# LOOP_CLOSURE";
+
+/// Loop closure flag of an edge read from a file without a marker.
+fn is_loop_closure_by_ids(from: usize, to: usize) -> bool {
+    from.abs_diff(to) != 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PoseGraphFormat {
+    G2o,
+    Toro,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_file_round_trip() {
+        let graph = square_graph();
+        assert_eq!(graph.nodes().len(), 4);
+        assert_eq!(graph.loop_closures().count(), 1);
+        let closure = graph.loop_closures().next().unwrap();
+        let relative_pose = graph.relative_pose(closure.from, closure.to).unwrap();
+        assert_approx_eq!(relative_pose.x(), 1.0);
+        assert_approx_eq!(relative_pose.y(), 0.0);
+
+        let mut g2o = Vec::new();
+        graph.write_g2o(&mut g2o).unwrap();
+        assert_eq!(
+            String::from_utf8(g2o.clone()).unwrap().lines().last(),
+            Some("EDGE_SE2 3 0 1 0 1.5707963267948966 100 1 2 100 3 400")
+        );
+        assert_eq!(PoseGraph2::read_g2o(&mut g2o.as_slice()).unwrap(), graph);
+
+        let mut toro = Vec::new();
+        graph.write_toro(&mut toro).unwrap();
+        assert_eq!(
+            String::from_utf8(toro.clone()).unwrap().lines().last(),
+            Some("EDGE2 3 0 1 0 1.5707963267948966 100 1 100 400 2 3")
+        );
+        assert_eq!(PoseGraph2::read_toro(&mut toro.as_slice()).unwrap(), graph);
+    }
+
+    #[test]
+    fn test_loop_closure_flags_round_trip() {
+        // Flags that the ids alone would get wrong.
+        let mut graph = PoseGraph2::new();
+        for i in 0..5 {
+            graph.push_node(&Pose2::new(i as f64, 0.0, 0.0));
+        }
+        let information = na::Matrix3::identity();
+        let skip = Pose2::new(2.0, 0.0, 0.0);
+        let step = Pose2::new(1.0, 0.0, 0.0);
+        graph.add_edge(0, 2, &skip, information);
+        graph.add_edge(2, 3, &step, information);
+        graph.add_loop_closure(3, 4, &step, information);
+        graph.add_loop_closure(4, 0, &Pose2::new(-4.0, 0.0, 0.0), information);
+
+        let mut g2o = Vec::new();
+        graph.write_g2o(&mut g2o).unwrap();
+        let text = String::from_utf8(g2o.clone()).unwrap();
+        assert_eq!(text.matches("# LOOP_CLOSURE").count(), 2);
+        assert_eq!(PoseGraph2::read_g2o(&mut g2o.as_slice()).unwrap(), graph);
+
+        let mut toro = Vec::new();
+        graph.write_toro(&mut toro).unwrap();
+        assert_eq!(PoseGraph2::read_toro(&mut toro.as_slice()).unwrap(), graph);
+
+        for text in [
+            "# LOOP_CLOSURE 1\n",
+            "VERTEX_SE2 0 0 0 0\nVERTEX_SE2 1 1 0 0\nEDGE_SE2 0 1 1 0 0 1 0 0 1 0 1\n\
+             # LOOP_CLOSURE yes\n",
+        ] {
+            let error = PoseGraph2::read_g2o(&mut text.as_bytes()).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_read_g2o() {
+        let text = "\
+# Two poses and an edge before the second vertex
+VERTEX_SE2 0 0.0 0.0 0.0
+FIX 0
+EDGE_SE2 0 1 1.0 0.0 0.1 10 0 0 10 0 20
+VERTEX_SE2 1 1.0 0.0 0.1  # after odometry
+";
+        let graph = PoseGraph2::read_g2o(&mut text.as_bytes()).unwrap();
+        assert_eq!(graph.nodes().len(), 2);
+        assert_eq!(graph.node(1), Some(Pose2::new(1.0, 0.0, 0.1)));
+        assert_eq!(graph.edges().len(), 1);
+        assert!(!graph.edges()[0].loop_closure);
+        assert_eq!(graph.edges()[0].information[(2, 2)], 20.0);
+
+        for text in [
+            "VERTEX_SE2 0 0.0 0.0\n",
+            "VERTEX_SE2 0 0.0 zero 0.0\n",
+            "VERTEX_SE2 0 0 0 0\nEDGE_SE2 0 1 1 0 0 1 0 0 1 0 1\n",
+        ] {
+            let error = PoseGraph2::read_g2o(&mut text.as_bytes()).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs the benchmark graphs in sample/pose_graphs"]
+    fn test_benchmark_graphs() {
+        let directory = format!("{}/sample/pose_graphs", env!("CARGO_MANIFEST_DIR"));
+        for (file_name, nodes, edges) in [
+            ("input_INTEL_g2o.g2o", 1228, 1483),
+            ("input_M3500_g2o.g2o", 3500, 5453),
+        ] {
+            let graph = PoseGraph2::load_g2o(format!("{}/{}", directory, file_name)).unwrap();
+            assert_eq!(graph.nodes().len(), nodes);
+            assert_eq!(graph.edges().len(), edges);
+            assert_eq!(graph.loop_closures().count(), edges - (nodes - 1));
+
+            let mut toro = Vec::new();
+            graph.write_toro(&mut toro).unwrap();
+            assert_eq!(PoseGraph2::read_toro(&mut toro.as_slice()).unwrap(), graph);
+        }
+    }
+
+    /// Unit square driven counterclockwise, closed by an edge from the last node to the first.
+    fn square_graph() -> PoseGraph2 {
+        let mut graph = PoseGraph2::new();
+        let information = na::Matrix3::new(100.0, 1.0, 2.0, 1.0, 100.0, 3.0, 2.0, 3.0, 400.0);
+        let step = Pose2::new(1.0, 0.0, std::f64::consts::FRAC_PI_2);
+        let mut previous = graph.push_node(&Pose2::new(0.0, 0.0, 0.0));
+        for i in 1..4 {
+            let pose = Pose2::new(
+                if i == 1 || i == 2 { 1.0 } else { 0.0 },
+                if i >= 2 { 1.0 } else { 0.0 },
+                normalize_angle(i as f64 * std::f64::consts::FRAC_PI_2),
+            );
+            let id = graph.push_node(&pose);
+            graph.add_edge(previous, id, &step, information);
+            previous = id;
+        }
+        graph.add_loop_closure(previous, 0, &step, information);
+        graph
+    }
+}
@@ -0,0 +1,559 @@
+/// Sparse nonlinear least-squares optimisation of 2D pose graphs
+use crate::*;
+use nalgebra as na;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_POSE_GRAPH_MAX_ITERATIONS: usize = 100;
+pub const DEFAULT_POSE_GRAPH_INITIAL_LAMBDA: f64 = 1e-4;
+const MAX_LAMBDA: f64 = 1e12;
+
+/// How [`PoseGraphOptimizer`] computes its steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoseGraphSolver {
+    /// Solves the normal equations as they are and always takes the step.
+    GaussNewton,
+    /// Adds lambda times the diagonal to the normal equations and only takes steps that reduce
+    /// chi2. Lambda follows Nielsen's rule: it shrinks when chi2 drops as much as the linearised
+    /// errors predict and grows ever faster over consecutive failures.
+    LevenbergMarquardt,
+}
+
+/// Why pose graph optimisation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoseGraphOptimizationStatus {
+    /// One of the convergence thresholds was met.
+    Converged,
+    /// The iteration limit was reached before convergence.
+    MaxIterationsReached,
+    /// The normal equations were singular, chi2 became invalid or lambda ran away.
+    Diverged,
+}
+
+/// Statistics of one iteration of [`PoseGraphOptimizer::optimize`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoseGraphIteration {
+    /// Chi2 of the poses after the iteration, i.e. before the step if it was rejected.
+    pub chi2: f64,
+    /// Damping of the step, zero for Gauss-Newton.
+    pub lambda: f64,
+    /// Largest change of a free node in x, y or theta. [m, rad]
+    pub step: f64,
+    /// Whether the step was applied to the poses.
+    pub accepted: bool,
+    /// Non-zeros in the factor of the normal equations.
+    pub factor_non_zeros: usize,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoseGraphOptimizationResult {
+    /// Chi2 of the poses before optimisation.
+    pub initial_chi2: f64,
+    /// Chi2 of the final poses.
+    pub chi2: f64,
+    pub iterations: Vec<PoseGraphIteration>,
+    pub status: PoseGraphOptimizationStatus,
+}
+
+impl PoseGraphOptimizationResult {
+    pub fn converged(&self) -> bool {
+        self.status == PoseGraphOptimizationStatus::Converged
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.iterations
+            .iter()
+            .map(|iteration| iteration.elapsed)
+            .sum()
+    }
+}
+
+/// Moves the nodes of a [`PoseGraph2`] to minimise chi2, the sum over the edges of the squared
+/// measurement errors weighted by their information matrices.
+///
+/// Each iteration linearises the errors with analytic Jacobians, assembles the block-sparse
+/// normal equations and solves them with a [`SparseLdlt`] in minimum degree order. The node
+/// with the smallest id is held fixed to remove the gauge freedom, so every other node must be
+/// connected to it through edges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoseGraphOptimizer {
+    pub solver: PoseGraphSolver,
+    pub max_iterations: usize,
+    /// Stops when chi2 decreases by less than this fraction of itself.
+    pub relative_chi2_delta: f64,
+    /// Stops when no coordinate of a free node moves by more than this. [m, rad]
+    pub step_delta: f64,
+    /// Initial damping of Levenberg-Marquardt relative to the diagonal.
+    pub initial_lambda: f64,
+}
+
+impl Default for PoseGraphOptimizer {
+    fn default() -> Self {
+        Self {
+            solver: PoseGraphSolver::LevenbergMarquardt,
+            max_iterations: DEFAULT_POSE_GRAPH_MAX_ITERATIONS,
+            relative_chi2_delta: 1e-6,
+            step_delta: 1e-9,
+            initial_lambda: DEFAULT_POSE_GRAPH_INITIAL_LAMBDA,
+        }
+    }
+}
+
+impl PoseGraphOptimizer {
+    pub fn new(solver: PoseGraphSolver) -> Self {
+        Self {
+            solver,
+            ..Default::default()
+        }
+    }
+
+    /// Chi2 of the graph at its current node poses.
+    pub fn chi2(graph: &PoseGraph2) -> f64 {
+        graph
+            .edges()
+            .iter()
+            .map(|edge| {
+                let error = edge_error(
+                    edge,
+                    &graph.node(edge.from).unwrap(),
+                    &graph.node(edge.to).unwrap(),
+                );
+                error.dot(&(edge.information * error))
+            })
+            .sum()
+    }
+
+    /// Optimises the node poses of the graph in place.
+    pub fn optimize(&self, graph: &mut PoseGraph2) -> PoseGraphOptimizationResult {
+        let problem = Problem::new(graph);
+        let mut poses = graph.nodes().values().copied().collect::<Vec<_>>();
+        let initial_chi2 = problem.chi2(&poses);
+        let mut result = PoseGraphOptimizationResult {
+            initial_chi2,
+            chi2: initial_chi2,
+            iterations: Vec::new(),
+            status: PoseGraphOptimizationStatus::MaxIterationsReached,
+        };
+        if problem.dimension() == 0 || initial_chi2 == 0.0 {
+            result.status = PoseGraphOptimizationStatus::Converged;
+            return result;
+        }
+        if !initial_chi2.is_finite() {
+            result.status = PoseGraphOptimizationStatus::Diverged;
+            return result;
+        }
+
+        let mut lambda = match self.solver {
+            PoseGraphSolver::GaussNewton => 0.0,
+            PoseGraphSolver::LevenbergMarquardt => self.initial_lambda,
+        };
+        let mut lambda_factor = 2.0;
+        let mut linear_system = None;
+        for _ in 0..self.max_iterations {
+            let start = Instant::now();
+            let (hessian, gradient) =
+                linear_system.get_or_insert_with(|| problem.linearize(&poses));
+            let damping = hessian.diagonal().map(|d| lambda * d.max(f64::EPSILON));
+            let mut damped = hessian.clone();
+            if lambda > 0.0 {
+                for (i, d) in damping.iter().enumerate() {
+                    damped.add(i, i, *d);
+                }
+            }
+
+            let ldlt = SparseLdlt::new(&damped, &problem.ordering)
+                .filter(|ldlt| lambda == 0.0 || ldlt.is_positive_definite());
+            let step = ldlt.as_ref().map(|ldlt| -ldlt.solve(gradient));
+            let candidate = step.as_ref().map(|step| problem.retract(&poses, step));
+            let chi2 = candidate
+                .as_ref()
+                .map_or(f64::NAN, |poses| problem.chi2(poses));
+            let step_size = step.as_ref().map_or(f64::INFINITY, |step| step.amax());
+            // Decrease of chi2 if the errors were linear, from H dx = -g - D dx.
+            let predicted_decrease = step.as_ref().map_or(0.0, |step| {
+                step.dot(&(damping.component_mul(step) - &*gradient))
+            });
+            let accepted = match self.solver {
+                PoseGraphSolver::GaussNewton => chi2.is_finite(),
+                PoseGraphSolver::LevenbergMarquardt => chi2 < result.chi2,
+            };
+
+            let previous_chi2 = result.chi2;
+            if accepted {
+                poses = candidate.unwrap();
+                result.chi2 = chi2;
+            }
+            result.iterations.push(PoseGraphIteration {
+                chi2: result.chi2,
+                lambda,
+                step: step_size,
+                accepted,
+                factor_non_zeros: ldlt.as_ref().map_or(0, |ldlt| ldlt.non_zeros()),
+                elapsed: start.elapsed(),
+            });
+
+            if accepted {
+                if (previous_chi2 - chi2).abs() <= self.relative_chi2_delta * previous_chi2
+                    || step_size < self.step_delta
+                {
+                    result.status = PoseGraphOptimizationStatus::Converged;
+                    break;
+                }
+                linear_system = None;
+                if lambda > 0.0 {
+                    let gain = (previous_chi2 - chi2) / predicted_decrease;
+                    lambda *= (1.0 - (2.0 * gain - 1.0).powi(3)).max(1.0 / 3.0);
+                    lambda_factor = 2.0;
+                }
+            } else if self.solver == PoseGraphSolver::GaussNewton {
+                result.status = PoseGraphOptimizationStatus::Diverged;
+                break;
+            } else if step_size < self.step_delta {
+                // No damped step improves chi2 any more.
+                result.status = PoseGraphOptimizationStatus::Converged;
+                break;
+            } else {
+                lambda *= lambda_factor;
+                lambda_factor *= 2.0;
+                if lambda > MAX_LAMBDA {
+                    result.status = PoseGraphOptimizationStatus::Diverged;
+                    break;
+                }
+            }
+        }
+
+        for (id, pose) in problem.ids.iter().zip(&poses) {
+            graph.set_node(*id, pose);
+        }
+        result
+    }
+}
+
+/// Error of the edge measurement given the poses of its nodes, i.e. the measurement taken out of
+/// the relative pose of the nodes. [m, m, rad]
+fn edge_error(edge: &PoseGraphEdge2, from: &Pose2, to: &Pose2) -> na::Vector3<f64> {
+    let from_rotation = na::Rotation2::new(from.theta());
+    let measurement_rotation = na::Rotation2::new(edge.measurement.theta());
+    let translation = measurement_rotation.inverse()
+        * (from_rotation.inverse() * na::Vector2::new(to.x() - from.x(), to.y() - from.y())
+            - na::Vector2::new(edge.measurement.x(), edge.measurement.y()));
+    na::Vector3::new(
+        translation.x,
+        translation.y,
+        normalize_angle(to.theta() - from.theta() - edge.measurement.theta()),
+    )
+}
+
+/// Edge error and its Jacobians with respect to (x, y, theta) of the `from` and `to` nodes.
+fn linearize_edge(
+    edge: &PoseGraphEdge2,
+    from: &Pose2,
+    to: &Pose2,
+) -> (na::Vector3<f64>, na::Matrix3<f64>, na::Matrix3<f64>) {
+    let (sin, cos) = from.theta().sin_cos();
+    let measurement_inverse = na::Rotation2::new(-edge.measurement.theta()).into_inner();
+    let from_inverse = na::Matrix2::new(cos, sin, -sin, cos);
+    let from_inverse_derivative = na::Matrix2::new(-sin, cos, -cos, -sin);
+    let delta = na::Vector2::new(to.x() - from.x(), to.y() - from.y());
+
+    let rotation = measurement_inverse * from_inverse;
+    let rotation_derivative = measurement_inverse * from_inverse_derivative * delta;
+    let from_jacobian = na::Matrix3::new(
+        -rotation[(0, 0)],
+        -rotation[(0, 1)],
+        rotation_derivative.x,
+        -rotation[(1, 0)],
+        -rotation[(1, 1)],
+        rotation_derivative.y,
+        0.0,
+        0.0,
+        -1.0,
+    );
+    let mut to_jacobian = na::Matrix3::identity();
+    to_jacobian
+        .fixed_view_mut::<2, 2>(0, 0)
+        .copy_from(&rotation);
+
+    (edge_error(edge, from, to), from_jacobian, to_jacobian)
+}
+
+/// Graph flattened for the iterations: nodes by index in id order, node 0 fixed and node i > 0
+/// owning variables 3 (i - 1) .. 3 i.
+struct Problem<'a> {
+    ids: Vec<usize>,
+    edges: Vec<(usize, usize, &'a PoseGraphEdge2)>,
+    /// Fill-reducing elimination order of the variables.
+    ordering: Vec<usize>,
+}
+
+impl<'a> Problem<'a> {
+    fn new(graph: &'a PoseGraph2) -> Self {
+        let ids = graph.nodes().keys().copied().collect::<Vec<_>>();
+        let index = |id| ids.binary_search(&id).unwrap();
+        let edges = graph
+            .edges()
+            .iter()
+            .map(|edge| (index(edge.from), index(edge.to), edge))
+            .collect::<Vec<_>>();
+
+        let free_nodes = ids.len().saturating_sub(1);
+        let mut adjacency = vec![Vec::new(); free_nodes];
+        for &(from, to, _) in &edges {
+            if from > 0 && to > 0 {
+                adjacency[from - 1].push(to - 1);
+            }
+        }
+        let ordering = minimum_degree_ordering(&adjacency)
+            .into_iter()
+            .flat_map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+            .collect();
+
+        Self {
+            ids,
+            edges,
+            ordering,
+        }
+    }
+
+    fn dimension(&self) -> usize {
+        self.ordering.len()
+    }
+
+    fn chi2(&self, poses: &[Pose2]) -> f64 {
+        self.edges
+            .iter()
+            .map(|&(from, to, edge)| {
+                let error = edge_error(edge, &poses[from], &poses[to]);
+                error.dot(&(edge.information * error))
+            })
+            .sum()
+    }
+
+    /// Normal equations H dx = -g of the errors linearised at the poses, as (H, g).
+    fn linearize(&self, poses: &[Pose2]) -> (SparseSymmetricMatrix, na::DVector<f64>) {
+        let mut hessian = SparseSymmetricMatrix::new(self.dimension());
+        let mut gradient = na::DVector::zeros(self.dimension());
+        for &(from, to, edge) in &self.edges {
+            let (error, from_jacobian, to_jacobian) =
+                linearize_edge(edge, &poses[from], &poses[to]);
+            let weighted_error = edge.information * error;
+            let blocks = [(from, from_jacobian), (to, to_jacobian)];
+            for (i, (node_i, jacobian_i)) in blocks.iter().enumerate() {
+                if *node_i == 0 {
+                    continue;
+                }
+                let row = 3 * (node_i - 1);
+                let mut gradient_block = gradient.fixed_rows_mut::<3>(row);
+                gradient_block += jacobian_i.transpose() * weighted_error;
+                for (node_j, jacobian_j) in &blocks[i..] {
+                    if *node_j == 0 {
+                        continue;
+                    }
+                    let block = jacobian_i.transpose() * edge.information * jacobian_j;
+                    let column = 3 * (node_j - 1);
+                    if row <= column {
+                        hessian.add_block(row, column, &block);
+                    } else {
+                        hessian.add_block(column, row, &block.transpose());
+                    }
+                }
+            }
+        }
+        (hessian, gradient)
+    }
+
+    fn retract(&self, poses: &[Pose2], step: &na::DVector<f64>) -> Vec<Pose2> {
+        let mut poses = poses.to_vec();
+        for (i, pose) in poses.iter_mut().enumerate().skip(1) {
+            let delta = step.fixed_rows::<3>(3 * (i - 1));
+            *pose = Pose2::new(
+                pose.x() + delta.x,
+                pose.y() + delta.y,
+                normalize_angle(pose.theta() + delta.z),
+            );
+        }
+        poses
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_edge_jacobians() {
+        let edge = PoseGraphEdge2 {
+            from: 0,
+            to: 1,
+            measurement: Pose2::new(0.8, -0.3, 0.4),
+            information: na::Matrix3::identity(),
+            loop_closure: false,
+        };
+        let from = Pose2::new(0.2, 0.5, 2.9);
+        let to = Pose2::new(-0.4, 1.1, -2.7);
+        let (error, from_jacobian, to_jacobian) = linearize_edge(&edge, &from, &to);
+
+        let epsilon = 1e-6;
+        let perturb = |pose: &Pose2, i: usize, h: f64| {
+            let mut delta = [0.0; 3];
+            delta[i] = h;
+            Pose2::new(
+                pose.x() + delta[0],
+                pose.y() + delta[1],
+                pose.theta() + delta[2],
+            )
+        };
+        for i in 0..3 {
+            let from_derivative = (edge_error(&edge, &perturb(&from, i, epsilon), &to)
+                - edge_error(&edge, &perturb(&from, i, -epsilon), &to))
+                / (2.0 * epsilon);
+            let to_derivative = (edge_error(&edge, &from, &perturb(&to, i, epsilon))
+                - edge_error(&edge, &from, &perturb(&to, i, -epsilon)))
+                / (2.0 * epsilon);
+            for j in 0..3 {
+                assert_approx_eq!(from_jacobian[(j, i)], from_derivative[j], 1e-6);
+                assert_approx_eq!(to_jacobian[(j, i)], to_derivative[j], 1e-6);
+            }
+        }
+        assert_approx_eq!(
+            error.z,
+            normalize_angle(to.theta() - from.theta() - edge.measurement.theta())
+        );
+    }
+
+    #[test]
+    fn test_exact_graph() {
+        let (truth, graph) = grid_graph(0.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        for solver in [
+            PoseGraphSolver::GaussNewton,
+            PoseGraphSolver::LevenbergMarquardt,
+        ] {
+            let mut perturbed = graph.clone();
+            for (&id, pose) in truth.nodes().iter().skip(1) {
+                perturbed.set_node(
+                    id,
+                    &Pose2::new(
+                        pose.x() + sample_normal(&mut rng, 0.01),
+                        pose.y() + sample_normal(&mut rng, 0.01),
+                        pose.theta() + sample_normal(&mut rng, 0.01),
+                    ),
+                );
+            }
+
+            let result = PoseGraphOptimizer::new(solver).optimize(&mut perturbed);
+            assert!(result.converged(), "{:?}", result);
+            assert!(result.chi2 < 1e-12);
+            assert!(result.chi2 < result.initial_chi2);
+            for (pose, expected) in perturbed.nodes().values().zip(truth.nodes().values()) {
+                assert_approx_eq!(pose.x(), expected.x(), 1e-6);
+                assert_approx_eq!(pose.y(), expected.y(), 1e-6);
+                assert_approx_eq!(normalize_angle(pose.theta() - expected.theta()), 0.0, 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_noisy_odometry() {
+        let (truth, mut graph) = grid_graph(1e-3);
+        let odometry_error = max_error(&graph, &truth);
+        let first = graph.node(0).unwrap();
+
+        let result = PoseGraphOptimizer::default().optimize(&mut graph);
+        assert!(result.converged(), "{:?}", result);
+        assert!(result.chi2 < 0.01 * result.initial_chi2);
+        assert_eq!(graph.node(0), Some(first));
+        assert!(max_error(&graph, &truth) < 0.2 * odometry_error);
+        assert_approx_eq!(result.chi2, PoseGraphOptimizer::chi2(&graph));
+
+        // Chi2 never increases along accepted Levenberg-Marquardt steps.
+        let chi2 = result.iterations.iter().map(|iteration| iteration.chi2);
+        assert!(chi2.clone().zip(chi2.skip(1)).all(|(a, b)| b <= a));
+    }
+
+    #[test]
+    #[ignore = "needs the benchmark graphs in sample/pose_graphs"]
+    fn test_benchmark_optimization() {
+        let directory = format!("{}/sample/pose_graphs", env!("CARGO_MANIFEST_DIR"));
+        for file_name in ["input_INTEL_g2o.g2o", "input_M3500_g2o.g2o"] {
+            let mut graph = PoseGraph2::load_g2o(format!("{}/{}", directory, file_name)).unwrap();
+            let result = PoseGraphOptimizer::default().optimize(&mut graph);
+            println!(
+                "{}: chi2 {:.3} -> {:.3} in {} iterations, {:?}",
+                file_name,
+                result.initial_chi2,
+                result.chi2,
+                result.iterations.len(),
+                result.elapsed()
+            );
+            assert!(result.converged());
+            assert!(result.chi2 < result.initial_chi2);
+        }
+    }
+
+    /// Robot driving a lawnmower pattern over a 10 x 10 grid, then back along the middle column,
+    /// with loop closures between neighbouring rows.
+    ///
+    /// Returns the true poses and a graph whose nodes are the odometry with the given noise
+    /// variance per step.
+    fn grid_graph(variance: f64) -> (PoseGraph2, PoseGraph2) {
+        let mut truth = PoseGraph2::new();
+        for row in 0..10 {
+            for column in 0..10 {
+                let (x, theta) = if row % 2 == 0 {
+                    (column as f64, 0.0)
+                } else {
+                    (9.0 - column as f64, std::f64::consts::PI)
+                };
+                truth.push_node(&Pose2::new(x, row as f64, theta));
+            }
+        }
+        for row in (0..10).rev() {
+            truth.push_node(&Pose2::new(5.0, row as f64, -std::f64::consts::FRAC_PI_2));
+        }
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let information = na::Matrix3::from_diagonal(&na::Vector3::new(100.0, 100.0, 400.0));
+        let mut graph = PoseGraph2::new();
+        graph.push_node(&truth.node(0).unwrap());
+        let mut odometry: na::Isometry2<f64> = truth.node(0).unwrap().into();
+        for id in 1..truth.nodes().len() {
+            let step = truth.relative_pose(id - 1, id).unwrap();
+            let noisy_step = Pose2::new(
+                step.x() + sample_normal(&mut rng, variance),
+                step.y() + sample_normal(&mut rng, variance),
+                step.theta() + sample_normal(&mut rng, variance),
+            );
+            odometry *= na::Isometry2::from(noisy_step);
+            graph.push_node(&Pose2::from(odometry));
+            graph.add_edge(id - 1, id, &noisy_step, information);
+        }
+
+        let position = |id: usize| {
+            let pose = truth.node(id).unwrap();
+            (pose.x().round() as i64, pose.y().round() as i64)
+        };
+        for from in 0..truth.nodes().len() {
+            for to in from + 2..truth.nodes().len() {
+                let (a, b) = (position(from), position(to));
+                if (a.0 - b.0).abs() + (a.1 - b.1).abs() <= 1 {
+                    let measurement = truth.relative_pose(from, to).unwrap();
+                    graph.add_loop_closure(from, to, &measurement, information);
+                }
+            }
+        }
+        (truth, graph)
+    }
+
+    fn max_error(graph: &PoseGraph2, truth: &PoseGraph2) -> f64 {
+        graph
+            .nodes()
+            .values()
+            .zip(truth.nodes().values())
+            .map(|(pose, expected)| (pose.x() - expected.x()).hypot(pose.y() - expected.y()))
+            .fold(0.0, f64::max)
+    }
+}
@@ -103,7 +103,8 @@ impl From<LaserScan> for Pointcloud2 {
         let header = laser_scan.header;
         let mut points = Vec::new();
         for (i, range) in laser_scan.ranges.iter().enumerate() {
-            if *range < laser_scan.range_min || *range > laser_scan.range_max {
+            if !range.is_finite() || *range < laser_scan.range_min || *range > laser_scan.range_max
+            {
                 continue;
             }
             let angle = laser_scan.angle_min + (i as f64) * laser_scan.angle_increment;
@@ -100,6 +100,15 @@ impl Odometry {
     }
 }
 
+/// Projects the pose onto the plane.
+impl From<Odometry> for Pose2 {
+    fn from(odometry: Odometry) -> Self {
+        let translation = odometry.pose().translation.vector;
+        let (_, _, yaw) = odometry.pose().rotation.euler_angles();
+        Self::new(translation.x, translation.y, yaw)
+    }
+}
+
 pub fn load_odometry_from_yaml(yaml: &str) -> Vec<Odometry> {
     let docs = YamlLoader::load_from_str(yaml).unwrap();
 
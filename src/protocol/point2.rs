@@ -41,6 +41,18 @@ impl Point for Point2 {
     }
 }
 
+impl KdPoint for Point2 {
+    const DIM: usize = 2;
+
+    fn coordinate(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => panic!("Point2 has no axis {}", axis),
+        }
+    }
+}
+
 impl From<na::Vector2<f64>> for Point2 {
     fn from(v: na::Vector2<f64>) -> Self {
         Self::new(v.x, v.y)
@@ -52,6 +52,19 @@ impl Point for Point3 {
     }
 }
 
+impl KdPoint for Point3 {
+    const DIM: usize = 3;
+
+    fn coordinate(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            2 => self.z,
+            _ => panic!("Point3 has no axis {}", axis),
+        }
+    }
+}
+
 impl From<na::Vector3<f64>> for Point3 {
     fn from(v: na::Vector3<f64>) -> Self {
         Self::new(v.x, v.y, v.z)
@@ -68,6 +68,88 @@ impl From<Pose2> for na::Isometry2<f64> {
     }
 }
 
+impl RigidTransform for Pose2 {
+    type Point = Point2;
+
+    const DOF: usize = 3;
+
+    fn transform_point(&self, point: &Point2) -> Point2 {
+        let (sin, cos) = self.theta.sin_cos();
+        Point2::new(
+            cos * point.x() - sin * point.y() + self.x,
+            sin * point.x() + cos * point.y() + self.y,
+        )
+    }
+
+    /// The increment is added to (x, y, theta).
+    fn retract(&self, delta: &[f64]) -> Self {
+        Self::new(self.x + delta[0], self.y + delta[1], self.theta + delta[2])
+    }
+
+    fn point_jacobian(&self, point: &Point2) -> na::DMatrix<f64> {
+        let (sin, cos) = self.theta.sin_cos();
+        na::DMatrix::from_row_slice(
+            2,
+            3,
+            &[
+                1.0,
+                0.0,
+                -sin * point.x() - cos * point.y(),
+                0.0,
+                1.0,
+                cos * point.x() - sin * point.y(),
+            ],
+        )
+    }
+
+    fn weighted_alignment(source: &[Point2], target: &[Point2], weights: &[f64]) -> Option<Self> {
+        if source.len() != target.len() || source.len() != weights.len() {
+            return None;
+        }
+        let total_weight = weights.iter().sum::<f64>();
+        if total_weight <= 0.0 {
+            return None;
+        }
+
+        let mean = |points: &[Point2]| {
+            points
+                .iter()
+                .zip(weights)
+                .map(|(p, w)| na::Vector2::from(*p) * *w)
+                .sum::<na::Vector2<f64>>()
+                / total_weight
+        };
+        let source_mean = mean(source);
+        let target_mean = mean(target);
+        let cross_covariance = source.iter().zip(target).zip(weights).fold(
+            na::Matrix2::zeros(),
+            |sum, ((s, t), w)| {
+                sum + *w
+                    * (na::Vector2::from(*t) - target_mean)
+                    * (na::Vector2::from(*s) - source_mean).transpose()
+            },
+        );
+
+        let svd = cross_covariance.svd(true, true);
+        let (u, v_t) = (svd.u?, svd.v_t?);
+        // Flip the last axis if the best orthogonal matrix is a reflection.
+        let d =
+            na::Matrix2::from_diagonal(&na::Vector2::new(1.0, (u * v_t).determinant().signum()));
+        let rotation = u * d * v_t;
+        let translation = target_mean - rotation * source_mean;
+
+        Some(Self::new(
+            translation.x,
+            translation.y,
+            rotation[(1, 0)].atan2(rotation[(0, 0)]),
+        ))
+    }
+
+    fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.theta.is_finite()
+    }
+}
+
 // TODO: Check following implementations are correct
 // NOTE: These implementations are required for argmin crate
 impl argmin_math::ArgminMul<f64, Pose2> for Pose2 {
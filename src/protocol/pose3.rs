@@ -69,6 +69,96 @@ impl Point for Pose3 {
     }
 }
 
+impl RigidTransform for Pose3 {
+    type Point = Point3;
+
+    const DOF: usize = 6;
+
+    fn transform_point(&self, point: &Point3) -> Point3 {
+        let p = self.q * na::Vector3::from(*point);
+        Point3::new(p.x + self.x, p.y + self.y, p.z + self.z)
+    }
+
+    /// The first three elements are added to the translation, the last three are a rotation
+    /// vector applied on the left of the orientation.
+    fn retract(&self, delta: &[f64]) -> Self {
+        let rotation =
+            na::UnitQuaternion::from_scaled_axis(na::Vector3::new(delta[3], delta[4], delta[5]));
+        Self::new(
+            self.x + delta[0],
+            self.y + delta[1],
+            self.z + delta[2],
+            rotation * self.q,
+        )
+    }
+
+    fn point_jacobian(&self, point: &Point3) -> na::DMatrix<f64> {
+        let rotated = self.q * na::Vector3::from(*point);
+        let mut jacobian = na::DMatrix::zeros(3, 6);
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&na::Matrix3::identity());
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(-rotated.cross_matrix()));
+        jacobian
+    }
+
+    fn weighted_alignment(source: &[Point3], target: &[Point3], weights: &[f64]) -> Option<Self> {
+        if source.len() != target.len() || source.len() != weights.len() {
+            return None;
+        }
+        let total_weight = weights.iter().sum::<f64>();
+        if total_weight <= 0.0 {
+            return None;
+        }
+
+        let mean = |points: &[Point3]| {
+            points
+                .iter()
+                .zip(weights)
+                .map(|(p, w)| na::Vector3::from(*p) * *w)
+                .sum::<na::Vector3<f64>>()
+                / total_weight
+        };
+        let source_mean = mean(source);
+        let target_mean = mean(target);
+        let cross_covariance = source.iter().zip(target).zip(weights).fold(
+            na::Matrix3::zeros(),
+            |sum, ((s, t), w)| {
+                sum + *w
+                    * (na::Vector3::from(*t) - target_mean)
+                    * (na::Vector3::from(*s) - source_mean).transpose()
+            },
+        );
+
+        let svd = cross_covariance.svd(true, true);
+        let (u, v_t) = (svd.u?, svd.v_t?);
+        // Flip the last axis if the best orthogonal matrix is a reflection.
+        let d = na::Matrix3::from_diagonal(&na::Vector3::new(
+            1.0,
+            1.0,
+            (u * v_t).determinant().signum(),
+        ));
+        let rotation = na::Rotation3::from_matrix_unchecked(u * d * v_t);
+        let translation = target_mean - rotation * source_mean;
+
+        Some(Self::new(
+            translation.x,
+            translation.y,
+            translation.z,
+            na::UnitQuaternion::from_rotation_matrix(&rotation),
+        ))
+    }
+
+    fn is_finite(&self) -> bool {
+        self.x.is_finite()
+            && self.y.is_finite()
+            && self.z.is_finite()
+            && self.q.coords.iter().all(|c| c.is_finite())
+    }
+}
+
 impl From<na::Isometry3<f64>> for Pose3 {
     fn from(isometry: na::Isometry3<f64>) -> Self {
         let translation = isometry.translation.vector;
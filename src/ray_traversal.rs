@@ -0,0 +1,301 @@
+/// Exact ray traversal of 2D and 3D grids (Amanatides and Woo)
+use grid_map::Position;
+use nalgebra as na;
+
+pub type RayCell2 = RayCell<2>;
+pub type RayCell3 = RayCell<3>;
+
+/// Cell crossed by a ray segment.
+///
+/// Cell `i` is centred at `min_point + i * resolution`, the same convention as `Mapping`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayCell<const N: usize> {
+    pub index: [i64; N],
+    /// Distance from the start of the segment at which the ray enters the cell. [m]
+    pub entry: f64,
+    /// Distance from the start of the segment at which the ray leaves the cell. [m]
+    pub exit: f64,
+}
+
+/// Returns every cell the segment crosses, in order, from the cell of `start` to the cell of
+/// `end`.
+pub fn ray_traversal2(
+    start: &na::Translation2<f64>,
+    end: &na::Translation2<f64>,
+    resolution: f64,
+    min_point: &Position,
+) -> Vec<RayCell2> {
+    traverse(
+        [start.x, start.y],
+        [end.x, end.y],
+        resolution,
+        [min_point.x, min_point.y],
+        None,
+    )
+}
+
+/// Like `ray_traversal2`, but only the part of the segment inside a grid of `map_size` cells.
+pub fn ray_traversal2_clipped(
+    start: &na::Translation2<f64>,
+    end: &na::Translation2<f64>,
+    resolution: f64,
+    min_point: &Position,
+    map_size: (usize, usize),
+) -> Vec<RayCell2> {
+    traverse(
+        [start.x, start.y],
+        [end.x, end.y],
+        resolution,
+        [min_point.x, min_point.y],
+        Some([map_size.0 as i64, map_size.1 as i64]),
+    )
+}
+
+/// Returns every voxel the segment crosses, in order, from the voxel of `start` to the voxel
+/// of `end`.
+pub fn ray_traversal3(
+    start: &na::Translation3<f64>,
+    end: &na::Translation3<f64>,
+    resolution: f64,
+    min_point: &na::Point3<f64>,
+) -> Vec<RayCell3> {
+    traverse(
+        [start.x, start.y, start.z],
+        [end.x, end.y, end.z],
+        resolution,
+        [min_point.x, min_point.y, min_point.z],
+        None,
+    )
+}
+
+/// Like `ray_traversal3`, but only the part of the segment inside a grid of `map_size` voxels.
+pub fn ray_traversal3_clipped(
+    start: &na::Translation3<f64>,
+    end: &na::Translation3<f64>,
+    resolution: f64,
+    min_point: &na::Point3<f64>,
+    map_size: (usize, usize, usize),
+) -> Vec<RayCell3> {
+    traverse(
+        [start.x, start.y, start.z],
+        [end.x, end.y, end.z],
+        resolution,
+        [min_point.x, min_point.y, min_point.z],
+        Some([map_size.0 as i64, map_size.1 as i64, map_size.2 as i64]),
+    )
+}
+
+fn traverse<const N: usize>(
+    start: [f64; N],
+    end: [f64; N],
+    resolution: f64,
+    min_point: [f64; N],
+    map_size: Option<[i64; N]>,
+) -> Vec<RayCell<N>> {
+    // In grid coordinates cell i spans [i, i + 1).
+    let grid_start: [f64; N] =
+        std::array::from_fn(|k| (start[k] - min_point[k]) / resolution + 0.5);
+    let grid_direction: [f64; N] = std::array::from_fn(|k| (end[k] - start[k]) / resolution);
+    let length = (0..N)
+        .map(|k| (end[k] - start[k]).powi(2))
+        .sum::<f64>()
+        .sqrt();
+    if !length.is_finite() || grid_start.iter().any(|g| !g.is_finite()) {
+        return Vec::new();
+    }
+
+    // Clip the parameter range [0, 1] of the segment to the grid (Liang-Barsky).
+    let (mut t_min, mut t_max) = (0.0_f64, 1.0_f64);
+    if let Some(map_size) = map_size {
+        for k in 0..N {
+            if grid_direction[k] == 0.0 {
+                if grid_start[k] < 0.0 || grid_start[k] >= map_size[k] as f64 {
+                    return Vec::new();
+                }
+                continue;
+            }
+            let t0 = (0.0 - grid_start[k]) / grid_direction[k];
+            let t1 = (map_size[k] as f64 - grid_start[k]) / grid_direction[k];
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+        }
+        if t_min > t_max {
+            return Vec::new();
+        }
+    }
+
+    let inside = |index: &[i64; N]| {
+        map_size.is_none_or(|map_size| (0..N).all(|k| index[k] >= 0 && index[k] < map_size[k]))
+    };
+
+    let entry_point: [f64; N] = std::array::from_fn(|k| grid_start[k] + t_min * grid_direction[k]);
+    let mut index: [i64; N] = std::array::from_fn(|k| {
+        let floor = entry_point[k].floor();
+        // A ray going down from a cell boundary starts in the lower cell.
+        if grid_direction[k] < 0.0 && floor == entry_point[k] {
+            floor as i64 - 1
+        } else {
+            floor as i64
+        }
+    });
+    if let Some(map_size) = map_size {
+        for k in 0..N {
+            index[k] = index[k].clamp(0, map_size[k] - 1);
+        }
+    }
+
+    let step: [i64; N] = std::array::from_fn(|k| {
+        if grid_direction[k] > 0.0 {
+            1
+        } else if grid_direction[k] < 0.0 {
+            -1
+        } else {
+            0
+        }
+    });
+    let t_delta: [f64; N] = std::array::from_fn(|k| (1.0 / grid_direction[k]).abs());
+    let mut t_next: [f64; N] = std::array::from_fn(|k| match step[k] {
+        1 => ((index[k] + 1) as f64 - grid_start[k]) / grid_direction[k],
+        -1 => (index[k] as f64 - grid_start[k]) / grid_direction[k],
+        _ => f64::INFINITY,
+    });
+
+    let mut cells = Vec::new();
+    let mut t_entry = t_min;
+    loop {
+        let axis = (0..N)
+            .min_by(|a, b| t_next[*a].total_cmp(&t_next[*b]))
+            .unwrap();
+        let t_exit = t_next[axis].min(t_max);
+        cells.push(RayCell {
+            index,
+            entry: t_entry * length,
+            exit: t_exit * length,
+        });
+        if t_next[axis] >= t_max {
+            break;
+        }
+
+        index[axis] += step[axis];
+        if !inside(&index) {
+            break;
+        }
+        t_entry = t_next[axis];
+        t_next[axis] += t_delta[axis];
+    }
+
+    cells
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_ray_traversal2() {
+        let cells = ray_traversal2(
+            &na::Translation2::new(0.2, 0.1),
+            &na::Translation2::new(2.6, 1.3),
+            1.0,
+            &Position::new(0.0, 0.0),
+        );
+        let indices = cells.iter().map(|cell| cell.index).collect::<Vec<_>>();
+
+        assert_eq!(indices, vec![[0, 0], [1, 0], [1, 1], [2, 1], [3, 1]]);
+        assert_approx_eq!(cells[0].entry, 0.0);
+        assert_approx_eq!(cells.last().unwrap().exit, 2.4_f64.hypot(1.2));
+        for pair in cells.windows(2) {
+            assert_approx_eq!(pair[0].exit, pair[1].entry);
+        }
+    }
+
+    #[test]
+    fn test_every_crossed_cell_is_returned() {
+        let start = na::Translation2::new(-0.37, 0.81);
+        let end = na::Translation2::new(4.13, -2.26);
+        let resolution = 0.1;
+        let min_point = Position::new(0.0, 0.0);
+        let cells = ray_traversal2(&start, &end, resolution, &min_point);
+
+        // Cells are 4-connected and contain the sampled points of the segment.
+        for pair in cells.windows(2) {
+            let distance = (pair[0].index[0] - pair[1].index[0]).abs()
+                + (pair[0].index[1] - pair[1].index[1]).abs();
+            assert_eq!(distance, 1);
+        }
+        let length = (end.vector - start.vector).norm();
+        for cell in cells.iter() {
+            let t = (cell.entry + cell.exit) / 2.0 / length;
+            let p = start.vector + (end.vector - start.vector) * t;
+            assert_eq!(
+                ((p.x - min_point.x) / resolution).round() as i64,
+                cell.index[0]
+            );
+            assert_eq!(
+                ((p.y - min_point.y) / resolution).round() as i64,
+                cell.index[1]
+            );
+        }
+        assert_eq!(cells[0].index, [-4, 8]);
+        assert_eq!(cells.last().unwrap().index, [41, -23]);
+    }
+
+    #[test]
+    fn test_clipping() {
+        let resolution = 0.5;
+        let min_point = Position::new(-1.0, -1.0);
+        let map_size = (5, 5);
+
+        let cells = ray_traversal2_clipped(
+            &na::Translation2::new(-3.0, 0.1),
+            &na::Translation2::new(3.0, 0.1),
+            resolution,
+            &min_point,
+            map_size,
+        );
+        let indices = cells.iter().map(|cell| cell.index).collect::<Vec<_>>();
+        assert_eq!(indices, vec![[0, 2], [1, 2], [2, 2], [3, 2], [4, 2]]);
+        assert_approx_eq!(cells[0].entry, 1.75);
+        assert_approx_eq!(cells.last().unwrap().exit, 4.25);
+
+        let outside = ray_traversal2_clipped(
+            &na::Translation2::new(-3.0, 5.0),
+            &na::Translation2::new(3.0, 5.0),
+            resolution,
+            &min_point,
+            map_size,
+        );
+        assert!(outside.is_empty());
+    }
+
+    #[test]
+    fn test_ray_traversal3() {
+        let start = na::Translation3::new(0.02, 0.02, 0.02);
+        let end = na::Translation3::new(0.32, 0.22, -0.18);
+        let cells = ray_traversal3(&start, &end, 0.1, &na::Point3::origin());
+
+        assert_eq!(cells[0].index, [0, 0, 0]);
+        assert_eq!(cells.last().unwrap().index, [3, 2, -2]);
+        assert_eq!(cells.len(), 1 + 3 + 2 + 2);
+        assert_approx_eq!(
+            cells.last().unwrap().exit,
+            (end.vector - start.vector).norm()
+        );
+
+        let clipped =
+            ray_traversal3_clipped(&start, &end, 0.1, &na::Point3::origin(), (10, 10, 10));
+        assert!(clipped.iter().all(|cell| cell.index[2] >= 0));
+        assert_eq!(clipped.last().unwrap().index[2], 0);
+    }
+
+    #[test]
+    fn test_zero_length() {
+        let point = na::Translation2::new(0.3, 0.3);
+        let cells = ray_traversal2(&point, &point, 0.1, &Position::new(0.0, 0.0));
+
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].index, [3, 3]);
+        assert_approx_eq!(cells[0].exit, 0.0);
+    }
+}
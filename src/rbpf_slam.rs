@@ -0,0 +1,388 @@
+/// SLAM with a Rao-Blackwellized particle filter
+use crate::*;
+use nalgebra as na;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Default number of particles.
+pub const DEFAULT_RBPF_PARTICLES: usize = 30;
+/// Default standard deviation of the scan-to-map residuals in the scan likelihood.
+pub const DEFAULT_RBPF_LIKELIHOOD_SIGMA: f64 = 0.3;
+/// Default fraction of the particles below which the effective sample size triggers resampling.
+pub const DEFAULT_RBPF_RESAMPLE_THRESHOLD: f64 = 0.5;
+/// Scan matching iterations per map level of the improved proposal.
+const RBPF_MATCHING_ITERATIONS: usize = 20;
+/// Scan matching of the improved proposal stops once the pose moves less than a tenth of a
+/// millimetre.
+const RBPF_CONVERGENCE_CRITERIA: ConvergenceCriteria = ConvergenceCriteria {
+    pose_delta: 1e-4,
+    cost_delta: 1e-6,
+};
+/// Offsets (x, y, theta) around the matched pose at which the proposal is evaluated. [m], [rad]
+const RBPF_SAMPLE_OFFSETS: (f64, f64, f64) = (0.01, 0.01, 0.005);
+/// Smallest fraction of the scan points inside the map for a match to be used.
+const RBPF_MIN_INLIER_RATIO: f64 = 0.5;
+/// Variance added to the proposal covariance so that it can be factorised.
+const RBPF_MIN_PROPOSAL_VARIANCE: f64 = 1e-12;
+
+/// Hypothesis of the particle filter: a trajectory and the map built along it.
+#[derive(Clone)]
+pub struct SlamParticle {
+    pose: Pose2,
+    weight: f64,
+    trajectory: Vec<Pose2>,
+    mapping: Mapping,
+    /// Pyramid of `mapping` for the scan matching, updated with the cells each scan changes.
+    pyramid: MapPyramid,
+}
+
+impl SlamParticle {
+    pub fn pose(&self) -> Pose2 {
+        self.pose
+    }
+
+    /// Normalised importance weight.
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// Poses at which the scans were integrated, oldest first.
+    pub fn trajectory(&self) -> &[Pose2] {
+        &self.trajectory
+    }
+
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+}
+
+/// SLAM with a Rao-Blackwellized particle filter in the style of GMapping (Grisetti et al.,
+/// 2007).
+///
+/// Every particle carries a trajectory and the map built along it. For each scan, the pose
+/// predicted by the odometry motion model is refined by scan-to-map matching against the map of
+/// the particle, and the new pose is drawn from a Gaussian fitted to the scan likelihood times
+/// the motion model around the match (improved proposal). Particles are resampled only when the
+/// effective sample size drops. The copies made by resampling share the map tiles until they
+/// write to them, and copy the map pyramid that each particle keeps up to date for the matching.
+///
+/// The map frame is the odometry frame at the first scan. The random numbers are seeded with 0,
+/// so that runs can be reproduced.
+pub struct RbpfSlam {
+    particles: Vec<SlamParticle>,
+    motion_model: OdometryMotionModel,
+    likelihood_sigma: f64,
+    resample_threshold: f64,
+    previous_odometry: Option<Pose2>,
+    rng: StdRng,
+}
+
+impl RbpfSlam {
+    /// Starts with `particles` particles, each with a copy of `mapping`, usually an empty map
+    /// with the resolution, the inverse sensor model and the policies to build.
+    pub fn new(mapping: Mapping, particles: usize) -> Self {
+        let count = particles.max(1);
+        let particle = SlamParticle {
+            pose: Pose2::new(0.0, 0.0, 0.0),
+            weight: 1.0 / count as f64,
+            trajectory: Vec::new(),
+            pyramid: MapPyramid::new(&mapping, DEFAULT_SCAN_TO_MAP_LEVELS),
+            mapping,
+        };
+        Self {
+            particles: vec![particle; count],
+            motion_model: OdometryMotionModel::default(),
+            likelihood_sigma: DEFAULT_RBPF_LIKELIHOOD_SIGMA,
+            resample_threshold: DEFAULT_RBPF_RESAMPLE_THRESHOLD,
+            previous_odometry: None,
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    /// Reseeds the generator behind the sampled motions and the resampling.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn particles(&self) -> &[SlamParticle] {
+        &self.particles
+    }
+
+    /// Particle with the largest weight.
+    pub fn best_particle(&self) -> &SlamParticle {
+        self.particles
+            .iter()
+            .max_by(|a, b| a.weight.total_cmp(&b.weight))
+            .unwrap()
+    }
+
+    /// Pose of the best particle.
+    pub fn pose(&self) -> Pose2 {
+        self.best_particle().pose
+    }
+
+    /// Map of the best particle.
+    pub fn mapping(&self) -> &Mapping {
+        &self.best_particle().mapping
+    }
+
+    pub fn motion_model(&self) -> OdometryMotionModel {
+        self.motion_model
+    }
+
+    pub fn set_motion_model(&mut self, motion_model: OdometryMotionModel) {
+        self.motion_model = motion_model;
+    }
+
+    pub fn likelihood_sigma(&self) -> f64 {
+        self.likelihood_sigma
+    }
+
+    /// Sets the standard deviation of the residuals of `ScanToMapMatcher`, which are between 0
+    /// and 1, in the scan likelihood. Larger values weight the particles more evenly.
+    pub fn set_likelihood_sigma(&mut self, likelihood_sigma: f64) {
+        self.likelihood_sigma = likelihood_sigma;
+    }
+
+    pub fn resample_threshold(&self) -> f64 {
+        self.resample_threshold
+    }
+
+    /// Fraction of the particles that the effective sample size has to fall below before the
+    /// maps are resampled, e.g. 0.5 as in GMapping. 1.0 resamples after every scan.
+    pub fn set_resample_threshold(&mut self, resample_threshold: f64) {
+        self.resample_threshold = resample_threshold;
+    }
+
+    /// Moves and weights the particles with the scan taken at the odometry pose, e.g. an
+    /// `Odometry` message, and integrates the scan into their maps.
+    pub fn update(&mut self, odometry: &(impl Into<Pose2> + Clone), laser_scan: &LaserScan) {
+        let odometry: Pose2 = (*odometry).clone().into();
+        let scan_points: Pointcloud2 = laser_scan.clone().into();
+
+        if let Some(previous_odometry) = self.previous_odometry {
+            let log_weights = (0..self.particles.len())
+                .map(|i| self.draw_pose(i, &previous_odometry, &odometry, &scan_points))
+                .collect::<Vec<_>>();
+            let max_log_weight = log_weights
+                .iter()
+                .copied()
+                .fold(f64::NEG_INFINITY, f64::max);
+            for (particle, log_weight) in self.particles.iter_mut().zip(log_weights) {
+                particle.weight *= (log_weight - max_log_weight).exp();
+            }
+            normalize_weights(
+                self.particles
+                    .iter_mut()
+                    .map(|particle| &mut particle.weight),
+            );
+
+            let effective_sample_size =
+                effective_sample_size(self.particles.iter().map(|particle| particle.weight));
+            if effective_sample_size < self.resample_threshold * self.particles.len() as f64 {
+                self.resample();
+            }
+        } else {
+            for particle in self.particles.iter_mut() {
+                particle.pose = odometry;
+            }
+        }
+        self.previous_odometry = Some(odometry);
+
+        for particle in self.particles.iter_mut() {
+            particle.trajectory.push(particle.pose);
+            let changes = particle.mapping.update(&particle.pose.into(), laser_scan);
+            particle
+                .pyramid
+                .update_from_changes(&particle.mapping, &changes);
+        }
+    }
+
+    /// Draws the new pose of a particle from the improved proposal and returns the log of the
+    /// weight update.
+    ///
+    /// Falls back to the motion model, weighted with the scan likelihood, if the scan does not
+    /// match the map of the particle.
+    fn draw_pose(
+        &mut self,
+        index: usize,
+        previous_odometry: &Pose2,
+        odometry: &Pose2,
+        scan_points: &Pointcloud2,
+    ) -> f64 {
+        let particle = &self.particles[index];
+        let previous_pose = particle.pose;
+        let predicted =
+            self.motion_model
+                .sample(&previous_pose, previous_odometry, odometry, &mut self.rng);
+
+        let mut matcher =
+            ScanToMapMatcher::from_pyramid(scan_points, &particle.pyramid, &predicted);
+        matcher.set_convergence_criteria(RBPF_CONVERGENCE_CRITERIA);
+        let result = matcher.scan_matching(RBPF_MATCHING_ITERATIONS);
+        let points = scan_points.points().len();
+        let variance = self.likelihood_sigma * self.likelihood_sigma;
+        // Points outside the map do not match anything.
+        let log_likelihood = |pose: &Pose2| {
+            let (cost, inliers) = matcher.residuals(pose);
+            -(cost + (points - inliers) as f64) / (2.0 * variance)
+        };
+
+        if result.status == ScanMatchingStatus::Diverged
+            || (result.inliers as f64) < RBPF_MIN_INLIER_RATIO * points as f64
+        {
+            let log_weight = log_likelihood(&predicted);
+            self.particles[index].pose = predicted;
+            return log_weight;
+        }
+
+        // Scan likelihood times motion model around the match.
+        let matched = result.pose;
+        let (step_x, step_y, step_theta) = RBPF_SAMPLE_OFFSETS;
+        let mut samples = Vec::with_capacity(27);
+        for dtheta in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let offset = na::Vector3::new(
+                        dx as f64 * step_x,
+                        dy as f64 * step_y,
+                        dtheta as f64 * step_theta,
+                    );
+                    let pose = Pose2::new(
+                        matched.x() + offset.x,
+                        matched.y() + offset.y,
+                        normalize_angle(matched.theta() + offset.z),
+                    );
+                    let log_probability = log_likelihood(&pose)
+                        + self.motion_model.log_probability(
+                            &pose,
+                            &previous_pose,
+                            previous_odometry,
+                            odometry,
+                        );
+                    samples.push((offset, log_probability));
+                }
+            }
+        }
+        let max_log_probability = samples
+            .iter()
+            .map(|(_, log_probability)| *log_probability)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let samples = samples
+            .into_iter()
+            .map(|(offset, log_probability)| {
+                (offset, (log_probability - max_log_probability).exp())
+            })
+            .collect::<Vec<_>>();
+        let normalizer = samples.iter().map(|(_, weight)| weight).sum::<f64>();
+        let mean = samples
+            .iter()
+            .map(|(offset, weight)| offset * *weight)
+            .sum::<na::Vector3<f64>>()
+            / normalizer;
+        let covariance = samples
+            .iter()
+            .map(|(offset, weight)| (offset - mean) * (offset - mean).transpose() * *weight)
+            .sum::<na::Matrix3<f64>>()
+            / normalizer
+            + na::Matrix3::identity() * RBPF_MIN_PROPOSAL_VARIANCE;
+
+        let noise = na::Vector3::from_fn(|_, _| sample_normal(&mut self.rng, 1.0));
+        let offset = mean
+            + na::Cholesky::new(covariance)
+                .map_or(na::Vector3::zeros(), |cholesky| cholesky.l() * noise);
+        self.particles[index].pose = Pose2::new(
+            matched.x() + offset.x,
+            matched.y() + offset.y,
+            normalize_angle(matched.theta() + offset.z),
+        );
+        // Integral of the product over the sampled cells, on the scale of the fallback weight.
+        max_log_probability + (normalizer * step_x * step_y * step_theta).ln()
+    }
+
+    /// Draws the particles anew with the low variance sampler. The copies share the maps of
+    /// the particles they were drawn from.
+    fn resample(&mut self) {
+        let weights = self
+            .particles
+            .iter()
+            .map(|particle| particle.weight)
+            .collect::<Vec<_>>();
+        let weight = 1.0 / weights.len() as f64;
+        self.particles = low_variance_sample(&mut self.rng, &weights)
+            .into_iter()
+            .map(|i| SlamParticle {
+                weight,
+                ..self.particles[i].clone()
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use grid_map::Position;
+
+    #[test]
+    fn test_drifting_odometry() {
+        let mapping = Mapping::new(
+            Position::new(-4.0, -3.0),
+            Position::new(4.0, 3.0),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+        let mut slam = RbpfSlam::new(mapping, 3);
+
+        // Odometry overestimates every translation and rotation by 10 %.
+        let mut pose = loop_pose(0);
+        let mut odometry = isometry(&pose);
+        slam.update(&odometry, &room_scan(&pose));
+        for i in 1..15 {
+            let motion = isometry(&pose).inverse() * isometry(&loop_pose(i));
+            odometry *= na::Isometry2::new(
+                motion.translation.vector * 1.1,
+                motion.rotation.angle() * 1.1,
+            );
+            pose = loop_pose(i);
+            slam.update(&odometry, &room_scan(&pose));
+        }
+
+        let estimate = slam.pose();
+        println!("{:?} for {:?}", estimate, pose);
+        // Odometry alone ends 0.19 m and 0.14 rad off.
+        assert!((estimate.x() - pose.x()).hypot(estimate.y() - pose.y()) < 0.05);
+        assert!(normalize_angle(estimate.theta() - pose.theta()).abs() < 0.03);
+
+        let best = slam.best_particle();
+        assert_eq!(best.trajectory().len(), 15);
+        assert_eq!(best.trajectory().last(), Some(&estimate));
+        let off_wall = best
+            .mapping()
+            .get_occupied_grids_positions()
+            .iter()
+            .filter(|position| distance_to_walls(position) > 0.1)
+            .count();
+        assert_eq!(off_wall, 0);
+    }
+
+    #[test]
+    fn test_resampling_keeps_particles() {
+        let mapping = room_mapping(0.05);
+        let mut slam = RbpfSlam::new(mapping, 5);
+        slam.set_resample_threshold(1.0);
+
+        for i in 0..5 {
+            let pose = Pose2::new(-1.0 + 0.1 * i as f64, 0.0, 0.0);
+            slam.update(&pose, &room_scan(&pose));
+        }
+
+        assert_eq!(slam.particles().len(), 5);
+        for particle in slam.particles() {
+            assert_eq!(particle.weight(), 0.2);
+            assert_eq!(particle.trajectory().len(), 5);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+/// Robust loss applied to the residuals of a least-squares problem.
+///
+/// Each kernel `rho` replaces the squared residual `r^2` and behaves like it for small residuals.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RobustKernel {
+    /// Plain squared loss.
+    #[default]
+    Squared,
+    /// Quadratic up to the threshold, linear beyond it.
+    Huber(f64),
+    /// Logarithmic growth with the given scale.
+    Cauchy(f64),
+    /// Tukey's biweight; residuals beyond the threshold have no influence.
+    Tukey(f64),
+}
+
+impl RobustKernel {
+    /// Loss of a residual.
+    pub fn rho(&self, residual: f64) -> f64 {
+        let r2 = residual * residual;
+        match *self {
+            RobustKernel::Squared => r2,
+            RobustKernel::Huber(k) => {
+                if residual.abs() <= k {
+                    r2
+                } else {
+                    2.0 * k * residual.abs() - k * k
+                }
+            }
+            RobustKernel::Cauchy(c) => c * c * (1.0 + r2 / (c * c)).ln(),
+            RobustKernel::Tukey(c) => {
+                if residual.abs() <= c {
+                    c * c / 3.0 * (1.0 - (1.0 - r2 / (c * c)).powi(3))
+                } else {
+                    c * c / 3.0
+                }
+            }
+        }
+    }
+
+    /// Weight of a residual for iteratively reweighted least squares, `rho'(r) / 2r`.
+    pub fn weight(&self, residual: f64) -> f64 {
+        let r2 = residual * residual;
+        match *self {
+            RobustKernel::Squared => 1.0,
+            RobustKernel::Huber(k) => {
+                if residual.abs() <= k {
+                    1.0
+                } else {
+                    k / residual.abs()
+                }
+            }
+            RobustKernel::Cauchy(c) => 1.0 / (1.0 + r2 / (c * c)),
+            RobustKernel::Tukey(c) => {
+                if residual.abs() <= c {
+                    (1.0 - r2 / (c * c)).powi(2)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    const KERNELS: [RobustKernel; 4] = [
+        RobustKernel::Squared,
+        RobustKernel::Huber(0.5),
+        RobustKernel::Cauchy(0.5),
+        RobustKernel::Tukey(0.5),
+    ];
+
+    #[test]
+    fn test_small_residuals_are_squared() {
+        for kernel in KERNELS {
+            assert_approx_eq!(kernel.rho(1e-3), 1e-6, 1e-9);
+            assert_approx_eq!(kernel.weight(0.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_weight_is_derivative_of_rho() {
+        let epsilon = 1e-6;
+        for kernel in KERNELS {
+            for residual in [0.1, 0.4, 0.7, 2.0] {
+                let derivative = (kernel.rho(residual + epsilon) - kernel.rho(residual - epsilon))
+                    / (2.0 * epsilon);
+                assert_approx_eq!(kernel.weight(residual), derivative / (2.0 * residual), 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_residuals_are_downweighted() {
+        assert!(RobustKernel::Huber(0.5).rho(10.0) < RobustKernel::Squared.rho(10.0));
+        assert!(RobustKernel::Cauchy(0.5).rho(10.0) < RobustKernel::Huber(0.5).rho(10.0));
+        assert_approx_eq!(RobustKernel::Tukey(0.5).weight(10.0), 0.0);
+    }
+}
@@ -0,0 +1,85 @@
+/// Types shared by the scan matchers
+use crate::*;
+use nalgebra as na;
+
+/// Thresholds between two consecutive iterations below which scan matching is converged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConvergenceCriteria {
+    /// Change of the estimated pose. [m, rad]
+    pub pose_delta: f64,
+    /// Change of the cost minimised by the matcher.
+    pub cost_delta: f64,
+}
+
+impl ConvergenceCriteria {
+    pub fn is_converged<P: Point>(
+        &self,
+        previous_pose: &P,
+        pose: &P,
+        previous_cost: f64,
+        cost: f64,
+    ) -> bool {
+        pose.distance(previous_pose) < self.pose_delta
+            || (previous_cost - cost).abs() < self.cost_delta
+    }
+}
+
+impl Default for ConvergenceCriteria {
+    fn default() -> Self {
+        Self {
+            pose_delta: 1e-9,
+            cost_delta: 1e-12,
+        }
+    }
+}
+
+/// Why scan matching stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMatchingStatus {
+    /// One of the convergence criteria was met.
+    Converged,
+    /// The iteration limit was reached, or no step decreased the cost any more, before
+    /// convergence.
+    MaxIterationsReached,
+    /// The error or the pose became invalid, or there was nothing to match.
+    Diverged,
+}
+
+/// Outcome of a scan matching run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanMatchingResult<T = Pose2> {
+    /// Final estimated robot pose.
+    pub pose: T,
+    /// Covariance of the pose in the tangent space of [`RigidTransform::retract`], e.g.
+    /// (x, y, theta) for [`Pose2`].
+    pub covariance: na::DMatrix<f64>,
+    /// Mean residual of the correspondences at the final pose, in the error unit of the matcher.
+    pub residual: f64,
+    /// Number of iterations performed.
+    pub iterations: usize,
+    /// Number of correspondences used at the final pose.
+    pub inliers: usize,
+    pub status: ScanMatchingStatus,
+}
+
+impl<T> ScanMatchingResult<T> {
+    pub fn converged(&self) -> bool {
+        self.status == ScanMatchingStatus::Converged
+    }
+}
+
+/// Smallest step length tried by the backtracking line searches.
+pub(crate) const MIN_STEP_LENGTH: f64 = 1e-4;
+/// Smallest Hessian eigenvalue used for the pose covariance, relative to the largest one.
+pub(crate) const HESSIAN_EIGENVALUE_FLOOR: f64 = 1e-9;
+
+/// Inverts a symmetric positive semi-definite matrix, raising eigenvalues below
+/// `relative_floor` times the largest one to that floor.
+pub fn regularized_inverse(matrix: &na::DMatrix<f64>, relative_floor: f64) -> na::DMatrix<f64> {
+    let eigen = matrix.clone().symmetric_eigen();
+    let floor = eigen.eigenvalues.max().max(f64::EPSILON) * relative_floor;
+    let inverse_eigenvalues = eigen.eigenvalues.map(|l| 1.0 / l.max(floor));
+    &eigen.eigenvectors
+        * na::DMatrix::from_diagonal(&inverse_eigenvalues)
+        * eigen.eigenvectors.transpose()
+}
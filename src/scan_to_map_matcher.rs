@@ -0,0 +1,420 @@
+/// Scan-to-map matching against the occupancy grid
+use crate::*;
+use grid_map::Grid;
+use nalgebra as na;
+use std::borrow::Cow;
+
+/// Default number of map resolutions, each twice as coarse as the previous one.
+pub const DEFAULT_SCAN_TO_MAP_LEVELS: usize = 3;
+/// Smallest variance of the residuals used for the pose covariance.
+const MIN_RESIDUAL_VARIANCE: f64 = 1e-6;
+
+/// Bilinear interpolation of the occupancy probability of a pyramid level between the cell
+/// centres, and its gradient. Unexplored cells count as free. Returns `None` outside the level.
+fn interpolate(level: &MapLevel, position: &na::Vector2<f64>) -> Option<(f64, na::Vector2<f64>)> {
+    let (width, height) = level.map_size();
+    let u = (position.x - level.min_point().x) / level.resolution();
+    let v = (position.y - level.min_point().y) / level.resolution();
+    if u < -0.5 || v < -0.5 || u > width as f64 - 0.5 || v > height as f64 - 0.5 {
+        return None;
+    }
+
+    let get = |x: i64, y: i64| {
+        if x < 0 || y < 0 {
+            return 0.0;
+        }
+        level
+            .probability(&Grid::new(x as usize, y as usize))
+            .unwrap_or(0.0)
+    };
+    let (i, j) = (u.floor(), v.floor());
+    let (fu, fv) = (u - i, v - j);
+    let (i, j) = (i as i64, j as i64);
+    let m00 = get(i, j);
+    let m10 = get(i + 1, j);
+    let m01 = get(i, j + 1);
+    let m11 = get(i + 1, j + 1);
+
+    let value = (1.0 - fv) * ((1.0 - fu) * m00 + fu * m10) + fv * ((1.0 - fu) * m01 + fu * m11);
+    let gradient = na::Vector2::new(
+        (1.0 - fv) * (m10 - m00) + fv * (m11 - m01),
+        (1.0 - fu) * (m01 - m00) + fu * (m11 - m10),
+    ) / level.resolution();
+    Some((value, gradient))
+}
+
+/// Hector-SLAM style matcher that aligns a scan with the interpolated occupancy probability.
+///
+/// Each scan point has the residual `1 - M(p)`, where `M` is the bilinear interpolation of the
+/// occupancy grid. The pose is refined with Gauss-Newton from the coarsest level of the map
+/// pyramid down to the map resolution.
+#[derive(Debug, Clone)]
+pub struct ScanToMapMatcher<'a> {
+    /// Robot coordinates of the scan points.
+    scan_points: Pointcloud2,
+    /// Map pyramid, from the map resolution to the coarsest level, owned or borrowed.
+    pyramid: Cow<'a, MapPyramid>,
+    /// Estimated robot pose.
+    robot_pose: Pose2,
+    /// Thresholds that stop the iterations on each level early.
+    convergence_criteria: ConvergenceCriteria,
+}
+
+impl<'a> ScanToMapMatcher<'a> {
+    /// Builds the map pyramid of `mapping`. Unexplored cells count as free.
+    pub fn new(
+        scan_points: &(impl Into<Pointcloud2> + Clone),
+        mapping: &Mapping,
+        robot_pose: &(impl Into<Pose2> + Clone),
+    ) -> Self {
+        Self {
+            scan_points: (*scan_points).clone().into(),
+            pyramid: Cow::Owned(MapPyramid::new(mapping, DEFAULT_SCAN_TO_MAP_LEVELS)),
+            robot_pose: (*robot_pose).clone().into(),
+            convergence_criteria: ConvergenceCriteria::default(),
+        }
+    }
+
+    /// Matches against an existing pyramid, e.g. one kept up to date with
+    /// `MapPyramid::update_from_changes`, instead of reading every map cell.
+    pub fn from_pyramid(
+        scan_points: &(impl Into<Pointcloud2> + Clone),
+        pyramid: &'a MapPyramid,
+        robot_pose: &(impl Into<Pose2> + Clone),
+    ) -> Self {
+        Self {
+            scan_points: (*scan_points).clone().into(),
+            pyramid: Cow::Borrowed(pyramid),
+            robot_pose: (*robot_pose).clone().into(),
+            convergence_criteria: ConvergenceCriteria::default(),
+        }
+    }
+
+    pub fn robot_pose(&self) -> Pose2 {
+        self.robot_pose
+    }
+
+    pub fn set_robot_pose(&mut self, robot_pose: &(impl Into<Pose2> + Clone)) {
+        self.robot_pose = (*robot_pose).clone().into();
+    }
+
+    pub fn set_scan_points(&mut self, scan_points: &(impl Into<Pointcloud2> + Clone)) {
+        self.scan_points = (*scan_points).clone().into();
+    }
+
+    pub fn levels(&self) -> usize {
+        self.pyramid.levels()
+    }
+
+    /// Sets the number of map resolutions used, at least one. A borrowed pyramid is copied.
+    pub fn set_levels(&mut self, levels: usize) {
+        self.pyramid.to_mut().set_levels(levels);
+    }
+
+    pub fn convergence_criteria(&self) -> ConvergenceCriteria {
+        self.convergence_criteria
+    }
+
+    pub fn set_convergence_criteria(&mut self, convergence_criteria: ConvergenceCriteria) {
+        self.convergence_criteria = convergence_criteria;
+    }
+
+    /// Returns the Gauss-Newton Hessian, the gradient, the sum of squared residuals, the number
+    /// of scan points inside the map and the sum of their residuals at `pose`.
+    fn normal_equations(
+        &self,
+        level: usize,
+        pose: &Pose2,
+    ) -> (na::Matrix3<f64>, na::Vector3<f64>, f64, usize, f64) {
+        let level = self.pyramid.level(level);
+        let (sin, cos) = pose.theta().sin_cos();
+        let mut hessian = na::Matrix3::zeros();
+        let mut gradient = na::Vector3::zeros();
+        let mut cost = 0.0;
+        let mut inliers = 0;
+        let mut residual_sum = 0.0;
+
+        for point in self.scan_points.points() {
+            let world = na::Vector2::from(pose.transform_point(point));
+            let Some((value, map_gradient)) = interpolate(level, &world) else {
+                continue;
+            };
+            let residual = 1.0 - value;
+            let jacobian = na::RowVector3::new(
+                -map_gradient.x,
+                -map_gradient.y,
+                -map_gradient.x * (-sin * point.x() - cos * point.y())
+                    - map_gradient.y * (cos * point.x() - sin * point.y()),
+            );
+            hessian += jacobian.transpose() * jacobian;
+            gradient += jacobian.transpose() * residual;
+            cost += residual * residual;
+            inliers += 1;
+            residual_sum += residual;
+        }
+
+        (hessian, gradient, cost, inliers, residual_sum)
+    }
+
+    /// Returns the sum of squared residuals and the number of scan points inside the map at
+    /// `pose`, at the map resolution.
+    pub fn residuals(&self, pose: &(impl Into<Pose2> + Clone)) -> (f64, usize) {
+        let (_, _, cost, inliers, _) = self.normal_equations(0, &(*pose).clone().into());
+        (cost, inliers)
+    }
+
+    /// Returns the sum of squared residuals and the number of scan points inside the map at
+    /// `pose` on a level, and the full Gauss-Newton step from there if the Hessian is invertible.
+    fn gauss_newton_step(
+        &self,
+        level: usize,
+        pose: &Pose2,
+    ) -> (f64, usize, Option<na::Vector3<f64>>) {
+        let (hessian, gradient, cost, inliers, _) = self.normal_equations(level, pose);
+        let step = hessian.try_inverse().map(|inverse| -(inverse * gradient));
+        (cost, inliers, step)
+    }
+
+    /// Returns whether the full Gauss-Newton step from `pose` on a level meets the convergence
+    /// criteria.
+    fn is_stationary(&self, level: usize, pose: &Pose2) -> bool {
+        let (cost, _, Some(step)) = self.gauss_newton_step(level, pose) else {
+            return false;
+        };
+        let candidate = pose.retract(step.as_slice());
+        let (_, _, candidate_cost, _, _) = self.normal_equations(level, &candidate);
+        self.convergence_criteria
+            .is_converged(pose, &candidate, cost, candidate_cost)
+    }
+
+    /// Takes one Gauss-Newton step on a level, halving it until the cost decreases.
+    ///
+    /// Returns `false` if no step decreased the cost.
+    pub fn optimize_once(&mut self, level: usize) -> bool {
+        let (cost, inliers, step) = self.gauss_newton_step(level, &self.robot_pose);
+        if inliers == 0 {
+            return false;
+        }
+        let Some(step) = step else {
+            return false;
+        };
+
+        let mut step_length = 1.0;
+        while step_length >= MIN_STEP_LENGTH {
+            let candidate = self.robot_pose.retract((step * step_length).as_slice());
+            let (_, _, candidate_cost, candidate_inliers, _) =
+                self.normal_equations(level, &candidate);
+            // Points that leave the map would otherwise lower the cost for free.
+            if candidate_inliers == inliers && candidate_cost < cost {
+                self.robot_pose = candidate;
+                return true;
+            }
+            step_length *= 0.5;
+        }
+        false
+    }
+
+    /// Runs up to `max_iterations` on every level, from the coarsest to the finest.
+    ///
+    /// The status and the covariance refer to the finest level. If no step on any level has
+    /// decreased the cost when a level stalls, the match diverged. Otherwise a stalled level is
+    /// converged only if the full Gauss-Newton step meets the criteria.
+    pub fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult {
+        let mut iterations = 0;
+        let mut status = ScanMatchingStatus::MaxIterationsReached;
+        let mut moved = false;
+
+        'levels: for level in (0..self.pyramid.levels()).rev() {
+            status = ScanMatchingStatus::MaxIterationsReached;
+            let (_, _, mut previous_cost, inliers, _) =
+                self.normal_equations(level, &self.robot_pose);
+            if inliers == 0 {
+                status = ScanMatchingStatus::Diverged;
+                break;
+            }
+            for _ in 0..max_iterations {
+                let previous_pose = self.robot_pose;
+                let improved = self.optimize_once(level);
+                iterations += 1;
+                if !self.robot_pose.is_finite() {
+                    status = ScanMatchingStatus::Diverged;
+                    break 'levels;
+                }
+                if !improved {
+                    // A finer level starts over and may still move the pose.
+                    status = if !moved {
+                        ScanMatchingStatus::Diverged
+                    } else if self.is_stationary(level, &self.robot_pose) {
+                        ScanMatchingStatus::Converged
+                    } else {
+                        ScanMatchingStatus::MaxIterationsReached
+                    };
+                    break;
+                }
+                moved = true;
+                let (_, _, cost, _, _) = self.normal_equations(level, &self.robot_pose);
+                if self.convergence_criteria.is_converged(
+                    &previous_pose,
+                    &self.robot_pose,
+                    previous_cost,
+                    cost,
+                ) {
+                    status = ScanMatchingStatus::Converged;
+                    break;
+                }
+                previous_cost = cost;
+            }
+        }
+
+        let (hessian, _, cost, inliers, residual_sum) = self.normal_equations(0, &self.robot_pose);
+        let (residual, covariance) = if inliers == 0 || status == ScanMatchingStatus::Diverged {
+            (
+                f64::INFINITY,
+                na::DMatrix::from_diagonal_element(3, 3, f64::INFINITY),
+            )
+        } else {
+            let degrees_of_freedom = inliers.saturating_sub(3).max(1);
+            let variance = (cost / degrees_of_freedom as f64).max(MIN_RESIDUAL_VARIANCE);
+            let hessian = na::DMatrix::from_column_slice(3, 3, hessian.as_slice());
+            (
+                residual_sum / inliers as f64,
+                variance * regularized_inverse(&hessian, HESSIAN_EIGENVALUE_FLOOR),
+            )
+        };
+
+        ScanMatchingResult {
+            pose: self.robot_pose,
+            covariance,
+            residual,
+            iterations,
+            inliers,
+            status,
+        }
+    }
+}
+
+impl ScanMatcher for ScanToMapMatcher<'_> {
+    fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult {
+        ScanToMapMatcher::scan_matching(self, max_iterations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_scan_to_map() {
+        let mapping = room_mapping(0.05);
+        let expected_pose = Pose2::new(0.1, -0.08, 0.05);
+        let mut matcher = ScanToMapMatcher::new(
+            &room_scan(&expected_pose),
+            &mapping,
+            &Pose2::new(0.0, 0.0, 0.0),
+        );
+        let result = matcher.scan_matching(30);
+
+        println!("{:?}", result);
+
+        assert_ne!(result.status, ScanMatchingStatus::Diverged);
+        assert!(result.pose.distance(&expected_pose) < 1e-3);
+        assert_eq!(result.pose, matcher.robot_pose());
+        assert_eq!(result.inliers, 360);
+    }
+
+    #[test]
+    fn test_from_pyramid() {
+        let mapping = room_mapping(0.05);
+        let scan_points = Pointcloud2::from(room_scan(&Pose2::new(0.1, -0.08, 0.05)));
+        let init_pose = Pose2::new(0.0, 0.0, 0.0);
+        let pyramid = MapPyramid::new(&mapping, DEFAULT_SCAN_TO_MAP_LEVELS);
+
+        let mut owned = ScanToMapMatcher::new(&scan_points, &mapping, &init_pose);
+        let mut borrowed = ScanToMapMatcher::from_pyramid(&scan_points, &pyramid, &init_pose);
+
+        assert_eq!(borrowed.scan_matching(30), owned.scan_matching(30));
+    }
+
+    #[test]
+    fn test_multi_resolution_widens_convergence() {
+        let mapping = room_mapping(0.05);
+        let expected_pose = Pose2::new(0.35, 0.25, 0.0);
+        let scan_points = Pointcloud2::from(room_scan(&expected_pose));
+        let init_pose = Pose2::new(0.0, 0.0, 0.0);
+
+        let mut single_level = ScanToMapMatcher::new(&scan_points, &mapping, &init_pose);
+        single_level.set_levels(1);
+        let mut multi_level = ScanToMapMatcher::new(&scan_points, &mapping, &init_pose);
+        multi_level.set_levels(4);
+
+        let single_level_result = single_level.scan_matching(30);
+        let multi_level_result = multi_level.scan_matching(30);
+
+        assert!(
+            multi_level_result.pose.distance(&expected_pose)
+                < single_level_result.pose.distance(&expected_pose)
+        );
+        assert!(multi_level_result.pose.distance(&expected_pose) < 1e-3);
+    }
+
+    #[test]
+    fn test_interpolation_gradient() {
+        let mapping = room_mapping(0.05);
+        let matcher = ScanToMapMatcher::new(
+            &room_scan(&Pose2::new(0.0, 0.0, 0.0)),
+            &mapping,
+            &Pose2::new(0.0, 0.0, 0.0),
+        );
+        let level = matcher.pyramid.level(0);
+        // Offsets from the centre of the first cell of the map.
+        let at = |x: f64, y: f64| {
+            let origin = na::Vector2::new(level.min_point().x, level.min_point().y);
+            interpolate(level, &(origin + na::Vector2::new(x, y)))
+        };
+
+        let epsilon = 1e-6;
+        for (x, y) in [(0.93, 1.02), (6.98, 3.51), (4.27, 0.96)] {
+            let (_, gradient) = at(x, y).unwrap();
+            let dx = at(x + epsilon, y).unwrap().0 - at(x - epsilon, y).unwrap().0;
+            let dy = at(x, y + epsilon).unwrap().0 - at(x, y - epsilon).unwrap().0;
+            assert_approx_eq!(gradient.x, dx / (2.0 * epsilon), 1e-4);
+            assert_approx_eq!(gradient.y, dy / (2.0 * epsilon), 1e-4);
+        }
+        assert!(at(-0.1, 1.0).is_none());
+    }
+
+    #[test]
+    fn test_unexplored_map() {
+        let mapping = Mapping::new(
+            grid_map::Position::new(-4.0, -3.0),
+            grid_map::Position::new(4.0, 3.0),
+            0.05,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+        let init_pose = Pose2::new(0.0, 0.0, 0.0);
+        let mut matcher = ScanToMapMatcher::new(&room_scan(&init_pose), &mapping, &init_pose);
+        let result = matcher.scan_matching(10);
+
+        // Nothing to pull the scan, so the match never leaves the initial pose.
+        assert_eq!(result.status, ScanMatchingStatus::Diverged);
+        assert_eq!(result.pose, init_pose);
+        assert_eq!(result.inliers, 360);
+    }
+
+    #[test]
+    fn test_scan_outside_map() {
+        let mapping = room_mapping(0.05);
+        let mut matcher = ScanToMapMatcher::new(
+            &room_scan(&Pose2::new(0.0, 0.0, 0.0)),
+            &mapping,
+            &Pose2::new(100.0, 100.0, 0.0),
+        );
+        let result = matcher.scan_matching(10);
+
+        assert_eq!(result.status, ScanMatchingStatus::Diverged);
+        assert_eq!(result.inliers, 0);
+    }
+}
@@ -0,0 +1,301 @@
+/// Submaps: local occupancy grids anchored to poses
+use crate::*;
+use grid_map::{Grid, Position};
+use nalgebra as na;
+
+/// Default number of scans inserted into a submap before it is finished.
+pub const DEFAULT_SCANS_PER_SUBMAP: usize = 30;
+/// Half of the edge length of a new submap, which grows as needed. [m]
+const SUBMAP_INITIAL_HALF_SIZE: f64 = 5.0;
+
+/// Occupancy grid in the frame of its anchor pose.
+pub struct Submap {
+    /// Pose of the submap frame in the world frame.
+    anchor: Pose2,
+    /// Grid in the submap frame.
+    mapping: Mapping,
+    scans: usize,
+    finished: bool,
+}
+
+impl Submap {
+    fn new(anchor: &Pose2, resolution: f64) -> Self {
+        Self {
+            anchor: *anchor,
+            mapping: Mapping::new(
+                Position::new(-SUBMAP_INITIAL_HALF_SIZE, -SUBMAP_INITIAL_HALF_SIZE),
+                Position::new(SUBMAP_INITIAL_HALF_SIZE, SUBMAP_INITIAL_HALF_SIZE),
+                resolution,
+                DEFAULT_PROBABILITY_FREE_SPACE,
+                DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+            ),
+            scans: 0,
+            finished: false,
+        }
+    }
+
+    pub fn anchor(&self) -> Pose2 {
+        self.anchor
+    }
+
+    /// Grid in the frame of the anchor.
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    /// Number of scans inserted so far.
+    pub fn scans(&self) -> usize {
+        self.scans
+    }
+
+    /// Whether the submap takes no more scans.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the element of the cell containing a point given in the submap frame.
+    fn element_at(&self, point: &na::Point2<f64>) -> Option<MapElement> {
+        let min_point = self.mapping.min_point();
+        let x = ((point.x - min_point.x) / self.mapping.resolution()).round();
+        let y = ((point.y - min_point.y) / self.mapping.resolution()).round();
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+        self.mapping.map_element(&Grid::new(x as usize, y as usize))
+    }
+
+    /// Corners of the grid in the world frame.
+    fn corners(&self) -> [na::Point2<f64>; 4] {
+        let anchor: na::Isometry2<f64> = self.anchor.into();
+        let resolution = self.mapping.resolution();
+        let (width, height) = self.mapping.map_size();
+        let min_x = self.mapping.min_point().x - resolution / 2.0;
+        let min_y = self.mapping.min_point().y - resolution / 2.0;
+        let max_x = min_x + width as f64 * resolution;
+        let max_y = min_y + height as f64 * resolution;
+        [
+            (min_x, min_y),
+            (max_x, min_y),
+            (min_x, max_y),
+            (max_x, max_y),
+        ]
+        .map(|(x, y)| anchor * na::Point2::new(x, y))
+    }
+}
+
+/// Builds the map as a sequence of submaps, so that the map can be corrected by moving the
+/// anchors, e.g. after a loop closure.
+///
+/// Scans go into the active submap at their pose relative to its anchor. After
+/// `scans_per_submap` scans the submap is finished and the next scan starts a new one anchored at
+/// its pose.
+pub struct SubmapManager {
+    submaps: Vec<Submap>,
+    resolution: f64,
+    scans_per_submap: usize,
+}
+
+impl SubmapManager {
+    pub fn new(resolution: f64) -> Self {
+        Self {
+            submaps: Vec::new(),
+            resolution,
+            scans_per_submap: DEFAULT_SCANS_PER_SUBMAP,
+        }
+    }
+
+    pub fn resolution(&self) -> f64 {
+        self.resolution
+    }
+
+    pub fn scans_per_submap(&self) -> usize {
+        self.scans_per_submap
+    }
+
+    pub fn set_scans_per_submap(&mut self, scans_per_submap: usize) {
+        self.scans_per_submap = scans_per_submap.max(1);
+    }
+
+    pub fn submaps(&self) -> &[Submap] {
+        &self.submaps
+    }
+
+    /// Submap that takes the next scan, if one has been started.
+    pub fn active_submap(&self) -> Option<&Submap> {
+        self.submaps.last().filter(|submap| !submap.finished)
+    }
+
+    /// Inserts a scan taken at `robot_pose` in the world frame and returns the index of the
+    /// submap it went into.
+    pub fn insert(&mut self, robot_pose: &Pose2, laser_scan: &LaserScan) -> usize {
+        if self.active_submap().is_none() {
+            self.submaps.push(Submap::new(robot_pose, self.resolution));
+        }
+        let index = self.submaps.len() - 1;
+        let submap = &mut self.submaps[index];
+
+        let anchor: na::Isometry2<f64> = submap.anchor.into();
+        let robot_pose: na::Isometry2<f64> = (*robot_pose).into();
+        submap
+            .mapping
+            .update(&(anchor.inverse() * robot_pose), laser_scan);
+        submap.scans += 1;
+        submap.finished = submap.scans >= self.scans_per_submap;
+
+        index
+    }
+
+    /// Moves a submap, e.g. to the pose optimised after a loop closure.
+    pub fn set_anchor(&mut self, index: usize, anchor: &Pose2) {
+        self.submaps[index].anchor = *anchor;
+    }
+
+    /// Finishes the active submap, so that the next scan starts a new one.
+    pub fn finish_submap(&mut self) {
+        if let Some(submap) = self.submaps.last_mut() {
+            submap.finished = true;
+        }
+    }
+
+    /// Renders all submaps at their current anchors into one global map.
+    ///
+    /// Every cell of the global map sums the log-odds of the submap cells containing its centre.
+    pub fn render(&self) -> Mapping {
+        let mut bounds: Option<(f64, f64, f64, f64)> = None;
+        for corner in self.submaps.iter().flat_map(|submap| submap.corners()) {
+            let (min_x, min_y, max_x, max_y) =
+                bounds.unwrap_or((corner.x, corner.y, corner.x, corner.y));
+            bounds = Some((
+                min_x.min(corner.x),
+                min_y.min(corner.y),
+                max_x.max(corner.x),
+                max_y.max(corner.y),
+            ));
+        }
+        let (min_x, min_y, max_x, max_y) = bounds.unwrap_or((0.0, 0.0, 0.0, 0.0));
+        let mut mapping = Mapping::new(
+            Position::new(min_x + self.resolution / 2.0, min_y + self.resolution / 2.0),
+            Position::new(max_x, max_y),
+            self.resolution,
+            DEFAULT_PROBABILITY_FREE_SPACE,
+            DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+        );
+
+        let (width, height) = mapping.map_size();
+        let origin = na::Vector2::new(mapping.min_point().x, mapping.min_point().y);
+        let mut log_odds: Vec<Option<f64>> = vec![None; width * height];
+        for submap in self.submaps.iter() {
+            let world_to_submap = na::Isometry2::<f64>::from(submap.anchor).inverse();
+            let corners = submap.corners();
+            let cell = |value: f64, origin: f64, size: usize| {
+                (((value - origin) / self.resolution).round().max(0.0) as usize).min(size - 1)
+            };
+            let x_range = corners.iter().map(|corner| cell(corner.x, origin.x, width));
+            let y_range = corners
+                .iter()
+                .map(|corner| cell(corner.y, origin.y, height));
+            let (x0, x1) = (x_range.clone().min().unwrap(), x_range.max().unwrap());
+            let (y0, y1) = (y_range.clone().min().unwrap(), y_range.max().unwrap());
+
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let center = na::Point2::new(
+                        origin.x + x as f64 * self.resolution,
+                        origin.y + y as f64 * self.resolution,
+                    );
+                    if let Some(element) = submap.element_at(&(world_to_submap * center)) {
+                        let value = &mut log_odds[y * width + x];
+                        *value = Some(value.unwrap_or(0.0) + element.log_odds);
+                    }
+                }
+            }
+        }
+
+        let (min_log_odds, max_log_odds) = mapping.log_odds_limits();
+        for (i, value) in log_odds.into_iter().enumerate() {
+            if let Some(value) = value {
+                mapping.set_map_element(
+                    &Grid::new(i % width, i / width),
+                    MapElement::from_log_odds(value.clamp(min_log_odds, max_log_odds)),
+                );
+            }
+        }
+        mapping
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+
+    const POSES: [(f64, f64, f64); 4] = [
+        (0.0, 0.0, 0.0),
+        (-1.5, -1.0, 0.5),
+        (0.5, 1.0, -1.0),
+        (-0.5, 0.5, 2.0),
+    ];
+
+    #[test]
+    fn test_submaps() {
+        let mut manager = SubmapManager::new(0.05);
+        manager.set_scans_per_submap(3);
+
+        let indices = (0..7)
+            .map(|i| {
+                let (x, y, theta) = POSES[i % POSES.len()];
+                let pose = Pose2::new(x, y, theta);
+                manager.insert(&pose, &room_scan(&pose))
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(indices, vec![0, 0, 0, 1, 1, 1, 2]);
+        assert!(manager.submaps()[0].is_finished());
+        assert_eq!(manager.submaps()[1].anchor(), Pose2::new(-0.5, 0.5, 2.0));
+        assert_eq!(manager.active_submap().unwrap().scans(), 1);
+
+        // Cells are in the frame of the anchor.
+        let submap = &manager.submaps()[1];
+        let anchor: na::Isometry2<f64> = submap.anchor().into();
+        let occupied = submap.mapping().get_occupied_grids_positions();
+        assert!(!occupied.is_empty());
+        for position in occupied {
+            let world = anchor * na::Point2::new(position.x, position.y);
+            assert!(distance_to_walls(&Position::new(world.x, world.y)) < 0.1);
+        }
+    }
+
+    #[test]
+    fn test_render_after_correction() {
+        let mut manager = SubmapManager::new(0.05);
+        manager.set_scans_per_submap(2);
+
+        // The second submap is built from odometry that has drifted by a constant offset.
+        let drift: na::Isometry2<f64> = Pose2::new(0.3, -0.2, 0.1).into();
+        for (i, (x, y, theta)) in POSES.iter().enumerate() {
+            let pose = Pose2::new(*x, *y, *theta);
+            let odometry_pose = if i < 2 {
+                pose
+            } else {
+                (drift * na::Isometry2::from(pose)).into()
+            };
+            manager.insert(&odometry_pose, &room_scan(&pose));
+        }
+        assert_eq!(manager.submaps().len(), 2);
+
+        let off_wall = |mapping: &Mapping| {
+            mapping
+                .get_occupied_grids_positions()
+                .iter()
+                .filter(|p| distance_to_walls(p) > 0.1)
+                .count()
+        };
+        assert!(off_wall(&manager.render()) > 100);
+
+        let anchor: na::Isometry2<f64> = manager.submaps()[1].anchor().into();
+        manager.set_anchor(1, &(drift.inverse() * anchor).into());
+        let mapping = manager.render();
+        assert_eq!(off_wall(&mapping), 0);
+        assert!(mapping.get_occupied_grids_positions().len() > 300);
+    }
+}
@@ -0,0 +1,99 @@
+/// Simulated environment shared by the tests
+use crate::*;
+use grid_map::Position;
+use nalgebra as na;
+
+/// Walls of a 6 m x 4 m room with a box in one corner, as (x0, y0, x1, y1).
+pub const ROOM_WALLS: [(f64, f64, f64, f64); 8] = [
+    (-3.0, -2.0, 3.0, -2.0),
+    (3.0, -2.0, 3.0, 2.0),
+    (3.0, 2.0, -3.0, 2.0),
+    (-3.0, 2.0, -3.0, -2.0),
+    (1.5, 0.8, 2.2, 0.8),
+    (2.2, 0.8, 2.2, 1.3),
+    (2.2, 1.3, 1.5, 1.3),
+    (1.5, 1.3, 1.5, 0.8),
+];
+
+/// Occupancy grid of the room built from scans at a few poses.
+pub fn room_mapping(resolution: f64) -> Mapping {
+    let mut mapping = Mapping::new(
+        Position::new(-4.0, -3.0),
+        Position::new(4.0, 3.0),
+        resolution,
+        DEFAULT_PROBABILITY_FREE_SPACE,
+        DEFAULT_PROBABILITY_OCCUPIED_SPACE,
+    );
+    for pose in [
+        Pose2::new(0.0, 0.0, 0.0),
+        Pose2::new(-1.5, -1.0, 0.5),
+        Pose2::new(0.5, 1.0, -1.0),
+    ] {
+        mapping.update(&isometry(&pose), &room_scan(&pose));
+    }
+    mapping
+}
+
+/// Simulates a 360 beam scan of the room from `pose`.
+pub fn room_scan(pose: &Pose2) -> LaserScan {
+    let beams = 360;
+    let angle_increment = 2.0 * std::f64::consts::PI / beams as f64;
+    let ranges = (0..beams)
+        .map(|i| {
+            let angle = pose.theta() + i as f64 * angle_increment;
+            let (dx, dy) = (angle.cos(), angle.sin());
+            ROOM_WALLS
+                .iter()
+                .filter_map(|(x0, y0, x1, y1)| {
+                    // Solve pose + t * d = p0 + s * (p1 - p0) for t >= 0, 0 <= s <= 1.
+                    let (ex, ey) = (x1 - x0, y1 - y0);
+                    let denominator = dx * ey - dy * ex;
+                    if denominator.abs() < 1e-12 {
+                        return None;
+                    }
+                    let (wx, wy) = (x0 - pose.x(), y0 - pose.y());
+                    let t = (wx * ey - wy * ex) / denominator;
+                    let s = (wx * dy - wy * dx) / denominator;
+                    (t >= 0.0 && (0.0..=1.0).contains(&s)).then_some(t)
+                })
+                .fold(f64::INFINITY, f64::min)
+        })
+        .collect();
+    LaserScan::new(
+        10.0,
+        0.1,
+        angle_increment,
+        0.0,
+        2.0 * std::f64::consts::PI,
+        0.0,
+        ranges,
+    )
+}
+
+pub fn isometry(pose: &Pose2) -> na::Isometry2<f64> {
+    na::Isometry2::new(na::Vector2::new(pose.x(), pose.y()), pose.theta())
+}
+
+/// Distance from a position to the nearest wall of the room.
+pub fn distance_to_walls(position: &Position) -> f64 {
+    let p = na::Vector2::new(position.x, position.y);
+    ROOM_WALLS
+        .iter()
+        .map(|(x0, y0, x1, y1)| {
+            let a = na::Vector2::new(*x0, *y0);
+            let ab = na::Vector2::new(*x1, *y1) - a;
+            let t = ((p - a).dot(&ab) / ab.norm_squared()).clamp(0.0, 1.0);
+            (a + ab * t - p).norm()
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// Pose on an ellipse around the room, facing along it.
+pub fn loop_pose(i: usize) -> Pose2 {
+    let t = 0.1 * i as f64;
+    Pose2::new(
+        -0.5 + 1.5 * t.cos(),
+        -0.3 + 1.0 * t.sin(),
+        normalize_angle((1.0 * t.cos()).atan2(-1.5 * t.sin())),
+    )
+}
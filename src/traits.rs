@@ -1,3 +1,11 @@
+mod inverse_sensor_model;
+mod kd_point;
 mod point;
+mod rigid_transform;
+mod scan_matcher;
 
+pub use inverse_sensor_model::*;
+pub use kd_point::*;
 pub use point::*;
+pub use rigid_transform::*;
+pub use scan_matcher::*;
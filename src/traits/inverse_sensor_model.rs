@@ -0,0 +1,26 @@
+use crate::*;
+
+/// Occupancy evidence of one beam of a range sensor, used by `Mapping::update`.
+///
+/// Updates are log-odds added to the cells; positive values mean occupied.
+pub trait InverseSensorModel: Send + Sync {
+    /// Log-odds of a cell the beam passes through at `distance` from the sensor, for a beam
+    /// that hit at `range`. `range` is infinite for beams without a return.
+    fn free_log_odds(&self, distance: f64, range: f64) -> f64;
+
+    /// Log-odds of a cell whose centre is `offset` away from the endpoint of a beam that hit
+    /// at `range`.
+    fn hit_log_odds(&self, offset: f64, range: f64) -> f64;
+
+    /// Radius around the endpoint whose cells get `hit_log_odds` instead of `free_log_odds`.
+    /// The cell of the endpoint is always a hit. [m]
+    fn hit_radius(&self) -> f64 {
+        0.0
+    }
+
+    /// Parameters that restore the model when a map is loaded. `None` for models that cannot
+    /// be saved with the map.
+    fn parameters(&self) -> Option<InverseSensorModelParameters> {
+        None
+    }
+}
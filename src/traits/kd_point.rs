@@ -0,0 +1,9 @@
+use crate::*;
+
+/// Point with a fixed number of coordinates that can be stored in a [`KdTree`].
+pub trait KdPoint: Point + Copy {
+    /// Number of coordinates.
+    const DIM: usize;
+
+    fn coordinate(&self, axis: usize) -> f64;
+}
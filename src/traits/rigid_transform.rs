@@ -0,0 +1,35 @@
+use crate::*;
+use nalgebra as na;
+
+/// Rigid body pose that can move points and be optimised in its tangent space.
+pub trait RigidTransform: Point + Copy + std::fmt::Debug {
+    /// Point type the pose acts on.
+    type Point: KdPoint;
+
+    /// Number of parameters of a tangent-space increment.
+    const DOF: usize;
+
+    fn transform_point(&self, point: &Self::Point) -> Self::Point;
+
+    /// Applies a tangent-space increment of length [`Self::DOF`].
+    fn retract(&self, delta: &[f64]) -> Self;
+
+    /// Jacobian (`Point::DIM` x `DOF`) of `self.retract(delta).transform_point(point)` at zero `delta`.
+    fn point_jacobian(&self, point: &Self::Point) -> na::DMatrix<f64>;
+
+    /// Finds the pose that maps `source` onto `target` in the weighted least-squares sense.
+    ///
+    /// Returns `None` if the lengths differ or the weights sum to zero.
+    fn weighted_alignment(
+        source: &[Self::Point],
+        target: &[Self::Point],
+        weights: &[f64],
+    ) -> Option<Self>;
+
+    fn is_finite(&self) -> bool;
+}
+
+/// Coordinates of a point as a column vector.
+pub fn point_to_vector<P: KdPoint>(point: &P) -> na::DVector<f64> {
+    na::DVector::from_fn(P::DIM, |i, _| point.coordinate(i))
+}
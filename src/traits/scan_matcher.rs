@@ -0,0 +1,7 @@
+use crate::*;
+
+/// Matcher that estimates the pose of a scan, so that matchers can be swapped and compared.
+pub trait ScanMatcher<T = Pose2> {
+    /// Iterates until convergence or `max_iterations` and reports the final pose.
+    fn scan_matching(&mut self, max_iterations: usize) -> ScanMatchingResult<T>;
+}
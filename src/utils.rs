@@ -1,5 +1,5 @@
-use grid_map::{Grid, Position};
 use nalgebra as na;
+use rand::Rng;
 
 pub fn coordinate_transformation(
     current_position: &(impl Into<na::Isometry2<f64>> + Clone),
@@ -16,45 +16,6 @@ pub fn coordinate_transformation(
     points
 }
 
-// TODO: Update to derive a grid that accurately traces the line segment between two points.
-pub fn bresenham_algorithm(
-    start: &na::Translation2<f64>,
-    end: &na::Translation2<f64>,
-    resolution: f64,
-    min_point: &Position,
-) -> Vec<Grid> {
-    let mut idx_x0 = ((start.x - min_point.x) / resolution).round() as i32;
-    let mut idx_y0 = ((start.y - min_point.y) / resolution).round() as i32;
-    let idx_x1 = ((end.x - min_point.x) / resolution).round() as i32;
-    let idx_y1 = ((end.y - min_point.y) / resolution).round() as i32;
-
-    let mut grids = Vec::new();
-    grids.push(Grid::new(idx_x0 as usize, idx_y0 as usize));
-
-    let delta_x = (idx_x1 - idx_x0).abs();
-    let delta_y = (idx_y1 - idx_y0).abs();
-
-    let step_x = if idx_x0 < idx_x1 { 1 } else { -1 };
-    let step_y = if idx_y0 < idx_y1 { 1 } else { -1 };
-
-    let mut error = delta_x - delta_y;
-
-    while idx_x0 != idx_x1 || idx_y0 != idx_y1 {
-        let error_x2 = 2 * error;
-        if error_x2 > -delta_y {
-            error -= delta_y;
-            idx_x0 += step_x;
-        }
-        if error_x2 < delta_x {
-            error += delta_x;
-            idx_y0 += step_y;
-        }
-        grids.push(Grid::new(idx_x0 as usize, idx_y0 as usize));
-    }
-
-    grids
-}
-
 pub fn linear_interpolation(
     time0: f64,
     value0: f64,
@@ -66,10 +27,68 @@ pub fn linear_interpolation(
     slope * (current_time - time0) + value0
 }
 
+/// Wraps an angle to (-pi, pi].
+pub fn normalize_angle(angle: f64) -> f64 {
+    na::UnitComplex::new(angle).angle()
+}
+
+/// Zero mean normal sample with the variance (Box-Muller).
+pub fn sample_normal(rng: &mut impl Rng, variance: f64) -> f64 {
+    if variance <= 0.0 {
+        return 0.0;
+    }
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    variance.sqrt() * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Scales importance weights to sum to one, or makes them uniform if they sum to zero.
+pub fn normalize_weights<'a>(weights: impl Iterator<Item = &'a mut f64>) {
+    let weights = weights.collect::<Vec<_>>();
+    let sum = weights.iter().map(|weight| **weight).sum::<f64>();
+    let count = weights.len() as f64;
+    for weight in weights {
+        *weight = if sum > 0.0 {
+            *weight / sum
+        } else {
+            1.0 / count
+        };
+    }
+}
+
+/// Effective number of samples of normalised importance weights, 1 / sum(w^2).
+pub fn effective_sample_size(weights: impl Iterator<Item = f64>) -> f64 {
+    1.0 / weights.map(|weight| weight * weight).sum::<f64>()
+}
+
+/// Low variance sampler: one random offset and equally spaced pointers into the cumulative
+/// normalised weights. Returns the indices of as many draws as there are weights.
+pub fn low_variance_sample(rng: &mut impl Rng, weights: &[f64]) -> Vec<usize> {
+    let count = weights.len();
+    if count == 0 {
+        return Vec::new();
+    }
+    let step = 1.0 / count as f64;
+    let mut pointer = rng.gen_range(0.0..step);
+    let mut cumulative = weights[0];
+    let mut i = 0;
+    let mut indices = Vec::with_capacity(count);
+    for _ in 0..count {
+        while pointer > cumulative && i + 1 < count {
+            i += 1;
+            cumulative += weights[i];
+        }
+        indices.push(i);
+        pointer += step;
+    }
+    indices
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use assert_approx_eq::assert_approx_eq;
+    use rand::SeedableRng;
 
     #[test]
     fn test_linear_interpolation() {
@@ -87,4 +106,19 @@ mod test {
         assert_approx_eq!(curret_value, 0.5);
         assert_approx_eq!(future_value, 2.0);
     }
+
+    #[test]
+    fn test_low_variance_sample() {
+        let mut weights = [0.0, 3.0, 1.0, 0.0];
+        normalize_weights(weights.iter_mut());
+        assert_eq!(weights, [0.0, 0.75, 0.25, 0.0]);
+        assert_approx_eq!(effective_sample_size(weights.iter().copied()), 1.6);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(low_variance_sample(&mut rng, &weights), vec![1, 1, 1, 2]);
+
+        let mut weights = [0.0; 2];
+        normalize_weights(weights.iter_mut());
+        assert_eq!(weights, [0.5, 0.5]);
+    }
 }